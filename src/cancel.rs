@@ -0,0 +1,43 @@
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Shared flag used to stop a running sync from another thread. Cloned tokens observe the same
+/// flag. Once cancelled, no new transfers are started but transfers already in flight are allowed
+/// to finish.
+#[derive(Debug, Default, Clone)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Error returned when a sync stops because its [CancellationToken] was cancelled. Use
+/// `downcast_ref` on the returned error to tell a cancellation apart from a failure.
+#[derive(Debug)]
+pub struct Cancelled {
+    /// Number of queued files that were not attempted
+    pub not_attempted: usize,
+}
+
+impl Display for Cancelled {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Sync was cancelled. {} files were not attempted",
+            self.not_attempted
+        )
+    }
+}
+
+impl std::error::Error for Cancelled {}
@@ -0,0 +1,103 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const HEADER: &str = "# sftp-sync checkpoint";
+
+/// Queued transfer as recorded in a checkpoint state file
+#[derive(Debug, Clone)]
+pub struct CheckpointEntry {
+    pub remote_path: PathBuf,
+    pub relative_path: PathBuf,
+}
+
+/// Tracks the queue of a running sync so the remaining work can be written to a state file and
+/// resumed later without walking the remote tree again
+#[derive(Debug, Default)]
+pub struct Checkpoint {
+    remote_directory: PathBuf,
+    queue: Vec<CheckpointEntry>,
+    completed: HashSet<PathBuf>,
+    completed_since_write: usize,
+}
+
+impl Checkpoint {
+    pub fn new(remote_directory: &Path, queue: Vec<CheckpointEntry>) -> Self {
+        Self {
+            remote_directory: remote_directory.to_path_buf(),
+            queue,
+            completed: HashSet::new(),
+            completed_since_write: 0,
+        }
+    }
+
+    /// Mark the remote path as completed, returning true when `interval` files have completed
+    /// since the state file was last written
+    pub fn complete(&mut self, remote_path: &Path, interval: usize) -> bool {
+        self.completed.insert(remote_path.to_path_buf());
+        self.completed_since_write += 1;
+        self.completed_since_write >= interval
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.queue.len() - self.completed.len()
+    }
+
+    /// Write the remaining queue to `path`. The file is written to a temporary sibling first and
+    /// renamed over the target so an interruption never leaves a truncated state file.
+    pub fn write(&mut self, path: &Path) -> std::io::Result<()> {
+        let mut temp_path = path.as_os_str().to_os_string();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+        let mut file = BufWriter::new(File::create(&temp_path)?);
+        writeln!(file, "{HEADER}")?;
+        writeln!(file, "{}", self.remote_directory.display())?;
+        for entry in &self.queue {
+            if self.completed.contains(&entry.remote_path) {
+                continue;
+            }
+            writeln!(
+                file,
+                "{}\t{}",
+                entry.remote_path.display(),
+                entry.relative_path.display()
+            )?;
+        }
+        file.flush()?;
+        drop(file);
+        std::fs::rename(&temp_path, path)?;
+        self.completed_since_write = 0;
+        Ok(())
+    }
+}
+
+/// Read a state file written by [Checkpoint::write], returning the remote directory the queue
+/// was built from and the remaining entries
+pub fn read_state(path: &Path) -> Result<(PathBuf, Vec<CheckpointEntry>), String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|error| format!("Could not read state file {path:?}. {error}"))?;
+    let mut lines = contents.lines();
+    if lines.next() != Some(HEADER) {
+        return Err(format!("{path:?} is not an sftp-sync state file"));
+    }
+    let Some(remote_directory) = lines.next() else {
+        return Err(format!(
+            "State file {path:?} is missing the remote directory"
+        ));
+    };
+    let mut entries = Vec::new();
+    for (index, line) in lines.enumerate() {
+        let Some((remote_path, relative_path)) = line.split_once('\t') else {
+            return Err(format!(
+                "State file {path:?} line {} is malformed",
+                index + 3
+            ));
+        };
+        entries.push(CheckpointEntry {
+            remote_path: PathBuf::from(remote_path),
+            relative_path: PathBuf::from(relative_path),
+        });
+    }
+    Ok((PathBuf::from(remote_directory), entries))
+}
@@ -0,0 +1,486 @@
+use crate::remote_error::RemoteContext;
+use crate::xattr::shell_quote;
+use ssh2::Session;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::UNIX_EPOCH;
+
+const BUFFER_SIZE: usize = 1024 * 128;
+
+const ROUND_CONSTANTS: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+const INITIAL_STATE: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// Incremental SHA-256 hasher used to compare and verify file contents
+#[derive(Clone)]
+pub struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    block_len: usize,
+    total_len: u64,
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sha256 {
+    pub fn new() -> Self {
+        Self {
+            state: INITIAL_STATE,
+            block: [0; 64],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.total_len += data.len() as u64;
+        if self.block_len > 0 {
+            let take = (64 - self.block_len).min(data.len());
+            self.block[self.block_len..self.block_len + take].copy_from_slice(&data[..take]);
+            self.block_len += take;
+            data = &data[take..];
+            if self.block_len < 64 {
+                return;
+            }
+            let block = self.block;
+            self.compress(&block);
+            self.block_len = 0;
+        }
+        let mut chunks = data.chunks_exact(64);
+        for chunk in &mut chunks {
+            let mut block = [0; 64];
+            block.copy_from_slice(chunk);
+            self.compress(&block);
+        }
+        let remainder = chunks.remainder();
+        self.block[..remainder.len()].copy_from_slice(remainder);
+        self.block_len = remainder.len();
+    }
+
+    pub fn finish(mut self) -> String {
+        let bit_len = self.total_len.wrapping_mul(8);
+        self.update(&[0x80]);
+        while self.block_len != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bit_len.to_be_bytes());
+        self.state
+            .iter()
+            .map(|word| format!("{word:08x}"))
+            .collect()
+    }
+
+    fn compress(&mut self, block: &[u8; 64]) {
+        let mut schedule = [0u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            schedule[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = schedule[i - 15].rotate_right(7)
+                ^ schedule[i - 15].rotate_right(18)
+                ^ (schedule[i - 15] >> 3);
+            let s1 = schedule[i - 2].rotate_right(17)
+                ^ schedule[i - 2].rotate_right(19)
+                ^ (schedule[i - 2] >> 10);
+            schedule[i] = schedule[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(schedule[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let temp1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(ROUND_CONSTANTS[i])
+                .wrapping_add(schedule[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let temp2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(temp1);
+            d = c;
+            c = b;
+            b = a;
+            a = temp1.wrapping_add(temp2);
+        }
+
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+}
+
+/// Read the entire contents of `reader` and return the hex encoded SHA-256 digest
+pub fn hash_reader<R: Read>(mut reader: R) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; BUFFER_SIZE];
+    loop {
+        let bytes_read = reader.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[0..bytes_read]);
+    }
+    Ok(hasher.finish())
+}
+
+/// Hash a remote file on the server by running `sha256sum` over an exec channel, so the contents
+/// are never transferred. Returns `None` when `sha256sum` is not available on the server.
+pub fn read_remote_sha256(
+    session: &Session,
+    remote_path: &Path,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let mut channel = session
+        .channel_session()
+        .session_context("opening an exec channel")?;
+    channel
+        .exec(&format!(
+            "sha256sum -b -- {}",
+            shell_quote(&remote_path.to_string_lossy())
+        ))
+        .remote_context("running sha256sum on", remote_path)?;
+    let mut output = String::new();
+    channel.read_to_string(&mut output)?;
+    channel.wait_close()?;
+    match channel.exit_status()? {
+        0 => {}
+        127 => return Ok(None),
+        status => {
+            return Err(format!("sha256sum exited with status {status} for {remote_path:?}").into())
+        }
+    }
+    parse_sha256sum_output(&output)
+        .map(Some)
+        .ok_or_else(|| format!("Unexpected sha256sum output for {remote_path:?}").into())
+}
+
+/// Digest from the first line of `sha256sum` output for a single file
+fn parse_sha256sum_output(output: &str) -> Option<String> {
+    let hash = output.lines().next()?.split(' ').next()?;
+    (hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit())).then(|| hash.to_lowercase())
+}
+
+/// Parse a `sha256sum` style manifest (`<hash>  <path>` or `<hash> *<path>` per line) into a map
+/// of path to lowercase hex digest. Leading `./` is stripped from paths.
+pub fn parse_sha256sums(contents: &str) -> Result<HashMap<PathBuf, String>, String> {
+    let mut hashes = HashMap::new();
+    for (index, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((hash, path)) = line.split_once(' ') else {
+            return Err(format!("Line {}: expected `<hash>  <path>`", index + 1));
+        };
+        if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("Line {}: invalid SHA-256 digest {hash}", index + 1));
+        }
+        let path = path.trim_start_matches([' ', '*']);
+        let path = path.strip_prefix("./").unwrap_or(path);
+        hashes.insert(PathBuf::from(path), hash.to_lowercase());
+    }
+    Ok(hashes)
+}
+
+/// Remote `sha256sum` manifest whose entries are relative to the directory it is stored in
+#[derive(Debug)]
+pub struct Sha256Manifest {
+    path: PathBuf,
+    hashes: HashMap<PathBuf, String>,
+}
+
+impl Sha256Manifest {
+    pub fn parse(path: &Path, contents: &str) -> Result<Self, String> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            hashes: parse_sha256sums(contents)?,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Expected hash of the remote file at `remote_path`, or `None` when the manifest does not
+    /// list it
+    pub fn expected_hash(&self, remote_path: &Path) -> Option<&str> {
+        let directory = self.path.parent().unwrap_or(Path::new(""));
+        let relative_path = remote_path.strip_prefix(directory).ok()?;
+        self.hashes.get(relative_path).map(String::as_str)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CacheEntry {
+    size: u64,
+    mtime: u128,
+    hash: String,
+}
+
+/// Sidecar cache of local file hashes keyed by path. An entry is only reused while the file's
+/// size and modification time match the values recorded when the hash was computed.
+#[derive(Debug)]
+pub struct ChecksumCache {
+    path: PathBuf,
+    entries: Mutex<HashMap<PathBuf, CacheEntry>>,
+}
+
+impl ChecksumCache {
+    pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut entries = HashMap::new();
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => {
+                for line in contents.lines() {
+                    let mut parts = line.splitn(4, '\t');
+                    let (Some(size), Some(mtime), Some(hash), Some(file_path)) =
+                        (parts.next(), parts.next(), parts.next(), parts.next())
+                    else {
+                        continue;
+                    };
+                    let (Ok(size), Ok(mtime)) = (size.parse(), mtime.parse()) else {
+                        continue;
+                    };
+                    entries.insert(
+                        PathBuf::from(file_path),
+                        CacheEntry {
+                            size,
+                            mtime,
+                            hash: hash.to_string(),
+                        },
+                    );
+                }
+            }
+            Err(error) if error.kind() == ErrorKind::NotFound => {}
+            Err(error) => return Err(error),
+        }
+        Ok(Self {
+            path,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    /// Hash the local file, reusing the cached value when the file is unchanged
+    pub fn hash_file(&self, local_path: &Path) -> std::io::Result<String> {
+        let metadata = local_path.metadata()?;
+        let size = metadata.len();
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        if let Some(entry) = self.lock().get(local_path) {
+            if entry.size == size && entry.mtime == mtime {
+                return Ok(entry.hash.clone());
+            }
+        }
+        let hash = hash_reader(File::open(local_path)?)?;
+        self.lock().insert(
+            local_path.to_path_buf(),
+            CacheEntry {
+                size,
+                mtime,
+                hash: hash.clone(),
+            },
+        );
+        Ok(hash)
+    }
+
+    pub fn save(&self) -> std::io::Result<()> {
+        let entries = self.lock();
+        let mut paths: Vec<_> = entries.keys().collect();
+        paths.sort();
+        let mut file = BufWriter::new(File::create(&self.path)?);
+        for path in paths {
+            let entry = &entries[path];
+            writeln!(
+                file,
+                "{}\t{}\t{}\t{}",
+                entry.size,
+                entry.mtime,
+                entry.hash,
+                path.display()
+            )?;
+        }
+        file.flush()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<PathBuf, CacheEntry>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha256(data: &[u8]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    #[test]
+    fn matches_known_answers() {
+        assert_eq!(
+            sha256(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(
+            sha256(&[b'a'; 1_000_000]),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+
+    #[test]
+    fn pads_inputs_around_the_block_boundary() {
+        let expected = [
+            (
+                55,
+                "9f4390f8d30c2dd92ec9f095b65e2b9ae9b0a925a5258e241c9f1e910f734318",
+            ),
+            (
+                56,
+                "b35439a4ac6f0948b6d6f9e3c6af0f5f590ce20f1bde7090ef7970686ec6738a",
+            ),
+            (
+                63,
+                "7d3e74a05d7db15bce4ad9ec0658ea98e3f06eeecf16b4c6fff2da457ddc2f34",
+            ),
+            (
+                64,
+                "ffe054fe7ae0cb6dc65c3af9b61d5209f439851db43d0ba5997337df154668eb",
+            ),
+            (
+                65,
+                "635361c48bb9eab14198e76ea8ab7f1a41685d6ad62aa9146d301d4f17eb0ae0",
+            ),
+        ];
+        for (length, hash) in expected {
+            assert_eq!(sha256(&vec![b'a'; length]), hash, "{length} bytes");
+        }
+    }
+
+    #[test]
+    fn split_updates_match_a_single_update() {
+        let data: Vec<u8> = (0..1000u32).map(|value| (value * 7) as u8).collect();
+        for chunk_size in [1, 3, 63, 64, 65, 999] {
+            let mut hasher = Sha256::new();
+            for chunk in data.chunks(chunk_size) {
+                hasher.update(chunk);
+            }
+            assert_eq!(hasher.finish(), sha256(&data), "chunks of {chunk_size}");
+        }
+        assert_eq!(hash_reader(data.as_slice()).unwrap(), sha256(&data));
+    }
+
+    #[test]
+    fn parses_sha256sums() {
+        let hashes = parse_sha256sums(
+            "# generated\n\
+             E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855  ./empty.txt\n\
+             \n\
+             ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad *dir/abc bin\n",
+        )
+        .unwrap();
+        assert_eq!(hashes.len(), 2);
+        assert_eq!(
+            hashes[Path::new("empty.txt")],
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hashes[Path::new("dir/abc bin")],
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_sha256sums() {
+        assert_eq!(
+            parse_sha256sums("no-separator").unwrap_err(),
+            "Line 1: expected `<hash>  <path>`"
+        );
+        assert_eq!(
+            parse_sha256sums("\nabc123  file").unwrap_err(),
+            "Line 2: invalid SHA-256 digest abc123"
+        );
+        let not_hex = "g".repeat(64);
+        assert_eq!(
+            parse_sha256sums(&format!("{not_hex}  file")).unwrap_err(),
+            format!("Line 1: invalid SHA-256 digest {not_hex}")
+        );
+    }
+
+    #[test]
+    fn resolves_manifest_entries_against_its_directory() {
+        let hash = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        let manifest = Sha256Manifest::parse(
+            Path::new("/data/releases/SHA256SUMS"),
+            &format!("{hash}  ./a.tar\n{hash} *nested/b.tar\n"),
+        )
+        .unwrap();
+        assert_eq!(
+            manifest.expected_hash(Path::new("/data/releases/a.tar")),
+            Some(hash)
+        );
+        assert_eq!(
+            manifest.expected_hash(Path::new("/data/releases/nested/b.tar")),
+            Some(hash)
+        );
+        assert_eq!(manifest.expected_hash(Path::new("/data/a.tar")), None);
+        assert_eq!(
+            manifest.expected_hash(Path::new("/data/releases/c.tar")),
+            None
+        );
+        assert_eq!(
+            manifest.expected_hash(Path::new("/other/releases/a.tar")),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_sha256sum_output() {
+        let hash = "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD";
+        assert_eq!(
+            parse_sha256sum_output(&format!("{hash} */data/abc.bin\n")),
+            Some(hash.to_lowercase())
+        );
+        assert_eq!(parse_sha256sum_output(""), None);
+        assert_eq!(parse_sha256sum_output("abc123 *file\n"), None);
+        assert_eq!(
+            parse_sha256sum_output("sha256sum: file: No such file or directory\n"),
+            None
+        );
+    }
+}
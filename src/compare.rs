@@ -0,0 +1,189 @@
+use crate::checksum::{hash_reader, ChecksumCache};
+use crate::compress::GzipDecoder;
+use crate::remote_error::RemoteContext;
+use clap::ValueEnum;
+use ssh2::{FileStat, Sftp};
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+/// Decides if a remote file that already exists locally needs to be transferred again. Custom
+/// implementations can be supplied to [crate::SftpSync] to replace the built-in modes.
+pub trait Comparator: Send + Sync {
+    fn needs_transfer(
+        &self,
+        client: &Sftp,
+        remote_path: &Path,
+        remote: &FileStat,
+        local_path: &Path,
+    ) -> Result<bool, Box<dyn std::error::Error>>;
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CompareMode {
+    #[default]
+    Size,
+    Mtime,
+    Checksum,
+    /// Size first, then checksum only when the sizes match. Files with different sizes never
+    /// have to be read so this costs little more than size mode on trees with many changes, but
+    /// unchanged files are still read in full on both sides
+    Both,
+}
+
+impl CompareMode {
+    pub fn comparator(self) -> Box<dyn Comparator> {
+        match self {
+            Self::Size => Box::new(SizeComparator),
+            Self::Mtime => Box::new(MtimeComparator),
+            Self::Checksum => Box::new(ChecksumComparator::default()),
+            Self::Both => Box::new(SizeAndChecksumComparator::new(ChecksumComparator::default())),
+        }
+    }
+}
+
+pub struct SizeComparator;
+
+impl Comparator for SizeComparator {
+    fn needs_transfer(
+        &self,
+        _client: &Sftp,
+        remote_path: &Path,
+        remote: &FileStat,
+        local_path: &Path,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let Some(remote_size) = remote.size else {
+            return Err(format!(
+                "Could not extract file size from the remote path {remote_path:?}"
+            )
+            .into());
+        };
+        Ok(local_path.metadata()?.len() != remote_size)
+    }
+}
+
+pub struct MtimeComparator;
+
+impl Comparator for MtimeComparator {
+    fn needs_transfer(
+        &self,
+        _client: &Sftp,
+        remote_path: &Path,
+        remote: &FileStat,
+        local_path: &Path,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let Some(remote_mtime) = remote.mtime else {
+            return Err(
+                format!("Could not extract mtime from the remote path {remote_path:?}").into(),
+            );
+        };
+        let local_mtime = local_path
+            .metadata()?
+            .modified()?
+            .duration_since(UNIX_EPOCH)?
+            .as_secs();
+        Ok(local_mtime < remote_mtime)
+    }
+}
+
+#[derive(Default)]
+pub struct ChecksumComparator {
+    cache: Option<Arc<ChecksumCache>>,
+}
+
+impl ChecksumComparator {
+    pub fn with_cache(cache: Arc<ChecksumCache>) -> Self {
+        Self { cache: Some(cache) }
+    }
+}
+
+impl Comparator for ChecksumComparator {
+    fn needs_transfer(
+        &self,
+        client: &Sftp,
+        remote_path: &Path,
+        _remote: &FileStat,
+        local_path: &Path,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let local_hash = match &self.cache {
+            Some(cache) => cache.hash_file(local_path)?,
+            None => hash_reader(File::open(local_path)?)?,
+        };
+        let remote_hash = hash_reader(
+            client
+                .open(remote_path)
+                .remote_context("opening", remote_path)?,
+        )?;
+        Ok(local_hash != remote_hash)
+    }
+}
+
+pub struct SizeAndChecksumComparator {
+    size: SizeComparator,
+    checksum: ChecksumComparator,
+}
+
+impl SizeAndChecksumComparator {
+    pub fn new(checksum: ChecksumComparator) -> Self {
+        Self {
+            size: SizeComparator,
+            checksum,
+        }
+    }
+}
+
+impl Comparator for SizeAndChecksumComparator {
+    fn needs_transfer(
+        &self,
+        client: &Sftp,
+        remote_path: &Path,
+        remote: &FileStat,
+        local_path: &Path,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        if self
+            .size
+            .needs_transfer(client, remote_path, remote, local_path)?
+        {
+            return Ok(true);
+        }
+        self.checksum
+            .needs_transfer(client, remote_path, remote, local_path)
+    }
+}
+
+/// Comparator for local copies written by --compress-local. Their size never matches the remote
+/// so the size based modes fall back to comparing mtimes and the checksum modes hash the
+/// decompressed contents.
+pub struct CompressedComparator {
+    checksum: bool,
+}
+
+impl CompressedComparator {
+    pub fn new(mode: CompareMode) -> Self {
+        Self {
+            checksum: matches!(mode, CompareMode::Checksum | CompareMode::Both),
+        }
+    }
+}
+
+impl Comparator for CompressedComparator {
+    fn needs_transfer(
+        &self,
+        client: &Sftp,
+        remote_path: &Path,
+        remote: &FileStat,
+        local_path: &Path,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        if !self.checksum {
+            return MtimeComparator.needs_transfer(client, remote_path, remote, local_path);
+        }
+        let local_hash = hash_reader(GzipDecoder::new(File::open(local_path)?)?)?;
+        let remote_hash = hash_reader(
+            client
+                .open(remote_path)
+                .remote_context("opening", remote_path)?,
+        )?;
+        Ok(local_hash != remote_hash)
+    }
+}
@@ -0,0 +1,320 @@
+use clap::ValueEnum;
+use libz_sys::{
+    deflate, deflateEnd, deflateInit2_, inflate, inflateEnd, inflateInit2_, uInt, voidpf, z_stream,
+    zlibVersion, Z_BUF_ERROR, Z_DEFAULT_COMPRESSION, Z_DEFAULT_STRATEGY, Z_DEFLATED, Z_FINISH,
+    Z_NO_FLUSH, Z_OK, Z_STREAM_END,
+};
+use std::io::{ErrorKind, Read, Write};
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+
+const BUFFER_SIZE: usize = 32 * 1024;
+/// Window bits for a gzip header and trailer instead of the raw zlib format
+const GZIP_WINDOW_BITS: c_int = 15 + 16;
+
+/// Compression applied to downloaded files by --compress-local. Gzip is implemented on top of
+/// the zlib that libssh2 already links against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    Gzip,
+}
+
+impl Compression {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Gzip => "gz",
+        }
+    }
+
+    /// Local path of a compressed copy, e.g. `data.csv` becomes `data.csv.gz`
+    pub fn local_path(self, path: &Path) -> PathBuf {
+        let mut compressed = path.as_os_str().to_os_string();
+        compressed.push(".");
+        compressed.push(self.extension());
+        PathBuf::from(compressed)
+    }
+}
+
+unsafe extern "C" fn zalloc(_opaque: voidpf, items: uInt, size: uInt) -> voidpf {
+    libc::calloc(items as usize, size as usize)
+}
+
+unsafe extern "C" fn zfree(_opaque: voidpf, address: voidpf) {
+    libc::free(address)
+}
+
+/// zlib keeps a pointer back to the stream so it is boxed to keep its address stable
+fn new_stream() -> Box<z_stream> {
+    Box::new(z_stream {
+        next_in: std::ptr::null_mut(),
+        avail_in: 0,
+        total_in: 0,
+        next_out: std::ptr::null_mut(),
+        avail_out: 0,
+        total_out: 0,
+        msg: std::ptr::null_mut(),
+        state: std::ptr::null_mut(),
+        zalloc,
+        zfree,
+        opaque: std::ptr::null_mut(),
+        data_type: 0,
+        adler: 0,
+        reserved: 0,
+    })
+}
+
+fn zlib_error(operation: &str, status: c_int) -> std::io::Error {
+    std::io::Error::other(format!("zlib {operation} failed with status {status}"))
+}
+
+/// Writer that gzip compresses everything written to it. [GzipEncoder::finish] must be called
+/// once all data is written to complete the gzip trailer.
+pub struct GzipEncoder<W: Write> {
+    inner: W,
+    stream: Box<z_stream>,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> GzipEncoder<W> {
+    pub fn new(inner: W) -> std::io::Result<Self> {
+        let mut stream = new_stream();
+        let status = unsafe {
+            deflateInit2_(
+                &mut *stream,
+                Z_DEFAULT_COMPRESSION,
+                Z_DEFLATED,
+                GZIP_WINDOW_BITS,
+                8,
+                Z_DEFAULT_STRATEGY,
+                zlibVersion(),
+                std::mem::size_of::<z_stream>() as c_int,
+            )
+        };
+        if status != Z_OK {
+            return Err(zlib_error("deflateInit2", status));
+        }
+        Ok(Self {
+            inner,
+            stream,
+            buffer: vec![0; BUFFER_SIZE],
+        })
+    }
+
+    fn deflate(&mut self, input: &[u8], flush: c_int) -> std::io::Result<()> {
+        self.stream.next_in = input.as_ptr() as *mut u8;
+        self.stream.avail_in = input.len() as uInt;
+        loop {
+            self.stream.next_out = self.buffer.as_mut_ptr();
+            self.stream.avail_out = self.buffer.len() as uInt;
+            let status = unsafe { deflate(&mut *self.stream, flush) };
+            let produced = self.buffer.len() - self.stream.avail_out as usize;
+            self.inner.write_all(&self.buffer[..produced])?;
+            match status {
+                Z_STREAM_END => return Ok(()),
+                Z_OK | Z_BUF_ERROR => {}
+                status => return Err(zlib_error("deflate", status)),
+            }
+            if flush == Z_NO_FLUSH && self.stream.avail_in == 0 && self.stream.avail_out != 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Write the remaining compressed data and the gzip trailer
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        self.deflate(&[], Z_FINISH)?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for GzipEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let length = buf.len().min(uInt::MAX as usize);
+        self.deflate(&buf[..length], Z_NO_FLUSH)?;
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for GzipEncoder<W> {
+    fn drop(&mut self) {
+        unsafe { deflateEnd(&mut *self.stream) };
+    }
+}
+
+/// Reader that decompresses a gzip stream, e.g. to hash the contents of a compressed local copy
+pub struct GzipDecoder<R: Read> {
+    inner: R,
+    stream: Box<z_stream>,
+    input: Vec<u8>,
+    finished: bool,
+}
+
+impl<R: Read> GzipDecoder<R> {
+    pub fn new(inner: R) -> std::io::Result<Self> {
+        let mut stream = new_stream();
+        let status = unsafe {
+            inflateInit2_(
+                &mut *stream,
+                GZIP_WINDOW_BITS,
+                zlibVersion(),
+                std::mem::size_of::<z_stream>() as c_int,
+            )
+        };
+        if status != Z_OK {
+            return Err(zlib_error("inflateInit2", status));
+        }
+        Ok(Self {
+            inner,
+            stream,
+            input: vec![0; BUFFER_SIZE],
+            finished: false,
+        })
+    }
+}
+
+impl<R: Read> Read for GzipDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.finished || buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let mut end_of_input = false;
+            if self.stream.avail_in == 0 {
+                let read = self.inner.read(&mut self.input)?;
+                self.stream.next_in = self.input.as_mut_ptr();
+                self.stream.avail_in = read as uInt;
+                end_of_input = read == 0;
+            }
+            let capacity = buf.len().min(uInt::MAX as usize);
+            self.stream.next_out = buf.as_mut_ptr();
+            self.stream.avail_out = capacity as uInt;
+            let status = unsafe { inflate(&mut *self.stream, Z_NO_FLUSH) };
+            let produced = capacity - self.stream.avail_out as usize;
+            match status {
+                Z_STREAM_END => {
+                    self.finished = true;
+                    return Ok(produced);
+                }
+                Z_OK | Z_BUF_ERROR if produced > 0 => return Ok(produced),
+                Z_OK | Z_BUF_ERROR if end_of_input => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "Gzip stream is truncated",
+                    ))
+                }
+                Z_OK | Z_BUF_ERROR => {}
+                status => return Err(zlib_error("inflate", status)),
+            }
+        }
+    }
+}
+
+impl<R: Read> Drop for GzipDecoder<R> {
+    fn drop(&mut self) {
+        unsafe { inflateEnd(&mut *self.stream) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compress(data: &[u8], chunk_size: usize) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        let mut encoder = GzipEncoder::new(&mut compressed).unwrap();
+        for chunk in data.chunks(chunk_size.max(1)) {
+            encoder.write_all(chunk).unwrap();
+        }
+        encoder.finish().unwrap();
+        drop(encoder);
+        compressed
+    }
+
+    fn decompress(compressed: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut decompressed = Vec::new();
+        GzipDecoder::new(compressed)?.read_to_end(&mut decompressed)?;
+        Ok(decompressed)
+    }
+
+    /// Deterministic bytes that do not compress well
+    fn noise(length: usize) -> Vec<u8> {
+        let mut state: u32 = 0x2545_f491;
+        (0..length)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_empty_input() {
+        let compressed = compress(&[], 1);
+        assert_eq!(&compressed[..2], &[0x1f, 0x8b], "gzip magic");
+        assert_eq!(decompress(&compressed).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn round_trips_compressible_input() {
+        let data = b"the same line over and over\n".repeat(10_000);
+        let compressed = compress(&data, 4096);
+        assert!(compressed.len() < data.len() / 10);
+        assert_eq!(decompress(&compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn round_trips_incompressible_input_larger_than_the_buffer() {
+        let data = noise(3 * BUFFER_SIZE + 17);
+        for chunk_size in [1, 1000, BUFFER_SIZE, data.len()] {
+            let compressed = compress(&data, chunk_size);
+            assert_eq!(
+                decompress(&compressed).unwrap(),
+                data,
+                "chunks of {chunk_size}"
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_with_small_output_buffers() {
+        let data = noise(10_000);
+        let compressed = compress(&data, data.len());
+        let mut decoder = GzipDecoder::new(compressed.as_slice()).unwrap();
+        let mut decompressed = Vec::new();
+        let mut buffer = [0; 7];
+        loop {
+            let read = decoder.read(&mut buffer).unwrap();
+            if read == 0 {
+                break;
+            }
+            decompressed.extend_from_slice(&buffer[..read]);
+        }
+        assert_eq!(decompressed, data);
+    }
+
+    #[test]
+    fn reports_truncated_streams() {
+        let compressed = compress(&noise(10_000), 10_000);
+        let error = decompress(&compressed[..compressed.len() / 2]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn rejects_data_that_is_not_gzip() {
+        assert!(decompress(b"definitely not a gzip stream").is_err());
+    }
+
+    #[test]
+    fn appends_the_extension() {
+        assert_eq!(
+            Compression::Gzip.local_path(Path::new("dir/data.csv")),
+            Path::new("dir/data.csv.gz")
+        );
+    }
+}
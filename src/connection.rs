@@ -0,0 +1,783 @@
+use crate::remote_error::RemoteContext;
+use ssh2::{HashType, MethodType, Session, Sftp};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Comma separated algorithm preference lists passed to libssh2 before the handshake. A `None`
+/// leaves the library defaults in place.
+#[derive(Debug, Default, Clone)]
+pub struct AlgorithmPreferences {
+    pub cipher: Option<String>,
+    pub kex: Option<String>,
+    pub mac: Option<String>,
+}
+
+impl AlgorithmPreferences {
+    fn apply(&self, session: &Session) -> Result<(), Box<dyn std::error::Error>> {
+        let preferences: [(&Option<String>, &[MethodType], &str); 3] = [
+            (&self.kex, &[MethodType::Kex], "kex"),
+            (
+                &self.cipher,
+                &[MethodType::CryptCs, MethodType::CryptSc],
+                "cipher",
+            ),
+            (&self.mac, &[MethodType::MacCs, MethodType::MacSc], "mac"),
+        ];
+        for (preference, method_types, name) in preferences {
+            let Some(preference) = preference else {
+                continue;
+            };
+            let preference = normalize_algorithms(preference);
+            if preference.is_empty() {
+                return Err(format!("No {name} algorithms were given").into());
+            }
+            for &method_type in method_types {
+                let supported = session
+                    .supported_algs(method_type)
+                    .session_context(&format!("listing supported {name} algorithms"))?;
+                if let Some(unsupported) =
+                    preference.split(',').find(|alg| !supported.contains(alg))
+                {
+                    return Err(format!(
+                        "Unsupported {name} algorithm '{unsupported}'. Supported: {}",
+                        supported.join(",")
+                    )
+                    .into());
+                }
+                session
+                    .method_pref(method_type, &preference)
+                    .session_context(&format!("setting {name} preference {preference}"))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Comma separated algorithm list with the whitespace around each name and empty names removed,
+/// the form both the validation and libssh2 expect
+fn normalize_algorithms(preference: &str) -> String {
+    preference
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Requirements the server host key must meet before authenticating
+#[derive(Debug, Default, Clone)]
+pub struct HostKeyPolicy {
+    /// Pinned fingerprint in either the `SHA256:<base64>` or `MD5:aa:bb:...` format
+    pub fingerprint: Option<String>,
+    /// Host key algorithm names (e.g. `ssh-ed25519`) the server key must use. Empty allows any
+    pub allowed_algorithms: Vec<String>,
+    /// Minimum modulus size of RSA host keys
+    pub min_rsa_bits: Option<usize>,
+}
+
+impl HostKeyPolicy {
+    fn verify(&self, session: &Session) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(expected) = &self.fingerprint {
+            verify_host_fingerprint(expected, |hash_type| session.host_key_hash(hash_type))?;
+        }
+        if self.allowed_algorithms.is_empty() && self.min_rsa_bits.is_none() {
+            return Ok(());
+        }
+        let (key, _) = session
+            .host_key()
+            .ok_or("Server did not provide a host key")?;
+        self.verify_key_blob(key)
+    }
+
+    /// Check the algorithm and RSA modulus size of an SSH wire format host key
+    fn verify_key_blob(&self, key: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut fields = SshFields(key);
+        let algorithm = String::from_utf8_lossy(fields.next().ok_or("Malformed host key")?);
+        if !self.allowed_algorithms.is_empty()
+            && !self
+                .allowed_algorithms
+                .iter()
+                .any(|allowed| *allowed == algorithm)
+        {
+            return Err(format!(
+                "Host key policy violation. Server host key algorithm {algorithm} is not one of {}",
+                self.allowed_algorithms.join(",")
+            )
+            .into());
+        }
+        if let (Some(min_bits), "ssh-rsa") = (self.min_rsa_bits, algorithm.as_ref()) {
+            let _exponent = fields.next().ok_or("Malformed RSA host key")?;
+            let modulus = fields.next().ok_or("Malformed RSA host key")?;
+            let bits = mpint_bits(modulus);
+            if bits < min_bits {
+                return Err(format!(
+                    "Host key policy violation. Server RSA host key is {bits} bits but at least {min_bits} are required"
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// IP address or CIDR block (e.g. `10.0.0.0/8` or `2001:db8::/32`) the server address must fall
+/// within. A bare address only matches itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllowedNetwork {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl AllowedNetwork {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.address, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for AllowedNetwork {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match value.trim().split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value.trim(), None),
+        };
+        let parsed = IpAddr::from_str(address)
+            .map_err(|_| format!("'{value}' is not an IP address or CIDR block"))?;
+        let address = parsed.to_canonical();
+        // An IPv4-mapped IPv6 block (e.g. `::ffff:10.0.0.0/104`) is stored as the IPv4 block it
+        // covers, so its prefix counts the 96 bits of the mapping
+        let mapped_bits = if parsed.is_ipv6() && address.is_ipv4() {
+            96
+        } else {
+            0
+        };
+        let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => match prefix.parse::<u8>() {
+                Ok(prefix) if prefix >= mapped_bits && prefix - mapped_bits <= max_prefix => {
+                    prefix - mapped_bits
+                }
+                _ => return Err(format!("'{value}' has an invalid prefix length")),
+            },
+            None => max_prefix,
+        };
+        Ok(Self { address, prefix })
+    }
+}
+
+impl std::fmt::Display for AllowedNetwork {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+/// Iterator over the length prefixed fields of an SSH wire format key blob
+struct SshFields<'a>(&'a [u8]);
+
+impl<'a> Iterator for SshFields<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let length = u32::from_be_bytes(self.0.get(..4)?.try_into().ok()?) as usize;
+        let field = self.0.get(4..4 + length)?;
+        self.0 = &self.0[4 + length..];
+        Some(field)
+    }
+}
+
+fn mpint_bits(value: &[u8]) -> usize {
+    let Some(start) = value.iter().position(|&byte| byte != 0) else {
+        return 0;
+    };
+    (value.len() - start) * 8 - value[start].leading_zeros() as usize
+}
+
+/// How the client authenticates once the handshake completes
+#[derive(Debug, Clone)]
+pub enum Authentication {
+    Password(String),
+    PrivateKey {
+        path: PathBuf,
+        passphrase: Option<String>,
+    },
+}
+
+/// Fluent configuration for an authenticated SSH session or SFTP channel.
+///
+/// ```no_run
+/// use sftp_sync::ConnectionBuilder;
+///
+/// let sftp = ConnectionBuilder::new("example.com", "user")
+///     .port(2222)
+///     .password("secret")
+///     .connect_timeout(std::time::Duration::from_secs(10))
+///     .connect()?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug)]
+pub struct ConnectionBuilder {
+    host: String,
+    port: u16,
+    username: String,
+    authentication: Option<Authentication>,
+    algorithms: AlgorithmPreferences,
+    host_key_policy: HostKeyPolicy,
+    connect_timeout: Option<Duration>,
+    session_timeout: Option<Duration>,
+    stream: Option<TcpStream>,
+    allowed_networks: Vec<AllowedNetwork>,
+    verbose: bool,
+}
+
+impl ConnectionBuilder {
+    pub fn new(host: &str, username: &str) -> Self {
+        Self {
+            host: host.to_string(),
+            port: 22,
+            username: username.to_string(),
+            authentication: None,
+            algorithms: AlgorithmPreferences::default(),
+            host_key_policy: HostKeyPolicy::default(),
+            connect_timeout: None,
+            session_timeout: None,
+            stream: None,
+            allowed_networks: Vec::new(),
+            verbose: false,
+        }
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub fn password(mut self, password: &str) -> Self {
+        self.authentication = Some(Authentication::Password(password.to_string()));
+        self
+    }
+
+    pub fn private_key(mut self, path: &Path, passphrase: Option<&str>) -> Self {
+        self.authentication = Some(Authentication::PrivateKey {
+            path: path.to_path_buf(),
+            passphrase: passphrase.map(String::from),
+        });
+        self
+    }
+
+    pub fn authentication(mut self, authentication: Authentication) -> Self {
+        self.authentication = Some(authentication);
+        self
+    }
+
+    pub fn algorithms(mut self, algorithms: AlgorithmPreferences) -> Self {
+        self.algorithms = algorithms;
+        self
+    }
+
+    pub fn host_key_policy(mut self, host_key_policy: HostKeyPolicy) -> Self {
+        self.host_key_policy = host_key_policy;
+        self
+    }
+
+    /// Limit how long establishing the TCP connection may take
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Limit how long any single blocking libssh2 call may take once connected
+    pub fn session_timeout(mut self, timeout: Duration) -> Self {
+        self.session_timeout = Some(timeout);
+        self
+    }
+
+    /// Only connect when the resolved server address falls within one of these networks. Empty
+    /// allows any address
+    pub fn allowed_networks(mut self, networks: Vec<AllowedNetwork>) -> Self {
+        self.allowed_networks = networks;
+        self
+    }
+
+    /// Print the server banner, negotiated algorithms and authentication method once connected
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Run the SSH protocol over a stream that is already connected to the server (e.g. one
+    /// created by [stream_from_fd] or opened through a proxy) instead of connecting to the host
+    pub fn stream(mut self, stream: TcpStream) -> Self {
+        self.stream = Some(stream);
+        self
+    }
+
+    fn check_allowed(&self, address: &SocketAddr) -> Result<(), String> {
+        if self.allowed_networks.is_empty()
+            || self
+                .allowed_networks
+                .iter()
+                .any(|network| network.contains(address.ip()))
+        {
+            return Ok(());
+        }
+        Err(format!(
+            "Server address {} of {} is not within the allowed hosts {}",
+            address.ip(),
+            self.host,
+            self.allowed_networks
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(",")
+        ))
+    }
+
+    fn tcp_stream(&mut self) -> Result<TcpStream, Box<dyn std::error::Error>> {
+        if let Some(stream) = self.stream.take() {
+            if !self.allowed_networks.is_empty() {
+                self.check_allowed(&stream.peer_addr()?)?;
+            }
+            return Ok(stream);
+        }
+        if self.connect_timeout.is_none() && self.allowed_networks.is_empty() {
+            return Ok(TcpStream::connect((self.host.as_str(), self.port))?);
+        }
+        let mut last_error: Option<Box<dyn std::error::Error>> = None;
+        for address in (self.host.as_str(), self.port).to_socket_addrs()? {
+            if let Err(error) = self.check_allowed(&address) {
+                last_error = Some(error.into());
+                continue;
+            }
+            let result = match self.connect_timeout {
+                Some(timeout) => TcpStream::connect_timeout(&address, timeout),
+                None => TcpStream::connect(address),
+            };
+            match result {
+                Ok(stream) => return Ok(stream),
+                Err(error) => last_error = Some(error.into()),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| format!("Could not resolve {}", self.host).into()))
+    }
+
+    /// Connect, verify the host key and authenticate
+    pub fn connect_session(mut self) -> Result<Session, Box<dyn std::error::Error>> {
+        let tcp = self.tcp_stream()?;
+        let peer_address = tcp.peer_addr().ok();
+        let mut ssh_session = Session::new().session_context("creating an SSH session")?;
+        self.algorithms.apply(&ssh_session)?;
+        ssh_session.set_tcp_stream(tcp);
+        if let Some(timeout) = self.session_timeout {
+            ssh_session.set_timeout(timeout.as_millis().min(u32::MAX as u128) as u32);
+        }
+        ssh_session
+            .handshake()
+            .session_context("performing the SSH handshake")?;
+        self.host_key_policy.verify(&ssh_session)?;
+        match &self.authentication {
+            Some(Authentication::PrivateKey { path, passphrase }) => ssh_session
+                .userauth_pubkey_file(&self.username, None, path, passphrase.as_deref())
+                .session_context(&format!(
+                    "authenticating as {} with key {path:?}",
+                    self.username
+                ))?,
+            Some(Authentication::Password(password)) => ssh_session
+                .userauth_password(&self.username, password)
+                .session_context(&format!(
+                    "authenticating as {} with a password",
+                    self.username
+                ))?,
+            None => return Err("A password or private key is required to authenticate".into()),
+        }
+        if self.verbose {
+            self.print_connection_details(&ssh_session, peer_address);
+        }
+        Ok(ssh_session)
+    }
+
+    fn print_connection_details(&self, session: &Session, peer_address: Option<SocketAddr>) {
+        match peer_address {
+            Some(address) => println!("Connected to {} ({address})", self.host),
+            None => println!("Connected to {}", self.host),
+        }
+        println!(
+            "Server banner: {}",
+            session.banner().unwrap_or("(not provided)")
+        );
+        let methods = [
+            ("Key exchange", MethodType::Kex),
+            ("Host key", MethodType::HostKey),
+            ("Cipher client to server", MethodType::CryptCs),
+            ("Cipher server to client", MethodType::CryptSc),
+            ("MAC client to server", MethodType::MacCs),
+            ("MAC server to client", MethodType::MacSc),
+        ];
+        for (name, method_type) in methods {
+            println!(
+                "{name}: {}",
+                session.methods(method_type).unwrap_or("(unknown)")
+            );
+        }
+        match &self.authentication {
+            Some(Authentication::PrivateKey { path, .. }) => {
+                println!("Authenticated as {} with key {path:?}", self.username)
+            }
+            Some(Authentication::Password(_)) => {
+                println!("Authenticated as {} with a password", self.username)
+            }
+            None => {}
+        }
+    }
+
+    /// Connect and open an SFTP channel on the authenticated session
+    pub fn connect(self) -> Result<Sftp, Box<dyn std::error::Error>> {
+        Ok(self
+            .connect_session()?
+            .sftp()
+            .session_context("starting the SFTP subsystem")?)
+    }
+}
+
+/// Take ownership of an inherited file descriptor that is already connected to the SSH server.
+/// The descriptor must be a connected socket. It is closed when the session is dropped.
+#[cfg(unix)]
+pub fn stream_from_fd(fd: std::os::fd::RawFd) -> Result<TcpStream, Box<dyn std::error::Error>> {
+    use std::os::fd::FromRawFd;
+
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } != 0 {
+        return Err(format!(
+            "File descriptor {fd} is not open. {}",
+            std::io::Error::last_os_error()
+        )
+        .into());
+    }
+    if stat.st_mode & libc::S_IFMT != libc::S_IFSOCK {
+        return Err(format!("File descriptor {fd} is not a socket").into());
+    }
+    let mut address: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut length = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getpeername(
+            fd,
+            &mut address as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut length,
+        )
+    };
+    if result != 0 {
+        return Err(format!(
+            "File descriptor {fd} is not a connected socket. {}",
+            std::io::Error::last_os_error()
+        )
+        .into());
+    }
+    Ok(unsafe { TcpStream::from_raw_fd(fd) })
+}
+
+/// Compare the server's host key against a pinned fingerprint in either the OpenSSH
+/// `SHA256:<base64>` format or the legacy `MD5:aa:bb:...` format (the `MD5:` prefix is optional).
+/// `host_key_hash` returns the server's host key hashed with the requested algorithm
+fn verify_host_fingerprint<'a>(
+    expected: &str,
+    host_key_hash: impl Fn(HashType) -> Option<&'a [u8]>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let expected = expected.trim();
+    let actual = if let Some(encoded) = expected.strip_prefix("SHA256:") {
+        let hash = host_key_hash(HashType::Sha256)
+            .ok_or("Server did not provide a SHA256 host key hash")?;
+        let actual = base64_encode(hash);
+        if encoded.trim_end_matches('=') == actual {
+            return Ok(());
+        }
+        format!("SHA256:{actual}")
+    } else {
+        let hash =
+            host_key_hash(HashType::Md5).ok_or("Server did not provide an MD5 host key hash")?;
+        let actual = hash
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<Vec<_>>()
+            .join(":");
+        let encoded = expected.strip_prefix("MD5:").unwrap_or(expected);
+        if encoded.eq_ignore_ascii_case(&actual) {
+            return Ok(());
+        }
+        format!("MD5:{actual}")
+    };
+    Err(format!(
+        "Host key fingerprint mismatch. Expected {expected} but the server presented {actual}"
+    )
+    .into())
+}
+
+/// Standard base64 without padding, matching the output of `ssh-keygen -l`
+fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let value = chunk.iter().enumerate().fold(0u32, |value, (i, &byte)| {
+            value | (byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..=chunk.len() {
+            encoded.push(ALPHABET[(value >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(value: &str) -> AllowedNetwork {
+        value.parse().unwrap()
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    /// SSH wire format blob of length prefixed fields
+    fn key_blob(fields: &[&[u8]]) -> Vec<u8> {
+        let mut blob = Vec::new();
+        for field in fields {
+            blob.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            blob.extend_from_slice(field);
+        }
+        blob
+    }
+
+    /// RSA host key blob with a modulus of exactly `bits` bits
+    fn rsa_key(bits: usize) -> Vec<u8> {
+        let mut modulus = vec![0u8; bits.div_ceil(8) + 1];
+        modulus[1] = 1 << ((bits - 1) % 8);
+        key_blob(&[b"ssh-rsa", &[1, 0, 1], &modulus])
+    }
+
+    #[test]
+    fn normalizes_algorithm_lists() {
+        assert_eq!(
+            normalize_algorithms("aes128-ctr, aes256-ctr"),
+            "aes128-ctr,aes256-ctr"
+        );
+        assert_eq!(
+            normalize_algorithms(" curve25519-sha256 ,,diffie-hellman-group14-sha256, "),
+            "curve25519-sha256,diffie-hellman-group14-sha256"
+        );
+        assert_eq!(normalize_algorithms("hmac-sha2-256"), "hmac-sha2-256");
+        assert_eq!(normalize_algorithms(" , "), "");
+    }
+
+    #[test]
+    fn parses_addresses_and_blocks() {
+        assert_eq!(network("10.0.0.0/8").to_string(), "10.0.0.0/8");
+        assert_eq!(network(" 192.168.1.5 ").to_string(), "192.168.1.5/32");
+        assert_eq!(network("2001:db8::/32").to_string(), "2001:db8::/32");
+        assert_eq!(network("2001:db8::1").to_string(), "2001:db8::1/128");
+        assert_eq!(network("::ffff:10.0.0.0/104").to_string(), "10.0.0.0/8");
+        assert_eq!(network("::ffff:10.1.2.3").to_string(), "10.1.2.3/32");
+    }
+
+    #[test]
+    fn rejects_malformed_blocks() {
+        for value in ["example.com", "10.0.0/8", "", "/8"] {
+            assert_eq!(
+                value.parse::<AllowedNetwork>().unwrap_err(),
+                format!("'{value}' is not an IP address or CIDR block"),
+            );
+        }
+        for value in [
+            "10.0.0.0/33",
+            "10.0.0.0/",
+            "10.0.0.0/-1",
+            "10.0.0.0/ 8",
+            "10.0.0.0/x",
+            "10.0.0.0/8/8",
+            "::/129",
+            "::ffff:10.0.0.0/95",
+            "::ffff:10.0.0.0/129",
+        ] {
+            assert_eq!(
+                value.parse::<AllowedNetwork>().unwrap_err(),
+                format!("'{value}' has an invalid prefix length"),
+            );
+        }
+    }
+
+    #[test]
+    fn matches_ipv4_blocks() {
+        let block = network("10.1.0.0/16");
+        assert!(block.contains(ip("10.1.0.0")));
+        assert!(block.contains(ip("10.1.255.255")));
+        assert!(!block.contains(ip("10.2.0.0")));
+        assert!(!block.contains(ip("::a01:0")));
+        // Host bits in the block itself are ignored
+        assert!(network("10.1.2.3/8").contains(ip("10.200.0.1")));
+    }
+
+    #[test]
+    fn matches_prefix_extremes() {
+        let everything = network("0.0.0.0/0");
+        assert!(everything.contains(ip("0.0.0.0")));
+        assert!(everything.contains(ip("255.255.255.255")));
+        assert!(!everything.contains(ip("2001:db8::1")));
+        let host = network("192.168.1.5/32");
+        assert!(host.contains(ip("192.168.1.5")));
+        assert!(!host.contains(ip("192.168.1.4")));
+        assert!(!host.contains(ip("192.168.1.6")));
+
+        let everything = network("::/0");
+        assert!(everything.contains(ip("2001:db8::1")));
+        assert!(everything.contains(ip("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff")));
+        assert!(!everything.contains(ip("10.0.0.1")));
+        let host = network("2001:db8::1/128");
+        assert!(host.contains(ip("2001:db8::1")));
+        assert!(!host.contains(ip("2001:db8::2")));
+    }
+
+    #[test]
+    fn matches_ipv4_mapped_addresses() {
+        assert!(network("10.0.0.0/8").contains(ip("::ffff:10.20.30.40")));
+        assert!(!network("10.0.0.0/8").contains(ip("::ffff:11.0.0.1")));
+        assert!(network("::ffff:10.0.0.0/104").contains(ip("10.20.30.40")));
+        assert!(network("::ffff:10.0.0.0/104").contains(ip("::ffff:10.20.30.40")));
+        assert!(!network("::ffff:10.0.0.0/104").contains(ip("11.0.0.1")));
+    }
+
+    #[test]
+    fn checks_server_address_against_allowed_networks() {
+        let address = |value: &str| SocketAddr::new(ip(value), 22);
+        let builder = ConnectionBuilder::new("example.com", "user");
+        assert!(builder.check_allowed(&address("203.0.113.9")).is_ok());
+
+        let builder =
+            builder.allowed_networks(vec![network("10.0.0.0/8"), network("2001:db8::/32")]);
+        assert!(builder.check_allowed(&address("10.9.8.7")).is_ok());
+        assert!(builder.check_allowed(&address("2001:db8::5")).is_ok());
+        assert_eq!(
+            builder.check_allowed(&address("203.0.113.9")).unwrap_err(),
+            "Server address 203.0.113.9 of example.com is not within the allowed hosts 10.0.0.0/8,2001:db8::/32"
+        );
+    }
+
+    #[test]
+    fn encodes_base64_without_padding() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg");
+        assert_eq!(base64_encode(b"fo"), "Zm8");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(base64_encode(b"foob"), "Zm9vYg");
+        assert_eq!(base64_encode(b"fooba"), "Zm9vYmE");
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64_encode(&[0xfb, 0xff, 0xbf]), "+/+/");
+    }
+
+    #[test]
+    fn verifies_sha256_fingerprints() {
+        let hash = [0xab; 32];
+        let fingerprint = format!("SHA256:{}", base64_encode(&hash));
+        let lookup = |hash_type| matches!(hash_type, HashType::Sha256).then_some(&hash[..]);
+        assert!(verify_host_fingerprint(&fingerprint, lookup).is_ok());
+        assert!(verify_host_fingerprint(&format!(" {fingerprint}= "), lookup).is_ok());
+
+        let error = verify_host_fingerprint("SHA256:AAAA", lookup).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Host key fingerprint mismatch. Expected SHA256:AAAA but the server presented {fingerprint}"
+            )
+        );
+        // The algorithm prefix is case sensitive, so anything else is read as MD5
+        assert!(verify_host_fingerprint(&fingerprint.to_lowercase(), lookup).is_err());
+        assert!(verify_host_fingerprint(&fingerprint, |_| None).is_err());
+    }
+
+    #[test]
+    fn verifies_md5_fingerprints() {
+        let hash: Vec<u8> = (0..16).collect();
+        let lookup = |hash_type| matches!(hash_type, HashType::Md5).then_some(&hash[..]);
+        let fingerprint = "00:01:02:03:04:05:06:07:08:09:0a:0b:0c:0d:0e:0f";
+        assert!(verify_host_fingerprint(fingerprint, lookup).is_ok());
+        assert!(verify_host_fingerprint(&format!("MD5:{fingerprint}"), lookup).is_ok());
+        assert!(verify_host_fingerprint(&fingerprint.to_uppercase(), lookup).is_ok());
+        assert!(verify_host_fingerprint("00:01:02", lookup).is_err());
+        assert!(verify_host_fingerprint(&fingerprint.replace(':', ""), lookup).is_err());
+        assert!(verify_host_fingerprint(fingerprint, |_| None).is_err());
+    }
+
+    #[test]
+    fn splits_key_blob_fields() {
+        let blob = key_blob(&[b"ssh-ed25519", b"", &[7; 32]]);
+        let fields: Vec<&[u8]> = SshFields(&blob).collect();
+        assert_eq!(fields, [&b"ssh-ed25519"[..], b"", &[7; 32]]);
+
+        // A length running past the end of the blob stops iteration
+        assert_eq!(SshFields(&blob[..blob.len() - 1]).count(), 2);
+        assert_eq!(SshFields(&[0, 0, 0]).count(), 0);
+        assert_eq!(SshFields(&[0xff, 0xff, 0xff, 0xff, 1, 2]).count(), 0);
+    }
+
+    #[test]
+    fn counts_mpint_bits() {
+        assert_eq!(mpint_bits(&[]), 0);
+        assert_eq!(mpint_bits(&[0, 0]), 0);
+        assert_eq!(mpint_bits(&[1]), 1);
+        assert_eq!(mpint_bits(&[0, 0x80, 0]), 16);
+        assert_eq!(mpint_bits(&[0x7f, 0xff]), 15);
+    }
+
+    #[test]
+    fn enforces_host_key_algorithms() {
+        let policy = HostKeyPolicy {
+            allowed_algorithms: vec!["ssh-ed25519".to_string(), "ssh-rsa".to_string()],
+            ..HostKeyPolicy::default()
+        };
+        assert!(policy
+            .verify_key_blob(&key_blob(&[b"ssh-ed25519", &[7; 32]]))
+            .is_ok());
+        assert!(policy.verify_key_blob(&rsa_key(1024)).is_ok());
+        let error = policy
+            .verify_key_blob(&key_blob(&[b"ecdsa-sha2-nistp256", b"nistp256"]))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Host key policy violation. Server host key algorithm ecdsa-sha2-nistp256 is not one of ssh-ed25519,ssh-rsa"
+        );
+        assert!(policy.verify_key_blob(&[]).is_err());
+        assert!(policy.verify_key_blob(&[0, 0, 0, 9, b's']).is_err());
+    }
+
+    #[test]
+    fn enforces_minimum_rsa_size() {
+        let policy = HostKeyPolicy {
+            min_rsa_bits: Some(2048),
+            ..HostKeyPolicy::default()
+        };
+        assert!(policy.verify_key_blob(&rsa_key(2048)).is_ok());
+        assert!(policy.verify_key_blob(&rsa_key(4096)).is_ok());
+        assert_eq!(
+            policy.verify_key_blob(&rsa_key(2047)).unwrap_err().to_string(),
+            "Host key policy violation. Server RSA host key is 2047 bits but at least 2048 are required"
+        );
+        // Other key types have no modulus to check
+        assert!(policy
+            .verify_key_blob(&key_blob(&[b"ssh-ed25519", &[7; 32]]))
+            .is_ok());
+        assert!(policy
+            .verify_key_blob(&key_blob(&[b"ssh-rsa", &[1, 0, 1]]))
+            .is_err());
+    }
+}
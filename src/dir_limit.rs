@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Split transfers into lanes that are each worked through sequentially, so running the lanes in
+/// parallel keeps at most `limit` transfers reading from the same remote directory at once.
+///
+/// Every directory gets up to `limit` lanes with its files dealt out round robin in their
+/// original order. No worker ever waits for a busy directory: a worker that finishes a lane picks
+/// up another one, so files in different directories still run fully parallel.
+pub fn directory_lanes<T, F>(items: Vec<T>, limit: usize, remote_path: F) -> Vec<Vec<T>>
+where
+    F: Fn(&T) -> &Path,
+{
+    let limit = limit.max(1);
+    let mut lanes: Vec<Vec<T>> = Vec::new();
+    // Lanes of each directory and how many of its files have been dealt
+    let mut directories: HashMap<PathBuf, (Vec<usize>, usize)> = HashMap::new();
+    for item in items {
+        let directory = remote_path(&item)
+            .parent()
+            .unwrap_or(Path::new(""))
+            .to_path_buf();
+        let (directory_lanes, dealt) = directories.entry(directory).or_default();
+        if directory_lanes.len() < limit {
+            directory_lanes.push(lanes.len());
+            lanes.push(Vec::new());
+        }
+        lanes[directory_lanes[*dealt % limit]].push(item);
+        *dealt += 1;
+    }
+    lanes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lanes(paths: &[&'static str], limit: usize) -> Vec<Vec<&'static str>> {
+        directory_lanes(paths.to_vec(), limit, |path| Path::new(*path))
+    }
+
+    #[test]
+    fn deals_each_directory_into_at_most_limit_lanes() {
+        assert_eq!(
+            lanes(&["a/1", "a/2", "a/3", "a/4", "a/5"], 2),
+            vec![vec!["a/1", "a/3", "a/5"], vec!["a/2", "a/4"]]
+        );
+    }
+
+    #[test]
+    fn directories_get_separate_lanes() {
+        assert_eq!(
+            lanes(&["a/1", "b/1", "a/2", "b/2", "c/1"], 1),
+            vec![vec!["a/1", "a/2"], vec!["b/1", "b/2"], vec!["c/1"]]
+        );
+    }
+
+    #[test]
+    fn later_directories_do_not_share_lanes_with_earlier_ones() {
+        let result = lanes(&["a/1", "b/1", "b/2", "a/2", "a/3"], 2);
+        assert_eq!(
+            result,
+            vec![vec!["a/1", "a/3"], vec!["b/1"], vec!["b/2"], vec!["a/2"]]
+        );
+    }
+
+    #[test]
+    fn zero_limit_is_treated_as_one() {
+        assert_eq!(lanes(&["a/1", "a/2"], 0), vec![vec!["a/1", "a/2"]]);
+    }
+}
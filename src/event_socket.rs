@@ -0,0 +1,146 @@
+use sftp_sync::events::SyncEvent;
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Streams sync events as newline delimited JSON to consumers of a Unix socket. If a listener
+/// already exists at the path the events are sent to it, otherwise a listener is created and
+/// every client that connects receives the events from that point on. Clients that disconnect or
+/// stop reading are dropped without interrupting the sync. Events are written by a background
+/// thread so a slow client never stalls the transfers sending them.
+pub struct EventSocket {
+    lines: Sender<(String, Option<Sender<()>>)>,
+}
+
+impl EventSocket {
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let clients = Arc::new(Mutex::new(Vec::new()));
+        if let Ok(stream) = UnixStream::connect(path) {
+            stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+            clients
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(stream);
+            return Ok(Self::start_writer(clients));
+        }
+
+        // Only a stale socket left behind by an earlier run is replaced
+        match path.symlink_metadata() {
+            Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+            Ok(_) => {
+                return Err(std::io::Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("{path:?} exists and is not a socket"),
+                ))
+            }
+            Err(error) if error.kind() == ErrorKind::NotFound => {}
+            Err(error) => return Err(error),
+        }
+        let listener = UnixListener::bind(path)?;
+        let accepted = clients.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if stream.set_write_timeout(Some(WRITE_TIMEOUT)).is_ok() {
+                    accepted
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .push(stream);
+                }
+            }
+        });
+        Ok(Self::start_writer(clients))
+    }
+
+    fn start_writer(clients: Arc<Mutex<Vec<UnixStream>>>) -> Self {
+        let (lines, received) = channel::<(String, Option<Sender<()>>)>();
+        std::thread::spawn(move || {
+            for (line, written) in received {
+                clients
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .retain_mut(|client| client.write_all(line.as_bytes()).is_ok());
+                if let Some(written) = written {
+                    let _ = written.send(());
+                }
+            }
+        });
+        Self { lines }
+    }
+
+    /// Queue an event for the clients. The last event of a sync waits until it is written since
+    /// the process may exit right after it
+    pub fn send(&self, event: &SyncEvent) {
+        let line = format!("{}\n", event.to_json());
+        if !matches!(event, SyncEvent::SyncFinished { .. }) {
+            let _ = self.lines.send((line, None));
+            return;
+        }
+        let (written, wait) = channel();
+        if self.lines.send((line, Some(written))).is_ok() {
+            let _ = wait.recv();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("sftp-sync-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn refuses_to_replace_a_regular_file() {
+        let path = temp_path("events-file");
+        std::fs::write(&path, "keep me").unwrap();
+        let error = EventSocket::open(&path).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "keep me");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replaces_a_stale_socket() {
+        let path = temp_path("events-stale.sock");
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(EventSocket::open(&path).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sends_events_to_an_existing_listener() {
+        let path = temp_path("events-listener.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let socket = EventSocket::open(&path).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        socket.send(&SyncEvent::SyncFinished {
+            transferred: 2,
+            failed: 1,
+        });
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        assert_eq!(
+            line,
+            format!(
+                "{}\n",
+                SyncEvent::SyncFinished {
+                    transferred: 2,
+                    failed: 1
+                }
+                .to_json()
+            )
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+use crate::json;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Progress events emitted while a sync runs. Handlers are registered through
+/// [crate::SftpSync::with_event_handler].
+#[derive(Debug, Clone)]
+pub enum SyncEvent {
+    /// Discovery finished and the totals of the transfer queue are known
+    PlanReady {
+        files: usize,
+        bytes: u64,
+    },
+    TransferStarted {
+        remote_path: PathBuf,
+        local_path: PathBuf,
+    },
+    TransferCompleted {
+        remote_path: PathBuf,
+        local_path: PathBuf,
+        bytes: u64,
+        duration: Duration,
+    },
+    TransferFailed {
+        remote_path: PathBuf,
+        local_path: PathBuf,
+        error: String,
+    },
+    SyncFinished {
+        transferred: usize,
+        failed: usize,
+    },
+}
+
+impl SyncEvent {
+    pub fn to_json(&self) -> String {
+        match self {
+            Self::PlanReady { files, bytes } => json::object([
+                ("event", json::string("plan_ready")),
+                ("files", files.to_string()),
+                ("bytes", bytes.to_string()),
+            ]),
+            Self::TransferStarted {
+                remote_path,
+                local_path,
+            } => json::object([
+                ("event", json::string("transfer_started")),
+                ("remote_path", json::string(&remote_path.to_string_lossy())),
+                ("local_path", json::string(&local_path.to_string_lossy())),
+            ]),
+            Self::TransferCompleted {
+                remote_path,
+                local_path,
+                bytes,
+                duration,
+            } => json::object([
+                ("event", json::string("transfer_completed")),
+                ("remote_path", json::string(&remote_path.to_string_lossy())),
+                ("local_path", json::string(&local_path.to_string_lossy())),
+                ("bytes", bytes.to_string()),
+                ("duration_ms", duration.as_millis().to_string()),
+            ]),
+            Self::TransferFailed {
+                remote_path,
+                local_path,
+                error,
+            } => json::object([
+                ("event", json::string("transfer_failed")),
+                ("remote_path", json::string(&remote_path.to_string_lossy())),
+                ("local_path", json::string(&local_path.to_string_lossy())),
+                ("error", json::string(error)),
+            ]),
+            Self::SyncFinished {
+                transferred,
+                failed,
+            } => json::object([
+                ("event", json::string("sync_finished")),
+                ("transferred", transferred.to_string()),
+                ("failed", failed.to_string()),
+            ]),
+        }
+    }
+}
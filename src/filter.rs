@@ -0,0 +1,563 @@
+use crate::glob::glob_match;
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter};
+
+/// `--exclude` patterns. Like gitignore, patterns containing a slash are glob matched against the
+/// path relative to the remote directory while plain names match an entry anywhere in the tree.
+/// With `ignore_case` both kinds are compared case-insensitively.
+#[derive(Debug, Default)]
+pub(crate) struct ExcludeRules {
+    /// Sorted for binary search, lowercase with `ignore_case`
+    names: Vec<String>,
+    paths: Vec<String>,
+    ignore_case: bool,
+}
+
+impl ExcludeRules {
+    pub(crate) fn new(patterns: Vec<String>, ignore_case: bool) -> Self {
+        let (paths, mut names): (Vec<String>, Vec<String>) = patterns
+            .into_iter()
+            .map(|pattern| pattern.trim_end_matches('/').to_string())
+            .partition(|pattern| pattern.contains('/'));
+        let paths = paths
+            .into_iter()
+            .map(|pattern| pattern.trim_start_matches('/').to_string())
+            .collect();
+        if ignore_case {
+            names
+                .iter_mut()
+                .for_each(|pattern| *pattern = pattern.to_lowercase());
+        }
+        names.sort();
+        Self {
+            names,
+            paths,
+            ignore_case,
+        }
+    }
+
+    pub(crate) fn excludes_name(&self, file_name: &str) -> bool {
+        let file_name = if self.ignore_case {
+            Cow::Owned(file_name.to_lowercase())
+        } else {
+            Cow::Borrowed(file_name)
+        };
+        self.names
+            .binary_search_by(|name| name.as_str().cmp(&file_name))
+            .is_ok()
+    }
+
+    pub(crate) fn excludes_path(&self, relative_path: &str) -> bool {
+        self.paths
+            .iter()
+            .any(|pattern| match_glob(pattern, relative_path, self.ignore_case))
+    }
+}
+
+/// [glob_match], lowercasing both sides with `ignore_case`
+pub(crate) fn match_glob(pattern: &str, text: &str, ignore_case: bool) -> bool {
+    if ignore_case {
+        glob_match(&pattern.to_lowercase(), &text.to_lowercase())
+    } else {
+        glob_match(pattern, text)
+    }
+}
+
+/// Remote entry attributes available to a filter expression
+pub struct FilterEntry<'a> {
+    /// Path relative to the remote directory using `/` separators
+    pub relative_path: &'a str,
+    pub size: Option<u64>,
+    pub mtime: Option<u64>,
+    pub is_dir: bool,
+}
+
+/// Parsed `--filter` expression.
+///
+/// ```text
+/// expr       := and ("||" and)*
+/// and        := unary ("&&" unary)*
+/// unary      := "!" unary | "(" expr ")" | predicate
+/// predicate  := "is_dir"
+///             | ("size" | "mtime") ("<" | "<=" | ">" | ">=" | "==" | "!=") number
+///             | "name" ("~" | "!~" | "==" | "!=") "quoted string"
+/// ```
+///
+/// Sizes accept K, M and G suffixes (powers of 1024). Mtimes are Unix timestamps in seconds.
+/// `name ~` matches a glob in the same way as the other glob options, so patterns without a `/`
+/// are matched against the file name only.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+    IsDir,
+    Size(Comparison, u64),
+    Mtime(Comparison, u64),
+    Name {
+        pattern: String,
+        glob: bool,
+        negate: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Equal,
+    NotEqual,
+}
+
+impl Comparison {
+    fn apply(self, left: u64, right: u64) -> bool {
+        match self {
+            Self::Less => left < right,
+            Self::LessOrEqual => left <= right,
+            Self::Greater => left > right,
+            Self::GreaterOrEqual => left >= right,
+            Self::Equal => left == right,
+            Self::NotEqual => left != right,
+        }
+    }
+}
+
+impl Filter {
+    pub fn parse(expression: &str) -> Result<Self, FilterError> {
+        let tokens = tokenize(expression)?;
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+        };
+        let filter = parser.parse_or()?;
+        match parser.tokens.get(parser.position) {
+            None => Ok(filter),
+            Some(token) => Err(FilterError(format!(
+                "Unexpected {token:?} after the expression"
+            ))),
+        }
+    }
+
+    /// Evaluate the expression. `glob` performs the glob match for `name ~` so the caller's
+    /// case sensitivity settings apply, and `ignore_case` does the same for `name ==`.
+    /// Comparisons against a missing size or mtime are false.
+    pub fn matches(
+        &self,
+        entry: &FilterEntry,
+        ignore_case: bool,
+        glob: &dyn Fn(&str, &str) -> bool,
+    ) -> bool {
+        match self {
+            Self::And(left, right) => {
+                left.matches(entry, ignore_case, glob) && right.matches(entry, ignore_case, glob)
+            }
+            Self::Or(left, right) => {
+                left.matches(entry, ignore_case, glob) || right.matches(entry, ignore_case, glob)
+            }
+            Self::Not(inner) => !inner.matches(entry, ignore_case, glob),
+            Self::IsDir => entry.is_dir,
+            Self::Size(comparison, value) => entry
+                .size
+                .is_some_and(|size| comparison.apply(size, *value)),
+            Self::Mtime(comparison, value) => entry
+                .mtime
+                .is_some_and(|mtime| comparison.apply(mtime, *value)),
+            Self::Name {
+                pattern,
+                glob: true,
+                negate,
+            } => glob(pattern, entry.relative_path) != *negate,
+            Self::Name {
+                pattern,
+                glob: false,
+                negate,
+            } => {
+                let name = entry.relative_path.rsplit('/').next().unwrap_or_default();
+                let equal = if ignore_case {
+                    name.to_lowercase() == pattern.to_lowercase()
+                } else {
+                    name == pattern
+                };
+                equal != *negate
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FilterError(String);
+
+impl Display for FilterError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid filter expression. {}", self.0)
+    }
+}
+
+impl std::error::Error for FilterError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Identifier(String),
+    Number(u64),
+    String(String),
+    Operator(&'static str),
+    Open,
+    Close,
+}
+
+const OPERATORS: [&str; 12] = [
+    "&&", "||", "<=", ">=", "==", "!=", "!~", "<", ">", "~", "!", "=",
+];
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, FilterError> {
+    let mut tokens = Vec::new();
+    let mut rest = expression.trim_start();
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap_or_default();
+        if c == '(' || c == ')' {
+            tokens.push(if c == '(' { Token::Open } else { Token::Close });
+            rest = &rest[1..];
+        } else if c == '"' {
+            let end = rest[1..]
+                .find('"')
+                .ok_or_else(|| FilterError("Unterminated string".to_string()))?;
+            tokens.push(Token::String(rest[1..=end].to_string()));
+            rest = &rest[end + 2..];
+        } else if let Some(operator) = OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+            if *operator == "=" {
+                return Err(FilterError("Use == to compare values".to_string()));
+            }
+            tokens.push(Token::Operator(operator));
+            rest = &rest[operator.len()..];
+        } else if c.is_ascii_digit() {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            tokens.push(Token::Number(parse_size(&rest[..end])?));
+            rest = &rest[end..];
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            tokens.push(Token::Identifier(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else {
+            return Err(FilterError(format!("Unexpected character '{c}'")));
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+fn parse_size(value: &str) -> Result<u64, FilterError> {
+    let (digits, multiplier) = match value.char_indices().last() {
+        Some((index, 'k' | 'K')) => (&value[..index], 1024),
+        Some((index, 'm' | 'M')) => (&value[..index], 1024 * 1024),
+        Some((index, 'g' | 'G')) => (&value[..index], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+    let number = digits
+        .parse::<u64>()
+        .map_err(|_| FilterError(format!("Invalid number '{value}'")))?;
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| FilterError(format!("Number '{value}' is too large")))
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat_operator(&mut self, operator: &'static str) -> bool {
+        if self.tokens.get(self.position) == Some(&Token::Operator(operator)) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    fn parse_or(&mut self) -> Result<Filter, FilterError> {
+        let mut left = self.parse_and()?;
+        while self.eat_operator("||") {
+            left = Filter::Or(Box::new(left), Box::new(self.parse_and()?));
+        }
+        Ok(left)
+    }
+
+    fn parse_and(&mut self) -> Result<Filter, FilterError> {
+        let mut left = self.parse_unary()?;
+        while self.eat_operator("&&") {
+            left = Filter::And(Box::new(left), Box::new(self.parse_unary()?));
+        }
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> Result<Filter, FilterError> {
+        if self.eat_operator("!") {
+            return Ok(Filter::Not(Box::new(self.parse_unary()?)));
+        }
+        match self.next() {
+            Some(Token::Open) => {
+                let inner = self.parse_or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(inner),
+                    _ => Err(FilterError("Expected )".to_string())),
+                }
+            }
+            Some(Token::Identifier(field)) => self.parse_predicate(&field),
+            Some(token) => Err(FilterError(format!("Unexpected {token:?}"))),
+            None => Err(FilterError("Unexpected end of expression".to_string())),
+        }
+    }
+
+    fn parse_predicate(&mut self, field: &str) -> Result<Filter, FilterError> {
+        if field == "is_dir" {
+            return Ok(Filter::IsDir);
+        }
+        let operator = match self.next() {
+            Some(Token::Operator(operator)) => operator,
+            _ => return Err(FilterError(format!("Expected an operator after {field}"))),
+        };
+        match (field, self.next()) {
+            ("size" | "mtime", Some(Token::Number(value))) => {
+                let comparison = match operator {
+                    "<" => Comparison::Less,
+                    "<=" => Comparison::LessOrEqual,
+                    ">" => Comparison::Greater,
+                    ">=" => Comparison::GreaterOrEqual,
+                    "==" => Comparison::Equal,
+                    "!=" => Comparison::NotEqual,
+                    _ => {
+                        return Err(FilterError(format!(
+                            "{operator} cannot be used with {field}"
+                        )))
+                    }
+                };
+                Ok(if field == "size" {
+                    Filter::Size(comparison, value)
+                } else {
+                    Filter::Mtime(comparison, value)
+                })
+            }
+            ("name", Some(Token::String(pattern))) => {
+                let (glob, negate) = match operator {
+                    "~" => (true, false),
+                    "!~" => (true, true),
+                    "==" => (false, false),
+                    "!=" => (false, true),
+                    _ => return Err(FilterError(format!("{operator} cannot be used with name"))),
+                };
+                Ok(Filter::Name {
+                    pattern,
+                    glob,
+                    negate,
+                })
+            }
+            ("size" | "mtime", _) => Err(FilterError(format!("Expected a number after {field}"))),
+            ("name", _) => Err(FilterError(
+                "Expected a quoted string after name".to_string(),
+            )),
+            _ => Err(FilterError(format!(
+                "Unknown field {field}. Expected size, mtime, name or is_dir"
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(relative_path: &str, size: Option<u64>, is_dir: bool) -> FilterEntry<'_> {
+        FilterEntry {
+            relative_path,
+            size,
+            mtime: Some(1_700_000_000),
+            is_dir,
+        }
+    }
+
+    fn matches(expression: &str, entry: &FilterEntry, ignore_case: bool) -> bool {
+        Filter::parse(expression)
+            .unwrap()
+            .matches(entry, ignore_case, &|pattern, text| {
+                match_glob(pattern, text, ignore_case)
+            })
+    }
+
+    fn excludes(patterns: &[&str], ignore_case: bool) -> ExcludeRules {
+        ExcludeRules::new(
+            patterns.iter().map(|pattern| pattern.to_string()).collect(),
+            ignore_case,
+        )
+    }
+
+    fn error(expression: &str) -> String {
+        Filter::parse(expression).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parses_precedence_and_grouping() {
+        let size = Filter::Size(Comparison::Greater, 10);
+        let dir = Filter::IsDir;
+        let mtime = Filter::Mtime(Comparison::Less, 5);
+        assert_eq!(
+            Filter::parse("size > 10 || is_dir && mtime < 5").unwrap(),
+            Filter::Or(
+                Box::new(size.clone()),
+                Box::new(Filter::And(Box::new(dir.clone()), Box::new(mtime.clone())))
+            )
+        );
+        assert_eq!(
+            Filter::parse("!(size > 10 || is_dir) && mtime < 5").unwrap(),
+            Filter::And(
+                Box::new(Filter::Not(Box::new(Filter::Or(
+                    Box::new(size),
+                    Box::new(dir)
+                )))),
+                Box::new(mtime)
+            )
+        );
+    }
+
+    #[test]
+    fn parses_size_suffixes() {
+        assert_eq!(
+            Filter::parse("size >= 2k").unwrap(),
+            Filter::Size(Comparison::GreaterOrEqual, 2048)
+        );
+        assert_eq!(
+            Filter::parse("size < 3M").unwrap(),
+            Filter::Size(Comparison::Less, 3 * 1024 * 1024)
+        );
+        assert_eq!(
+            Filter::parse("size != 1g").unwrap(),
+            Filter::Size(Comparison::NotEqual, 1024 * 1024 * 1024)
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_numbers() {
+        assert_eq!(
+            error("size > 99999999999999999999G"),
+            "Invalid filter expression. Invalid number '99999999999999999999G'"
+        );
+        assert_eq!(
+            error("size > 17179869184G"),
+            "Invalid filter expression. Number '17179869184G' is too large"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_expressions() {
+        assert_eq!(
+            error("size = 1"),
+            "Invalid filter expression. Use == to compare values"
+        );
+        assert_eq!(
+            error("name ~ \"*.tmp"),
+            "Invalid filter expression. Unterminated string"
+        );
+        assert_eq!(error("(is_dir"), "Invalid filter expression. Expected )");
+        assert_eq!(
+            error("size ~ 1"),
+            "Invalid filter expression. ~ cannot be used with size"
+        );
+        assert_eq!(
+            error("name < \"a\""),
+            "Invalid filter expression. < cannot be used with name"
+        );
+        assert_eq!(
+            error("owner == 1"),
+            "Invalid filter expression. Unknown field owner. Expected size, mtime, name or is_dir"
+        );
+        assert_eq!(
+            error("is_dir is_dir"),
+            "Invalid filter expression. Unexpected Identifier(\"is_dir\") after the expression"
+        );
+        assert_eq!(
+            error("is_dir &&"),
+            "Invalid filter expression. Unexpected end of expression"
+        );
+    }
+
+    #[test]
+    fn evaluates_size_and_directory_predicates() {
+        let file = entry("logs/app.log", Some(4096), false);
+        assert!(matches(
+            "size > 4k || is_dir",
+            &entry("logs", None, true),
+            false
+        ));
+        assert!(!matches("size > 4k", &file, false));
+        assert!(matches("size >= 4k && !is_dir", &file, false));
+        assert!(matches("mtime == 1700000000", &file, false));
+        // A missing size never matches a comparison, negated or not
+        assert!(!matches("size < 1", &entry("logs", None, true), false));
+        assert!(!matches("size != 1", &entry("logs", None, true), false));
+    }
+
+    #[test]
+    fn evaluates_name_predicates() {
+        let file = entry("logs/App.LOG", Some(1), false);
+        assert!(matches("name == \"App.LOG\"", &file, false));
+        assert!(!matches("name == \"app.log\"", &file, false));
+        assert!(matches("name != \"app.log\"", &file, false));
+        assert!(matches("name ~ \"*.LOG\"", &file, false));
+        assert!(matches("name !~ \"*.log\"", &file, false));
+    }
+
+    #[test]
+    fn name_predicates_honour_ignore_case() {
+        let file = entry("logs/App.LOG", Some(1), false);
+        assert!(matches("name == \"app.log\"", &file, true));
+        assert!(!matches("name != \"app.log\"", &file, true));
+        assert!(matches("name ~ \"*.log\"", &file, true));
+        assert!(!matches("name !~ \"*.log\"", &file, true));
+    }
+
+    #[test]
+    fn exclude_names_are_case_sensitive_by_default() {
+        let rules = excludes(&["Node_Modules", "README.TXT"], false);
+        assert!(rules.excludes_name("Node_Modules"));
+        assert!(!rules.excludes_name("node_modules"));
+        assert!(!rules.excludes_name("readme.txt"));
+    }
+
+    #[test]
+    fn exclude_names_ignore_case() {
+        let rules = excludes(&["Node_Modules", "README.TXT"], true);
+        assert!(rules.excludes_name("node_modules"));
+        assert!(rules.excludes_name("NODE_MODULES"));
+        assert!(rules.excludes_name("ReadMe.txt"));
+        assert!(!rules.excludes_name("readme.md"));
+    }
+
+    #[test]
+    fn exclude_path_patterns_ignore_case() {
+        let sensitive = excludes(&["/Foo/*", "logs/*.TXT/"], false);
+        assert!(sensitive.excludes_path("Foo/bar"));
+        assert!(!sensitive.excludes_path("foo/bar"));
+        assert!(sensitive.excludes_path("logs/a.TXT"));
+        assert!(!sensitive.excludes_path("logs/a.txt"));
+
+        let insensitive = excludes(&["/Foo/*", "logs/*.TXT/"], true);
+        assert!(insensitive.excludes_path("foo/bar"));
+        assert!(insensitive.excludes_path("FOO/Bar"));
+        assert!(insensitive.excludes_path("LOGS/a.txt"));
+        assert!(!insensitive.excludes_path("logs/a.log"));
+        // Path patterns are not matched against plain names and the reverse
+        assert!(!insensitive.excludes_name("foo"));
+        assert!(!excludes(&["Foo"], true).excludes_path("foo/bar"));
+    }
+}
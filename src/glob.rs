@@ -0,0 +1,42 @@
+/// Match `text` against a glob `pattern`. `?` matches a single character, `*` matches any run
+/// of characters except `/` and `**` matches any run of characters including `/`. Patterns
+/// without a `/` are matched against the last path segment only.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let text = if pattern.contains('/') {
+        text
+    } else {
+        text.rsplit('/').next().unwrap_or(text)
+    };
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    match_from(&pattern, &text)
+}
+
+fn match_from(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') if pattern.get(1) == Some(&'*') => {
+            let rest = &pattern[2..];
+            if let Some(after_separator) = rest.strip_prefix(&['/'][..]) {
+                if match_from(after_separator, text) {
+                    return true;
+                }
+            }
+            (0..=text.len()).any(|i| match_from(rest, &text[i..]))
+        }
+        Some('*') => {
+            let rest = &pattern[1..];
+            for i in 0..=text.len() {
+                if match_from(rest, &text[i..]) {
+                    return true;
+                }
+                if text.get(i) == Some(&'/') {
+                    break;
+                }
+            }
+            false
+        }
+        Some('?') => !text.is_empty() && text[0] != '/' && match_from(&pattern[1..], &text[1..]),
+        Some(c) => text.first() == Some(c) && match_from(&pattern[1..], &text[1..]),
+    }
+}
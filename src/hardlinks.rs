@@ -0,0 +1,49 @@
+use crate::remote_error::RemoteContext;
+use crate::xattr::shell_quote;
+use ssh2::Session;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Device and inode number of a remote file
+pub type Inode = (u64, u64);
+
+/// Find the regular files below `remote_directory` that have more than one hard link, keyed by
+/// the path relative to `remote_directory`. SFTP v3 does not report inode numbers so this runs
+/// GNU `find` on the server over an exec channel. Returns `None` when the server does not allow
+/// exec channels or has no `find` supporting `-printf`.
+pub fn read_remote(
+    session: &Session,
+    remote_directory: &Path,
+) -> Result<Option<HashMap<PathBuf, Inode>>, Box<dyn std::error::Error>> {
+    let mut channel = session
+        .channel_session()
+        .session_context("opening an exec channel")?;
+    channel
+        .exec(&format!(
+            "find {} -type f -links +1 -printf '%D %i %P\\0'",
+            shell_quote(&remote_directory.to_string_lossy())
+        ))
+        .remote_context("running find on", remote_directory)?;
+    let mut output = Vec::new();
+    channel.read_to_end(&mut output)?;
+    channel.wait_close()?;
+    if channel.exit_status()? != 0 {
+        return Ok(None);
+    }
+    let mut inodes = HashMap::new();
+    for record in output.split(|&byte| byte == 0) {
+        let record = String::from_utf8_lossy(record);
+        let mut parts = record.splitn(3, ' ');
+        let (Some(device), Some(inode), Some(relative_path)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            continue;
+        };
+        let (Ok(device), Ok(inode)) = (device.parse(), inode.parse()) else {
+            continue;
+        };
+        inodes.insert(PathBuf::from(relative_path), (device, inode));
+    }
+    Ok(Some(inodes))
+}
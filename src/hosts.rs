@@ -0,0 +1,88 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default, Clone)]
+pub struct HostEntry {
+    pub ip: Option<String>,
+    pub port: Option<u16>,
+    pub username: Option<String>,
+    pub key: Option<PathBuf>,
+}
+
+pub fn default_hosts_file() -> Option<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("sftp-sync").join("hosts.toml"))
+}
+
+/// Parse the hosts file. Only the small subset of TOML required for host definitions is
+/// supported: `[alias]` tables containing `key = "string"` or `key = integer` pairs.
+pub fn parse_hosts(contents: &str) -> Result<BTreeMap<String, HostEntry>, String> {
+    let mut hosts = BTreeMap::new();
+    let mut current: Option<String> = None;
+    for (index, line) in contents.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(alias) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            let alias = alias.trim().trim_matches('"').to_string();
+            hosts
+                .entry(alias.clone())
+                .or_insert_with(HostEntry::default);
+            current = Some(alias);
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(format!("Line {line_number}: expected `key = value`"));
+        };
+        let Some(alias) = &current else {
+            return Err(format!("Line {line_number}: key outside of a [host] table"));
+        };
+        let entry = hosts
+            .get_mut(alias)
+            .expect("current alias is always inserted");
+        let value = value.trim();
+        let string_value = || -> Result<String, String> {
+            value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .map(String::from)
+                .ok_or_else(|| format!("Line {line_number}: expected a quoted string"))
+        };
+        match key.trim() {
+            "ip" => entry.ip = Some(string_value()?),
+            "port" => {
+                entry.port = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("Line {line_number}: invalid port {value}"))?,
+                )
+            }
+            "username" => entry.username = Some(string_value()?),
+            "key" => entry.key = Some(expand_home(&string_value()?)),
+            other => return Err(format!("Line {line_number}: unknown key {other}")),
+        }
+    }
+    Ok(hosts)
+}
+
+pub fn load_host(path: &Path, alias: &str) -> Result<HostEntry, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|error| format!("Could not read hosts file {path:?}. {error}"))?;
+    let mut hosts = parse_hosts(&contents).map_err(|error| format!("{path:?}: {error}"))?;
+    hosts.remove(alias).ok_or_else(|| {
+        let available = hosts.keys().cloned().collect::<Vec<_>>().join(", ");
+        format!("Host alias '{alias}' not found in {path:?}. Available aliases: {available}")
+    })
+}
+
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
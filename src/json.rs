@@ -0,0 +1,411 @@
+use std::fmt::Write;
+
+/// Quote and escape `value` as a JSON string
+pub fn string(value: &str) -> String {
+    let mut result = String::with_capacity(value.len() + 2);
+    result.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(result, "\\u{:04x}", c as u32);
+            }
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+/// Build a JSON object from already encoded values
+pub fn object<'a, I>(fields: I) -> String
+where
+    I: IntoIterator<Item = (&'a str, String)>,
+{
+    let fields: Vec<String> = fields
+        .into_iter()
+        .map(|(key, value)| format!("{}:{value}", string(key)))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+/// Parsed JSON document
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    /// Members in document order
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Member `key` of an object
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Non-negative integer value
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::Number(value) if *value >= 0.0 && value.fract() == 0.0 => Some(*value as u64),
+            _ => None,
+        }
+    }
+}
+
+/// Parse a complete JSON document. Errors give the line and column of the offending character
+pub fn parse(text: &str) -> Result<Value, String> {
+    let mut parser = Parser {
+        text,
+        chars: text.char_indices().peekable(),
+    };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    match parser.chars.next() {
+        None => Ok(value),
+        Some((offset, _)) => Err(format!(
+            "Unexpected trailing data at {}",
+            parser.position(offset)
+        )),
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+}
+
+impl Parser<'_> {
+    /// One based line and column of a byte offset into the document
+    fn position(&self, offset: usize) -> String {
+        let before = &self.text[..offset];
+        let line = before.matches('\n').count() + 1;
+        let column = before.chars().rev().take_while(|c| *c != '\n').count() + 1;
+        format!("line {line} column {column}")
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self
+            .chars
+            .peek()
+            .is_some_and(|(_, c)| matches!(c, ' ' | '\t' | '\n' | '\r'))
+        {
+            self.chars.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.chars.next() {
+            Some((_, c)) if c == expected => Ok(()),
+            Some((offset, c)) => Err(format!(
+                "Expected '{expected}' at {} but found '{c}'",
+                self.position(offset)
+            )),
+            None => Err(format!(
+                "Expected '{expected}' but the document ended at {}",
+                self.end()
+            )),
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Value) -> Result<Value, String> {
+        for expected in word.chars() {
+            self.expect(expected)?;
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        match self.chars.peek().copied() {
+            Some((_, '{')) => self.object(),
+            Some((_, '[')) => self.array(),
+            Some((_, '"')) => self.string().map(Value::String),
+            Some((_, 't')) => self.literal("true", Value::Bool(true)),
+            Some((_, 'f')) => self.literal("false", Value::Bool(false)),
+            Some((_, 'n')) => self.literal("null", Value::Null),
+            Some((_, c)) if c == '-' || c.is_ascii_digit() => self.number(),
+            Some((offset, c)) => Err(format!("Unexpected '{c}' at {}", self.position(offset))),
+            None => Err(format!("Unexpected end of the document at {}", self.end())),
+        }
+    }
+
+    /// Offset of the next character, or the end of the document
+    fn offset(&mut self) -> usize {
+        self.chars
+            .peek()
+            .map_or(self.text.len(), |(offset, _)| *offset)
+    }
+
+    fn end(&self) -> String {
+        self.position(self.text.len())
+    }
+
+    fn object(&mut self) -> Result<Value, String> {
+        let start = self.offset();
+        self.expect('{')?;
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if self.chars.peek().is_some_and(|(_, c)| *c == '}') {
+            self.chars.next();
+            return Ok(Value::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.skip_whitespace();
+            self.expect(':')?;
+            members.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some((_, ',')) => {}
+                Some((_, '}')) => return Ok(Value::Object(members)),
+                Some((offset, c)) => {
+                    return Err(format!(
+                        "Expected ',' or '}}' at {} but found '{c}'",
+                        self.position(offset)
+                    ))
+                }
+                None => {
+                    return Err(format!(
+                        "Unterminated object starting at {}",
+                        self.position(start)
+                    ))
+                }
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        let start = self.offset();
+        self.expect('[')?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.chars.peek().is_some_and(|(_, c)| *c == ']') {
+            self.chars.next();
+            return Ok(Value::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some((_, ',')) => {}
+                Some((_, ']')) => return Ok(Value::Array(items)),
+                Some((offset, c)) => {
+                    return Err(format!(
+                        "Expected ',' or ']' at {} but found '{c}'",
+                        self.position(offset)
+                    ))
+                }
+                None => {
+                    return Err(format!(
+                        "Unterminated array starting at {}",
+                        self.position(start)
+                    ))
+                }
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let start = self.offset();
+        self.expect('"')?;
+        let mut result = String::new();
+        loop {
+            match self.chars.next() {
+                Some((_, '"')) => return Ok(result),
+                Some((_, '\\')) => match self.chars.next() {
+                    Some((_, '"')) => result.push('"'),
+                    Some((_, '\\')) => result.push('\\'),
+                    Some((_, '/')) => result.push('/'),
+                    Some((_, 'b')) => result.push('\u{8}'),
+                    Some((_, 'f')) => result.push('\u{c}'),
+                    Some((_, 'n')) => result.push('\n'),
+                    Some((_, 'r')) => result.push('\r'),
+                    Some((_, 't')) => result.push('\t'),
+                    Some((_, 'u')) => {
+                        let mut code = self.hex_escape()?;
+                        // Surrogate pair for characters outside the basic multilingual plane
+                        if (0xD800..0xDC00).contains(&code) {
+                            self.expect('\\')?;
+                            self.expect('u')?;
+                            let low = self.hex_escape()?;
+                            code = 0x10000
+                                + ((code - 0xD800) << 10)
+                                + (low.wrapping_sub(0xDC00) & 0x3FF);
+                        }
+                        result.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                    }
+                    Some((offset, c)) => {
+                        return Err(format!(
+                            "Invalid escape '\\{c}' at {}",
+                            self.position(offset)
+                        ))
+                    }
+                    None => {
+                        return Err(format!(
+                            "Unterminated string starting at {}",
+                            self.position(start)
+                        ))
+                    }
+                },
+                Some((_, c)) => result.push(c),
+                None => {
+                    return Err(format!(
+                        "Unterminated string starting at {}",
+                        self.position(start)
+                    ))
+                }
+            }
+        }
+    }
+
+    fn hex_escape(&mut self) -> Result<u32, String> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let offset = self.offset();
+            match self.chars.next() {
+                Some((_, c)) if c.is_ascii_hexdigit() => {
+                    code = code * 16 + c.to_digit(16).unwrap_or(0)
+                }
+                _ => return Err(format!("Invalid \\u escape at {}", self.position(offset))),
+            }
+        }
+        Ok(code)
+    }
+
+    fn number(&mut self) -> Result<Value, String> {
+        let mut text = String::new();
+        let offset = self
+            .chars
+            .peek()
+            .map(|(offset, _)| *offset)
+            .unwrap_or_default();
+        while let Some((_, c)) = self
+            .chars
+            .peek()
+            .copied()
+            .filter(|(_, c)| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E'))
+        {
+            text.push(c);
+            self.chars.next();
+        }
+        text.parse()
+            .map(Value::Number)
+            .map_err(|_| format!("Invalid number '{text}' at {}", self.position(offset)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_nested_document() {
+        let value =
+            parse(r#"{"name": "a\tbé", "size": 12, "items": [true, null, -1.5e2]}"#).unwrap();
+        assert_eq!(value.get("name").and_then(Value::as_str), Some("a\tbé"));
+        assert_eq!(value.get("size").and_then(Value::as_u64), Some(12));
+        assert_eq!(
+            value.get("items"),
+            Some(&Value::Array(vec![
+                Value::Bool(true),
+                Value::Null,
+                Value::Number(-150.0)
+            ]))
+        );
+    }
+
+    #[test]
+    fn parses_surrogate_pairs() {
+        assert_eq!(
+            parse(r#""\ud83d\ude00""#).unwrap(),
+            Value::String("😀".to_string())
+        );
+    }
+
+    #[test]
+    fn round_trips_encoded_strings() {
+        let text = "quote \" backslash \\ newline \n control \u{1}";
+        assert_eq!(
+            parse(&string(text)).unwrap(),
+            Value::String(text.to_string())
+        );
+    }
+
+    #[test]
+    fn reports_line_and_column_of_unexpected_characters() {
+        assert_eq!(
+            parse("{\n  \"ip\": \"x\",\n  \"port\" 3}").unwrap_err(),
+            "Expected ':' at line 3 column 10 but found '3'"
+        );
+        assert_eq!(
+            parse("[1, 2] x").unwrap_err(),
+            "Unexpected trailing data at line 1 column 8"
+        );
+        assert_eq!(
+            parse("{\"a\": 1 \"b\": 2}").unwrap_err(),
+            "Expected ',' or '}' at line 1 column 9 but found '\"'"
+        );
+        assert_eq!(
+            parse("[1,\n@]").unwrap_err(),
+            "Unexpected '@' at line 2 column 1"
+        );
+    }
+
+    #[test]
+    fn reports_position_when_the_document_ends_early() {
+        assert_eq!(
+            parse("{\"a\": \"unterminated").unwrap_err(),
+            "Unterminated string starting at line 1 column 7"
+        );
+        assert_eq!(
+            parse("[1,\n 2").unwrap_err(),
+            "Unterminated array starting at line 1 column 1"
+        );
+        assert_eq!(
+            parse("\n  {\"a\": 1").unwrap_err(),
+            "Unterminated object starting at line 2 column 3"
+        );
+        assert_eq!(
+            parse("{\"a\"").unwrap_err(),
+            "Expected ':' but the document ended at line 1 column 5"
+        );
+        assert_eq!(
+            parse("[1,").unwrap_err(),
+            "Unexpected end of the document at line 1 column 4"
+        );
+        assert_eq!(
+            parse(r#""\u12x4""#).unwrap_err(),
+            "Invalid \\u escape at line 1 column 6"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_numbers() {
+        assert_eq!(
+            parse("[1.2.3]").unwrap_err(),
+            "Invalid number '1.2.3' at line 1 column 2"
+        );
+    }
+}
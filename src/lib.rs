@@ -0,0 +1,4055 @@
+mod cancel;
+mod checkpoint;
+pub mod checksum;
+pub mod compare;
+pub mod compress;
+mod connection;
+mod dir_limit;
+pub mod events;
+pub mod filter;
+pub mod glob;
+mod hardlinks;
+pub mod json;
+pub mod listing;
+pub mod manifest;
+mod rate;
+pub mod remote_error;
+pub mod schedule;
+mod trace;
+mod xattr;
+
+pub use cancel::{CancellationToken, Cancelled};
+use checkpoint::{read_state, Checkpoint, CheckpointEntry};
+use checksum::{hash_reader, read_remote_sha256, ChecksumCache, Sha256, Sha256Manifest};
+use clap::ValueEnum;
+use compare::{
+    ChecksumComparator, Comparator, CompareMode, CompressedComparator, MtimeComparator,
+    SizeAndChecksumComparator,
+};
+use compress::{Compression, GzipDecoder, GzipEncoder};
+#[cfg(unix)]
+pub use connection::stream_from_fd;
+pub use connection::{
+    AlgorithmPreferences, AllowedNetwork, Authentication, ConnectionBuilder, HostKeyPolicy,
+};
+use dir_limit::directory_lanes;
+use events::SyncEvent;
+use filter::{match_glob, ExcludeRules, Filter, FilterEntry};
+use hardlinks::Inode;
+use listing::{write_listing, write_local_tree, ListingFormat};
+use manifest::{DirectoryMtimes, LastSuccess, LocalManifest, ManifestEntry};
+use rate::RateLimiter;
+use rayon::prelude::*;
+use remote_error::RemoteContext;
+use ssh2::{FileStat, FileType, MethodType, Session, Sftp};
+use std::cmp::Reverse;
+use std::collections::hash_map::{Entry, RandomState};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs::{File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{BufReader, ErrorKind, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use trace::TransferTrace;
+
+const BUFFER_SIZE: usize = 1024 * 128;
+/// Largest payload libssh2 asks for in a single SFTP read request
+const SFTP_READ_REQUEST_SIZE: usize = 30000;
+const CLEAR_LINE: &str = "\x1B[2K";
+
+#[derive(Debug)]
+pub struct SyncOptions {
+    pub follow_root_symlink: bool,
+    pub completion_log: Option<PathBuf>,
+    pub exclude_extensions: HashSet<String>,
+    pub sequential: bool,
+    pub compare: CompareMode,
+    pub checksum_cache: Option<PathBuf>,
+    pub chaos_rate: Option<f64>,
+    pub max_errors: Option<usize>,
+    pub dedup_local: bool,
+    pub skip_empty: bool,
+    pub priorities: Vec<(String, i32)>,
+    pub only_dirs: bool,
+    pub state_file: Option<PathBuf>,
+    pub checkpoint_interval: usize,
+    pub resume_from: Option<PathBuf>,
+    pub sync_permissions: bool,
+    pub max_runtime: Option<Duration>,
+    pub ramp_up: Option<Duration>,
+    pub ignore_case: bool,
+    pub preserve_xattrs: bool,
+    pub dry_run: bool,
+    pub summary_only: bool,
+    pub special_files: SpecialFiles,
+    pub local_manifest: Option<PathBuf>,
+    /// Global download cap in bytes per second, shared fairly between concurrent transfers
+    pub max_rate: Option<u64>,
+    /// Only visit the entries directly inside the remote directory
+    pub no_recurse: bool,
+    /// Number of slowest transfers to report at the end of the run
+    pub show_slowest: Option<usize>,
+    /// Cache file of the mtimes of remote directories whose files are all in sync. Files directly
+    /// inside a directory whose mtime matches the cache are not compared. Subdirectories
+    /// are still listed and checked on their own
+    pub trust_dir_mtime: Option<PathBuf>,
+    /// Remote sha256sum manifest, relative to the remote directory unless absolute, that every
+    /// downloaded file is checked against. Its entries are relative to the manifest's directory
+    /// and files it does not list fail
+    pub verify_against: Option<PathBuf>,
+    /// Download a file a second time when it does not match the manifest
+    pub redownload_on_mismatch: bool,
+    /// Count excluded entries instead of printing each one
+    pub quiet_skips: bool,
+    pub verbose: bool,
+    /// Glob of rotated files where only the newest match in each directory is synced
+    pub latest: Option<String>,
+    pub latest_by: LatestBy,
+    pub remote_sep: RemoteSeparator,
+    /// Longest local path in bytes. 0 uses [DEFAULT_MAX_PATH_LENGTH]
+    pub max_path_length: usize,
+    pub on_long_path: LongPath,
+    /// Remote change feed listing the only files to sync. See the --changes-from help text for
+    /// the format
+    pub changes_from: Option<PathBuf>,
+    /// Maximum number of concurrent transfers from the same remote directory
+    pub per_dir_jobs: Option<usize>,
+    /// Ask before replacing changed local text files. Forces sequential transfers
+    pub interactive: bool,
+    /// Shell command used to show the difference between the local and remote version, e.g.
+    /// `diff -u`. The local and downloaded paths are appended as arguments
+    pub diff_command: Option<String>,
+    /// Interval of plain progress lines printed when stdout is not a terminal
+    pub heartbeat: Option<Duration>,
+    /// Longest time a single file may take to download before it is failed
+    pub file_timeout: Option<Duration>,
+    /// Remote entries matching this expression are excluded
+    pub filter: Option<Filter>,
+    pub skipped_markers: Option<SkippedMarkers>,
+    /// Stop dispatching transfers when the local filesystem has fewer free bytes than this
+    pub min_free_space: Option<u64>,
+    /// Finish discovery and size every queued file before the first transfer starts. Missing
+    /// sizes are fetched and the run fails upfront when the queue cannot fit within
+    /// `min_free_space`
+    pub two_pass: bool,
+    /// Compress downloaded files, writing them with the compression's extension appended. Local
+    /// copies are compared by mtime, or by the checksum of their decompressed contents in the
+    /// checksum modes, since their size no longer matches the remote
+    pub compress_local: Option<Compression>,
+    /// Suffix of the file a download is written to before it is renamed into place. Empty uses
+    /// [DEFAULT_TEMP_SUFFIX]
+    pub temp_suffix: String,
+    /// Prefix transfer log lines with the run ID and a per-file ID so interleaved output from
+    /// parallel transfers can be untangled
+    pub correlation_ids: bool,
+    /// Abort the run when no bytes were written by any transfer for this long. The SSH socket is
+    /// shut down to unblock transfers stuck on a silent connection
+    pub stall_timeout: Option<Duration>,
+    /// Write every remote entry seen while finding paths to this file as a point-in-time snapshot
+    pub save_listing: Option<PathBuf>,
+    pub listing_format: ListingFormat,
+    /// Diagnostic CSV of the timing of every chunk read and written while downloading. Produces
+    /// one line per chunk so it gets large quickly
+    pub trace_transfers: Option<PathBuf>,
+    /// More remote directories merged into the same local directory after the primary one
+    pub additional_remote_directories: Vec<PathBuf>,
+    pub on_collision: Collision,
+    /// Fraction of files found unchanged in size mode that are checksummed anyway. Mismatches are
+    /// reported as silent corruption and downloaded again
+    pub verify_sample_rate: Option<f64>,
+    /// Number of SFTP read requests kept in flight per download. `None` keeps the default
+    /// 128 KiB read buffer
+    pub read_ahead: Option<usize>,
+    /// Resolve every remote directory with realpath and skip directories that were already
+    /// traversed. Symlinks to directories are followed since cycles are detected
+    pub canonicalize_remote: bool,
+    /// Reserve the full remote size of each local file before writing so a lack of space fails
+    /// the transfer immediately. Ignored with [SyncOptions::compress_local]
+    pub preallocate: bool,
+    /// Only consider remote files modified at or after this many seconds since the Unix epoch
+    pub newer_than: Option<u64>,
+    /// Watermark file storing the start time of the last run that finished without errors. Its
+    /// timestamp is used as [SyncOptions::newer_than] unless that is set explicitly
+    pub since_last_success: Option<PathBuf>,
+    /// Stat every remote file again instead of trusting the attributes from the directory
+    /// listing, for servers that list stale sizes
+    pub restat: bool,
+    /// With [SyncOptions::only_dirs], only create directories that contain at least one entry
+    /// that is not excluded
+    pub no_empty_dirs: bool,
+    /// Write the files that failed to transfer to this file in the state file format. The file is
+    /// removed when a run has no failures
+    pub failed_list: Option<PathBuf>,
+    /// Only retry the files listed in a file written by [SyncOptions::failed_list] instead of
+    /// walking the remote tree. The file is rewritten with the files that still fail
+    pub retry_failed: Option<PathBuf>,
+    /// Skip files whose existing local copy is locked by another process instead of replacing it
+    pub skip_locked: bool,
+    /// How to handle a remote file whose length differs from the size recorded when it was
+    /// queued. `None` accepts files that grew and fails files that were cut short
+    pub on_size_change: Option<SizeChange>,
+    pub order: TransferOrder,
+    /// Recreate remote hard links locally instead of downloading every linked path separately.
+    /// Needs an SSH session that can run GNU `find` on the server
+    pub preserve_hardlinks: bool,
+    /// Minimum number of path components below `/` the remote directory must have before local
+    /// files are pruned. 0 uses the default of 1, which refuses the filesystem root
+    pub min_remote_depth: usize,
+    /// Prune even when the remote directory is shallower than [SyncOptions::min_remote_depth]
+    /// or the remote tree is empty
+    pub force_prune: bool,
+    /// Only download the first this many bytes of each remote file. Local files are
+    /// intentionally partial so they are compared by mtime instead of size
+    pub head_bytes: Option<u64>,
+    /// Only download the last this many bytes of each remote file. Compared like
+    /// [SyncOptions::head_bytes]
+    pub tail_bytes: Option<u64>,
+}
+
+/// Matches the defaults of the command line arguments
+impl Default for SyncOptions {
+    fn default() -> Self {
+        Self {
+            follow_root_symlink: true,
+            completion_log: None,
+            exclude_extensions: HashSet::new(),
+            sequential: false,
+            compare: CompareMode::Size,
+            checksum_cache: None,
+            chaos_rate: None,
+            max_errors: None,
+            dedup_local: false,
+            skip_empty: false,
+            priorities: Vec::new(),
+            only_dirs: false,
+            state_file: None,
+            checkpoint_interval: 100,
+            resume_from: None,
+            sync_permissions: false,
+            max_runtime: None,
+            ramp_up: None,
+            ignore_case: false,
+            preserve_xattrs: false,
+            dry_run: false,
+            summary_only: false,
+            special_files: SpecialFiles::Skip,
+            local_manifest: None,
+            max_rate: None,
+            no_recurse: false,
+            show_slowest: None,
+            trust_dir_mtime: None,
+            verify_against: None,
+            redownload_on_mismatch: false,
+            quiet_skips: false,
+            verbose: false,
+            latest: None,
+            latest_by: LatestBy::Mtime,
+            remote_sep: RemoteSeparator::Auto,
+            max_path_length: DEFAULT_MAX_PATH_LENGTH,
+            on_long_path: LongPath::Skip,
+            changes_from: None,
+            per_dir_jobs: None,
+            interactive: false,
+            diff_command: None,
+            heartbeat: None,
+            file_timeout: None,
+            filter: None,
+            skipped_markers: None,
+            min_free_space: None,
+            two_pass: false,
+            compress_local: None,
+            temp_suffix: DEFAULT_TEMP_SUFFIX.to_string(),
+            correlation_ids: false,
+            stall_timeout: None,
+            save_listing: None,
+            listing_format: ListingFormat::Tsv,
+            trace_transfers: None,
+            additional_remote_directories: Vec::new(),
+            on_collision: Collision::FirstWins,
+            verify_sample_rate: None,
+            read_ahead: None,
+            canonicalize_remote: false,
+            preallocate: false,
+            newer_than: None,
+            since_last_success: None,
+            restat: false,
+            no_empty_dirs: false,
+            failed_list: None,
+            retry_failed: None,
+            skip_locked: false,
+            on_size_change: None,
+            order: TransferOrder::Listing,
+            preserve_hardlinks: false,
+            min_remote_depth: 1,
+            force_prune: false,
+            head_bytes: None,
+            tail_bytes: None,
+        }
+    }
+}
+
+pub struct RemoteEntry {
+    pub path: PathBuf,
+    pub relative_path: PathBuf,
+    pub stat: FileStat,
+}
+
+/// Handling of remote fifos, sockets and device nodes which cannot be copied like regular files
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SpecialFiles {
+    #[default]
+    Skip,
+    Error,
+}
+
+/// What to do with remote files whose local path would exceed [SyncOptions::max_path_length]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LongPath {
+    #[default]
+    Skip,
+    /// Shorten the file name, keeping the extension and adding a hash of the original name
+    Truncate,
+    Error,
+}
+
+/// How files from different remote directories that map to the same local path are resolved
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Collision {
+    /// Keep the file from the remote directory listed first and skip the others
+    #[default]
+    FirstWins,
+    Error,
+}
+
+/// Order queued transfers are processed in, within each --priority class
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TransferOrder {
+    /// The order files were found while walking the remote tree
+    #[default]
+    Listing,
+    /// Transfer one remote directory's files, sorted by name, before moving to the next
+    /// directory. Parallel transfers only run within a directory
+    DirGrouped,
+}
+
+/// What to do when a remote file changes size between being listed and being downloaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SizeChange {
+    /// Stat the remote file again and download it once more with the new size
+    Refetch,
+    /// Keep whatever was read from the remote file
+    Accept,
+    Fail,
+}
+
+/// Number of bytes read from a remote file did not match the size it was queued with
+#[derive(Debug)]
+struct SizeMismatch {
+    expected: u64,
+    actual: u64,
+}
+
+impl std::fmt::Display for SizeMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Remote file changed size during the transfer. Expected {} bytes but read {}",
+            self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for SizeMismatch {}
+
+/// Longest local path accepted by the platform, used as the default limit
+#[cfg(unix)]
+pub const DEFAULT_MAX_PATH_LENGTH: usize = libc::PATH_MAX as usize;
+#[cfg(not(unix))]
+pub const DEFAULT_MAX_PATH_LENGTH: usize = 260;
+
+/// Suffix appended to the local path while a download is in progress
+pub const DEFAULT_TEMP_SUFFIX: &str = ".part";
+
+/// Check that a temp file suffix is usable, i.e. not empty and without path separators
+pub fn validate_temp_suffix(suffix: &str) -> Result<String, String> {
+    if suffix.is_empty() {
+        return Err("Temp suffix cannot be empty".to_string());
+    }
+    if suffix.contains(['/', '\\']) {
+        return Err(format!(
+            "Temp suffix '{suffix}' cannot contain a path separator"
+        ));
+    }
+    Ok(suffix.to_string())
+}
+
+/// Placeholder written for each excluded remote file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SkippedMarkers {
+    /// Zero byte file at the local path of the skipped file
+    Empty,
+    /// Zero byte `<name>.skipped` file next to where the skipped file would be
+    Sidecar,
+}
+
+/// Path separator used in remote file names
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RemoteSeparator {
+    /// Treat names as using `\\` if any name in the first listing contains one
+    #[default]
+    Auto,
+    Slash,
+    Backslash,
+}
+
+/// How the newest file is picked for [SyncOptions::latest]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LatestBy {
+    /// Most recent modification time, ties broken by name
+    #[default]
+    Mtime,
+    /// Lexicographically greatest name, for date stamped names like app.log.2024-06-01
+    Name,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferReason {
+    New,
+    Changed,
+}
+
+pub struct PendingTransfer {
+    pub remote_path: PathBuf,
+    pub relative_path: PathBuf,
+    pub local_path: PathBuf,
+    pub stat: FileStat,
+    pub reason: TransferReason,
+}
+
+/// Result of a successful download
+struct Downloaded {
+    bytes: u64,
+    /// Hash of the local contents when they were checked after the download
+    sha256: Option<String>,
+}
+
+/// Counters from the most recent transfer run
+#[derive(Debug, Default, Clone)]
+pub struct SyncStats {
+    pub transferred: usize,
+    pub failed: usize,
+    pub bytes: u64,
+    /// Files queued for transfer when discovery finished
+    pub planned_files: usize,
+    /// Total remote size of the queued files
+    pub planned_bytes: u64,
+    /// Remote directories that were listed while finding paths and had no entries
+    pub empty_directories: usize,
+    /// Slowest transfers, slowest first. Only populated when [SyncOptions::show_slowest] is set
+    pub slowest: Vec<TransferTiming>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TransferTiming {
+    pub remote_path: PathBuf,
+    pub bytes: u64,
+    pub duration: Duration,
+}
+
+impl TransferTiming {
+    /// Effective transfer rate in bytes per second
+    pub fn rate(&self) -> f64 {
+        self.bytes as f64 / self.duration.as_secs_f64().max(f64::EPSILON)
+    }
+
+    pub fn to_json(&self) -> String {
+        json::object([
+            (
+                "remote_path",
+                json::string(&self.remote_path.to_string_lossy()),
+            ),
+            ("bytes", self.bytes.to_string()),
+            ("duration_ms", self.duration.as_millis().to_string()),
+            ("bytes_per_second", format!("{:.0}", self.rate())),
+        ])
+    }
+}
+
+/// Connection details gathered by [SftpSync::probe]
+#[derive(Debug, Clone)]
+pub struct ProbeReport {
+    pub banner: Option<String>,
+    /// Negotiated algorithm for each method (kex, host key, ciphers and MACs)
+    pub algorithms: Vec<(&'static str, String)>,
+    /// Number of entries directly inside the remote directory
+    pub entries: usize,
+}
+
+impl ProbeReport {
+    pub fn to_json(&self) -> String {
+        let banner = self
+            .banner
+            .as_deref()
+            .map_or_else(|| "null".to_string(), json::string);
+        let algorithms = json::object(
+            self.algorithms
+                .iter()
+                .map(|(name, algorithm)| (*name, json::string(algorithm))),
+        );
+        json::object([
+            ("ok", "true".to_string()),
+            ("banner", banner),
+            ("algorithms", algorithms),
+            ("entries", self.entries.to_string()),
+        ])
+    }
+}
+
+pub struct SftpSync {
+    client: Sftp,
+    exclude: ExcludeRules,
+    local_directory: PathBuf,
+    remote_directory: PathBuf,
+    options: SyncOptions,
+    completion_log: Option<Mutex<File>>,
+    comparator: Box<dyn Comparator>,
+    checksum_cache: Option<Arc<ChecksumCache>>,
+    failed_transfers: AtomicUsize,
+    skipped_transfers: AtomicUsize,
+    local_index: Mutex<HashMap<u64, Vec<PathBuf>>>,
+    checkpoint: Mutex<Checkpoint>,
+    deadline: Mutex<Option<Instant>>,
+    /// Held while a read runs with the session timeout lowered to its --file-timeout budget
+    read_timeout_lock: Mutex<()>,
+    completed_transfers: AtomicUsize,
+    transferred_bytes: AtomicU64,
+    event_handler: Option<EventHandler>,
+    session: Option<Session>,
+    xattrs_unsupported: AtomicBool,
+    remote_hash_unsupported: AtomicBool,
+    cancellation: CancellationToken,
+    local_manifest: Option<LocalManifest>,
+    rate_limiter: Option<RateLimiter>,
+    timings: Mutex<Vec<TransferTiming>>,
+    dir_mtimes: Mutex<DirectoryMtimes>,
+    observed_dir_mtimes: Mutex<DirectoryMtimes>,
+    /// Remote directories containing a file that failed to transfer in this run
+    unsynced_directories: Mutex<HashSet<PathBuf>>,
+    verify_manifest: Option<Sha256Manifest>,
+    excluded_entries: AtomicUsize,
+    detected_backslash: OnceLock<bool>,
+    long_paths: AtomicUsize,
+    timed_out_transfers: AtomicUsize,
+    locked_files: AtomicUsize,
+    finding_paths: AtomicBool,
+    /// Set while a sync that downloads finds its paths. Local side effects of finding paths
+    /// (skipped markers, permission updates and directory creation) only happen then, never for
+    /// --verify-only
+    downloading: AtomicBool,
+    low_space: AtomicBool,
+    planned_files: AtomicUsize,
+    planned_bytes: AtomicU64,
+    run_id: String,
+    next_file_id: AtomicUsize,
+    progress_epoch: Instant,
+    /// Milliseconds after `progress_epoch` when a transfer last wrote bytes
+    last_progress: AtomicU64,
+    stalled: AtomicBool,
+    transfer_trace: Option<TransferTrace>,
+    visited_directories: Mutex<HashSet<PathBuf>>,
+    /// Remote entries seen by the last walk whose contents were not listed: entries hidden by
+    /// --filter and directory aliases skipped by --canonicalize-remote
+    unlisted_remote: Mutex<HashSet<PathBuf>>,
+    newer_than: Option<u64>,
+    empty_directories: AtomicUsize,
+    failures: Mutex<Vec<CheckpointEntry>>,
+    remote_inodes: Mutex<HashMap<PathBuf, Inode>>,
+    /// Local copy of each hard linked remote inode that new links can point at
+    hardlink_targets: Mutex<HashMap<Inode, PathBuf>>,
+}
+
+pub type EventHandler = Box<dyn Fn(&SyncEvent) + Send + Sync>;
+
+impl SftpSync {
+    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(
+        client: Sftp,
+        exclude: Option<Vec<String>>,
+        local_directory: P,
+        remote_directory: Q,
+        options: SyncOptions,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let exclude = ExcludeRules::new(exclude.unwrap_or_default(), options.ignore_case);
+        if !options.additional_remote_directories.is_empty() && options.changes_from.is_some() {
+            return Err("A change feed cannot be combined with multiple remote directories".into());
+        }
+        if !options.temp_suffix.is_empty() {
+            validate_temp_suffix(&options.temp_suffix)?;
+        }
+        let completion_log = match &options.completion_log {
+            Some(path) => Some(Mutex::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )),
+            None => None,
+        };
+        let checksum_cache = match &options.checksum_cache {
+            Some(path) => Some(Arc::new(ChecksumCache::load(path)?)),
+            None => None,
+        };
+        let comparator: Box<dyn Comparator> = match (options.compare, &checksum_cache) {
+            _ if options.compress_local.is_some() => {
+                Box::new(CompressedComparator::new(options.compare))
+            }
+            // Partial copies never match the remote size or contents
+            _ if options.head_bytes.is_some() || options.tail_bytes.is_some() => {
+                Box::new(MtimeComparator)
+            }
+            (CompareMode::Checksum, Some(cache)) => {
+                Box::new(ChecksumComparator::with_cache(cache.clone()))
+            }
+            (CompareMode::Both, Some(cache)) => Box::new(SizeAndChecksumComparator::new(
+                ChecksumComparator::with_cache(cache.clone()),
+            )),
+            (mode, _) => mode.comparator(),
+        };
+        let transfer_trace = match &options.trace_transfers {
+            Some(path) => {
+                println!(
+                    "Tracing every transfer chunk to {path:?}. The trace grows with every chunk transferred"
+                );
+                Some(TransferTrace::create(path).map_err(|error| {
+                    format!("Could not create transfer trace {path:?}. {error}")
+                })?)
+            }
+            None => None,
+        };
+        let local_manifest = match &options.local_manifest {
+            Some(path) => Some(LocalManifest::load(path)?),
+            None => None,
+        };
+        let last_success = match &options.since_last_success {
+            Some(path) => LastSuccess::load(path)?,
+            None => None,
+        };
+        let newer_than = match (options.newer_than, last_success) {
+            (Some(newer_than), _) => Some(newer_than),
+            (None, Some(last_success)) => {
+                println!(
+                    "Only considering files modified since the last successful run at {}, which transferred {} files ({} bytes)",
+                    last_success.timestamp, last_success.files, last_success.bytes
+                );
+                Some(last_success.timestamp)
+            }
+            (None, None) => None,
+        };
+        let rate_limiter = options.max_rate.map(RateLimiter::new);
+        let dir_mtimes = match &options.trust_dir_mtime {
+            Some(path) => DirectoryMtimes::load(path)?,
+            None => DirectoryMtimes::default(),
+        };
+        let verify_manifest = match &options.verify_against {
+            Some(manifest) => {
+                let manifest = remote_directory.as_ref().join(manifest);
+                let mut contents = String::new();
+                client
+                    .open(&manifest)
+                    .remote_context("opening", &manifest)?
+                    .read_to_string(&mut contents)?;
+                let manifest = Sha256Manifest::parse(&manifest, &contents)
+                    .map_err(|error| format!("Invalid manifest {manifest:?}. {error}"))?;
+                Some(manifest)
+            }
+            None => None,
+        };
+        Ok(Self {
+            client,
+            exclude,
+            local_directory: canonical_local_directory(local_directory.as_ref()),
+            remote_directory: remote_directory.as_ref().to_path_buf(),
+            options,
+            completion_log,
+            comparator,
+            checksum_cache,
+            failed_transfers: AtomicUsize::new(0),
+            skipped_transfers: AtomicUsize::new(0),
+            local_index: Mutex::new(HashMap::new()),
+            checkpoint: Mutex::new(Checkpoint::default()),
+            deadline: Mutex::new(None),
+            read_timeout_lock: Mutex::new(()),
+            completed_transfers: AtomicUsize::new(0),
+            transferred_bytes: AtomicU64::new(0),
+            event_handler: None,
+            session: None,
+            xattrs_unsupported: AtomicBool::new(false),
+            remote_hash_unsupported: AtomicBool::new(false),
+            cancellation: CancellationToken::new(),
+            local_manifest,
+            rate_limiter,
+            timings: Mutex::new(Vec::new()),
+            dir_mtimes: Mutex::new(dir_mtimes),
+            observed_dir_mtimes: Mutex::new(DirectoryMtimes::default()),
+            unsynced_directories: Mutex::new(HashSet::new()),
+            verify_manifest,
+            excluded_entries: AtomicUsize::new(0),
+            detected_backslash: OnceLock::new(),
+            long_paths: AtomicUsize::new(0),
+            timed_out_transfers: AtomicUsize::new(0),
+            locked_files: AtomicUsize::new(0),
+            finding_paths: AtomicBool::new(false),
+            downloading: AtomicBool::new(false),
+            low_space: AtomicBool::new(false),
+            planned_files: AtomicUsize::new(0),
+            planned_bytes: AtomicU64::new(0),
+            run_id: format!("{:08x}", RandomState::new().build_hasher().finish() as u32),
+            next_file_id: AtomicUsize::new(0),
+            progress_epoch: Instant::now(),
+            last_progress: AtomicU64::new(0),
+            stalled: AtomicBool::new(false),
+            transfer_trace,
+            visited_directories: Mutex::new(HashSet::new()),
+            unlisted_remote: Mutex::new(HashSet::new()),
+            newer_than,
+            empty_directories: AtomicUsize::new(0),
+            failures: Mutex::new(Vec::new()),
+            remote_inodes: Mutex::new(HashMap::new()),
+            hardlink_targets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Provide the SSH session the SFTP client was created from. Features that need to run
+    /// commands on the server (e.g. preserving extended attributes) require the session.
+    pub fn with_session(mut self, session: Session) -> Self {
+        self.session = Some(session);
+        self
+    }
+
+    /// Check that the remote directory can be listed and report the negotiated connection
+    /// details without transferring anything
+    pub fn probe(&self) -> Result<ProbeReport, Box<dyn std::error::Error>> {
+        let remote_directory = self.resolve_remote_root()?;
+        let entries = self
+            .client
+            .readdir(&remote_directory)
+            .remote_context("listing", &remote_directory)?
+            .len();
+        let Some(session) = &self.session else {
+            return Ok(ProbeReport {
+                banner: None,
+                algorithms: Vec::new(),
+                entries,
+            });
+        };
+        let algorithms = [
+            ("kex", MethodType::Kex),
+            ("host_key", MethodType::HostKey),
+            ("cipher_client_to_server", MethodType::CryptCs),
+            ("cipher_server_to_client", MethodType::CryptSc),
+            ("mac_client_to_server", MethodType::MacCs),
+            ("mac_server_to_client", MethodType::MacSc),
+        ]
+        .into_iter()
+        .filter_map(|(name, method)| Some((name, session.methods(method)?.to_string())))
+        .collect();
+        Ok(ProbeReport {
+            banner: session.banner().map(String::from),
+            algorithms,
+            entries,
+        })
+    }
+
+    pub fn with_event_handler(mut self, handler: EventHandler) -> Self {
+        self.event_handler = Some(handler);
+        self
+    }
+
+    /// Use `token` to stop the sync from another thread. A cancelled sync stops walking the
+    /// remote tree and queuing transfers, waits for in-flight transfers and returns [Cancelled].
+    pub fn with_cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.cancellation = token;
+        self
+    }
+
+    pub fn with_comparator(mut self, comparator: Box<dyn Comparator>) -> Self {
+        self.comparator = comparator;
+        self
+    }
+
+    pub fn stats(&self) -> SyncStats {
+        SyncStats {
+            transferred: self.completed_transfers.load(Ordering::SeqCst),
+            failed: self.failed_transfers.load(Ordering::SeqCst),
+            bytes: self.transferred_bytes.load(Ordering::SeqCst),
+            planned_files: self.planned_files.load(Ordering::SeqCst),
+            planned_bytes: self.planned_bytes.load(Ordering::SeqCst),
+            empty_directories: self.empty_directories.load(Ordering::SeqCst),
+            slowest: self.slowest_transfers(),
+        }
+    }
+
+    fn slowest_transfers(&self) -> Vec<TransferTiming> {
+        let Some(count) = self.options.show_slowest else {
+            return Vec::new();
+        };
+        let mut timings = self
+            .timings
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        timings.sort_by_key(|timing| Reverse(timing.duration));
+        timings.truncate(count);
+        timings
+    }
+
+    fn print_slowest_transfers(&self) {
+        let slowest = self.slowest_transfers();
+        if slowest.is_empty() {
+            return;
+        }
+        println!("Slowest {} transfers:", slowest.len());
+        for timing in slowest {
+            println!(
+                "{:>10.2}s {:>12} bytes {:>12.0} B/s  {}",
+                timing.duration.as_secs_f64(),
+                timing.bytes,
+                timing.rate(),
+                timing.remote_path.display()
+            );
+        }
+    }
+
+    /// Append a line to --completion-log. The SHA-256 column is empty unless the download was
+    /// hashed for --verify-against or --checksum-cache
+    fn log_completion(
+        &self,
+        remote_path: &Path,
+        local_path: &Path,
+        bytes: u64,
+        duration: Duration,
+        sha256: Option<&str>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(completion_log) = &self.completion_log else {
+            return Ok(());
+        };
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut file = completion_log
+            .lock()
+            .map_err(|_| "Completion log lock was poisoned")?;
+        writeln!(
+            file,
+            "{}",
+            completion_line(timestamp, remote_path, local_path, bytes, duration, sha256)
+        )?;
+        file.flush()?;
+        Ok(())
+    }
+
+    /// Apply the exclude names, path patterns and extensions to a remote entry, reporting it when
+    /// it is excluded
+    fn is_excluded(&self, file_name: &str, relative_path: &Path, is_dir: bool) -> bool {
+        if self.exclude.excludes_name(file_name) {
+            self.report_excluded(format_args!("Skipping excluded file/directory {file_name}"));
+            self.mark_skipped(relative_path, is_dir);
+            return true;
+        }
+
+        if self.is_excluded_path(relative_path) {
+            self.report_excluded(format_args!(
+                "Skipping excluded path {}",
+                relative_path.display()
+            ));
+            self.mark_skipped(relative_path, is_dir);
+            return true;
+        }
+
+        if !is_dir && self.is_excluded_extension(file_name) {
+            self.report_excluded(format_args!(
+                "Skipping file with excluded extension {file_name}"
+            ));
+            self.mark_skipped(relative_path, is_dir);
+            return true;
+        }
+        false
+    }
+
+    /// Check the entry against the --filter expression, reporting it when it is excluded
+    fn is_filtered_out(&self, relative_path: &Path, stat: &FileStat) -> bool {
+        let Some(filter) = &self.options.filter else {
+            return false;
+        };
+        let relative_path_str = relative_path.to_string_lossy();
+        let entry = FilterEntry {
+            relative_path: &relative_path_str,
+            size: stat.size,
+            mtime: stat.mtime,
+            is_dir: stat.is_dir(),
+        };
+        if !filter.matches(&entry, self.options.ignore_case, &|pattern, text| {
+            self.glob_match(pattern, text)
+        }) {
+            return false;
+        }
+        self.report_excluded(format_args!(
+            "Skipping {} matched by --filter",
+            relative_path.display()
+        ));
+        self.mark_skipped(relative_path, stat.is_dir());
+        true
+    }
+
+    /// With --skipped-markers, leave a placeholder where an excluded remote file would have been
+    /// written. Only done while finding paths for a sync, never while listing or verifying.
+    fn mark_skipped(&self, relative_path: &Path, is_dir: bool) {
+        let Some(markers) = self.options.skipped_markers else {
+            return;
+        };
+        if is_dir || !self.writes_local_changes() {
+            return;
+        }
+        let local_path = self.local_directory.join(relative_path);
+        let marker_path = match markers {
+            SkippedMarkers::Empty => local_path,
+            SkippedMarkers::Sidecar => {
+                let mut name = local_path.file_name().unwrap_or_default().to_os_string();
+                name.push(".skipped");
+                local_path.with_file_name(name)
+            }
+        };
+        if marker_path.exists() {
+            return;
+        }
+        let result = marker_path
+            .parent()
+            .map_or(Ok(()), create_local_dir_all)
+            .and_then(|_| File::create(&marker_path).map(|_| ()));
+        if let Err(error) = result {
+            println!("{CLEAR_LINE}\rError writing skipped marker {marker_path:?}. {error}");
+        }
+    }
+
+    /// Check if finding paths may write to the local tree, i.e. a sync is about to download and
+    /// this is not a dry run
+    fn writes_local_changes(&self) -> bool {
+        self.downloading.load(Ordering::SeqCst) && !self.options.dry_run
+    }
+
+    /// Count an excluded entry, printing it unless --quiet-skips is set without --verbose
+    fn report_excluded(&self, message: std::fmt::Arguments) {
+        self.excluded_entries.fetch_add(1, Ordering::SeqCst);
+        if !self.options.quiet_skips || self.options.verbose {
+            println!("{CLEAR_LINE}\r{message}");
+        }
+    }
+
+    fn is_excluded_path(&self, relative_path: &Path) -> bool {
+        self.exclude.excludes_path(&relative_path.to_string_lossy())
+    }
+
+    fn is_excluded_extension(&self, file_name: &str) -> bool {
+        if self.options.exclude_extensions.is_empty() {
+            return false;
+        }
+        Path::new(file_name)
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| self.options.exclude_extensions.contains(&e.to_lowercase()))
+    }
+
+    fn resolve_remote_root(&self) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        self.resolve_root(&self.remote_directory)
+    }
+
+    fn resolve_root(&self, remote_directory: &Path) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        if !self.options.follow_root_symlink {
+            return Ok(remote_directory.to_path_buf());
+        }
+        let stat = self
+            .client
+            .lstat(remote_directory)
+            .remote_context("reading attributes of", remote_directory)?;
+        if !stat.file_type().is_symlink() {
+            return Ok(remote_directory.to_path_buf());
+        }
+        let resolved = self
+            .client
+            .realpath(remote_directory)
+            .remote_context("resolving", remote_directory)?;
+        println!("Remote directory {remote_directory:?} is a symlink. Resolved to {resolved:?}");
+        Ok(resolved)
+    }
+
+    fn temp_suffix(&self) -> &str {
+        match self.options.temp_suffix.as_str() {
+            "" => DEFAULT_TEMP_SUFFIX,
+            suffix => suffix,
+        }
+    }
+
+    fn copy_file(
+        &self,
+        remote_path: &Path,
+        local_path: &Path,
+        remote_size: Option<u64>,
+        log_prefix: &str,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        match self.copy_file_once(remote_path, local_path, remote_size, log_prefix) {
+            Err(error)
+                if self.options.on_size_change == Some(SizeChange::Refetch)
+                    && error.is::<SizeMismatch>() =>
+            {
+                let stat = self
+                    .client
+                    .stat(remote_path)
+                    .remote_context("reading attributes of", remote_path)?;
+                if stat.size == remote_size {
+                    // The size did not change so the stream was cut short
+                    return Err(error);
+                }
+                println!(
+                    "{log_prefix}{error}. Downloading {remote_path:?} again with {} bytes",
+                    stat.size.unwrap_or_default()
+                );
+                self.copy_file_once(remote_path, local_path, stat.size, log_prefix)
+            }
+            result => result,
+        }
+    }
+
+    fn copy_file_once(
+        &self,
+        remote_path: &Path,
+        local_path: &Path,
+        remote_size: Option<u64>,
+        log_prefix: &str,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        println!("{log_prefix}Copying remote file {remote_path:?} to {local_path:?}");
+        self.inject_chaos()?;
+        let mut remote_file = self.open_remote(remote_path)?;
+        // Downloads are written next to the destination and renamed into place once complete so
+        // an interrupted transfer never leaves a partial file under the final name
+        let mut temp_path = local_path.as_os_str().to_os_string();
+        temp_path.push(self.temp_suffix());
+        let temp_path = PathBuf::from(temp_path);
+        let result = match self.partial_range(remote_path, remote_size)? {
+            Some((start, length)) => {
+                remote_file.seek(SeekFrom::Start(start))?;
+                let expected_size = remote_size.map(|size| size.saturating_sub(start).min(length));
+                let mut remote_file = remote_file.take(length);
+                self.download_to(remote_path, &mut remote_file, &temp_path, expected_size)
+            }
+            None => self.download_to(remote_path, &mut remote_file, &temp_path, remote_size),
+        };
+        match result {
+            Ok(bytes_written) => {
+                std::fs::rename(&temp_path, local_path)?;
+                Ok(bytes_written)
+            }
+            Err(error) => {
+                let _ = std::fs::remove_file(&temp_path);
+                Err(error)
+            }
+        }
+    }
+
+    fn download_to<R: Read>(
+        &self,
+        remote_path: &Path,
+        remote_file: &mut R,
+        local_path: &Path,
+        remote_size: Option<u64>,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        let mut local_file = File::create(local_path)?;
+        if let (true, None, Some(remote_size)) = (
+            self.options.preallocate,
+            self.options.compress_local,
+            remote_size,
+        ) {
+            preallocate(&local_file, remote_size).map_err(|error| {
+                format!("Could not preallocate {remote_size} bytes for {local_path:?}. {error}")
+            })?;
+        }
+        let deadline = self
+            .options
+            .file_timeout
+            .map(|file_timeout| Instant::now() + file_timeout);
+        let bytes_written = match self.options.compress_local {
+            Some(Compression::Gzip) => {
+                let mut encoder = GzipEncoder::new(&mut local_file)?;
+                let bytes_written =
+                    self.copy_remote(remote_path, remote_file, &mut encoder, deadline)?;
+                encoder.finish()?;
+                bytes_written
+            }
+            None => self.copy_remote(remote_path, remote_file, &mut local_file, deadline)?,
+        };
+        let Some(remote_size) = remote_size.filter(|&size| size != bytes_written) else {
+            return Ok(bytes_written);
+        };
+        match self.options.on_size_change {
+            None if bytes_written > remote_size => {}
+            None => {
+                return Err(format!(
+                    "Truncated transfer. Remote stream ended after {bytes_written} of {remote_size} bytes"
+                )
+                .into())
+            }
+            Some(SizeChange::Accept) => {
+                // Drop the space reserved by --preallocate past the end of what was read
+                if bytes_written < remote_size && self.options.compress_local.is_none() {
+                    local_file.set_len(bytes_written)?;
+                }
+            }
+            Some(SizeChange::Refetch | SizeChange::Fail) => {
+                return Err(SizeMismatch {
+                    expected: remote_size,
+                    actual: bytes_written,
+                }
+                .into())
+            }
+        }
+        Ok(bytes_written)
+    }
+
+    /// Copy the remote stream into `writer`, applying --max-rate to the uncompressed bytes
+    fn copy_remote<R: Read, W: Write>(
+        &self,
+        remote_path: &Path,
+        remote_file: &mut R,
+        writer: &mut W,
+        deadline: Option<Instant>,
+    ) -> std::io::Result<u64> {
+        let mut writer = ProgressTracked {
+            inner: writer,
+            sync: self,
+        };
+        // The deadline is checked between reads by copy_stream. Bounding each read with the
+        // session timeout as well stops a single blocked read from outliving it
+        let mut bounded;
+        let mut remote_file: &mut dyn Read = match (deadline, &self.session) {
+            (Some(deadline), Some(session)) => {
+                bounded = DeadlineBounded {
+                    inner: remote_file,
+                    session,
+                    lock: &self.read_timeout_lock,
+                    deadline,
+                };
+                &mut bounded
+            }
+            _ => remote_file,
+        };
+        let remote_file = &mut remote_file;
+        let trace = self
+            .transfer_trace
+            .as_ref()
+            .map(|trace| (trace, remote_path));
+        match &self.rate_limiter {
+            Some(limiter) => copy_stream(
+                remote_file,
+                &mut limiter.throttle(&mut writer),
+                self.read_buffer_size(),
+                deadline,
+                trace,
+                None,
+            ),
+            None => copy_stream(
+                remote_file,
+                &mut writer,
+                self.read_buffer_size(),
+                deadline,
+                trace,
+                None,
+            ),
+        }
+    }
+
+    /// Offset and length of the part of a remote file downloaded with --head-bytes or
+    /// --tail-bytes, or `None` when the whole file is downloaded
+    fn partial_range(
+        &self,
+        remote_path: &Path,
+        remote_size: Option<u64>,
+    ) -> Result<Option<(u64, u64)>, Box<dyn std::error::Error>> {
+        if let Some(length) = self.options.head_bytes {
+            return Ok(Some((0, length)));
+        }
+        let Some(length) = self.options.tail_bytes else {
+            return Ok(None);
+        };
+        let remote_size = match remote_size {
+            Some(remote_size) => remote_size,
+            None => self
+                .client
+                .stat(remote_path)
+                .remote_context("reading attributes of", remote_path)?
+                .size
+                .ok_or_else(|| format!("Could not extract file size from {remote_path:?}"))?,
+        };
+        Ok(Some((remote_size.saturating_sub(length), length)))
+    }
+
+    /// Size of the buffer passed to each remote read. libssh2 pipelines reads by keeping up to
+    /// four times the requested size in flight as separate read requests, so --read-ahead picks
+    /// the buffer that gives the requested number of outstanding requests.
+    fn read_buffer_size(&self) -> usize {
+        match self.options.read_ahead {
+            Some(depth) => (depth.max(1) * SFTP_READ_REQUEST_SIZE).div_ceil(4),
+            None => BUFFER_SIZE,
+        }
+    }
+
+    fn record_progress(&self) {
+        self.last_progress.store(
+            self.progress_epoch.elapsed().as_millis() as u64,
+            Ordering::SeqCst,
+        );
+    }
+
+    /// Check if --stall-timeout has passed since any transfer last made progress, marking the
+    /// run as stalled the first time it has
+    fn check_stalled(&self, stall_timeout: Duration) -> bool {
+        let last_progress = Duration::from_millis(self.last_progress.load(Ordering::SeqCst));
+        let idle = self.progress_epoch.elapsed().saturating_sub(last_progress);
+        if idle < stall_timeout {
+            return false;
+        }
+        if !self.stalled.swap(true, Ordering::SeqCst) {
+            println!(
+                "No progress for {} seconds. Aborting the stalled session",
+                idle.as_secs()
+            );
+        }
+        true
+    }
+
+    /// Watch for --stall-timeout until `stopped` is closed. A stalled session is shut down so
+    /// blocked reads fail instead of hanging.
+    fn watch_for_stall(&self, stall_timeout: Duration, stopped: Receiver<()>) {
+        #[cfg(unix)]
+        use std::os::fd::AsRawFd;
+
+        #[cfg(unix)]
+        let socket = self.session.as_ref().map(|session| session.as_raw_fd());
+        let interval =
+            (stall_timeout / 4).clamp(Duration::from_millis(100), Duration::from_secs(1));
+        while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+            if self.check_stalled(stall_timeout) {
+                #[cfg(unix)]
+                if let Some(socket) = socket {
+                    unsafe { libc::shutdown(socket, libc::SHUT_RDWR) };
+                }
+                return;
+            }
+        }
+    }
+
+    /// Hash the contents of a local copy, decompressing it first with --compress-local
+    fn hash_local_contents(&self, local_path: &Path) -> std::io::Result<String> {
+        let file = File::open(local_path)?;
+        match self.options.compress_local {
+            Some(Compression::Gzip) => hash_reader(GzipDecoder::new(file)?),
+            None => hash_reader(file),
+        }
+    }
+
+    /// Copy the file and, with --verify-against, check the local copy against the expected hash
+    /// from the manifest. A mismatch is a failed transfer unless the single re-download allowed by
+    /// --redownload-on-mismatch produces a matching file. Files the manifest does not list fail
+    /// without being downloaded.
+    fn copy_and_verify(
+        &self,
+        pending: &PendingTransfer,
+        log_prefix: &str,
+    ) -> Result<Downloaded, Box<dyn std::error::Error>> {
+        let remote_path = pending.remote_path.as_path();
+        let local_path = pending.local_path.as_path();
+        let expected = match &self.verify_manifest {
+            Some(manifest) if manifest.path() != remote_path => Some(
+                manifest
+                    .expected_hash(remote_path)
+                    .ok_or_else(|| format!("Not listed in the manifest {:?}", manifest.path()))?,
+            ),
+            _ => None,
+        };
+        let bytes = self.copy_file(remote_path, local_path, pending.stat.size, log_prefix)?;
+        let Some(expected) = expected else {
+            // Hashing into --checksum-cache now saves the next checksum compare a read and gives
+            // --completion-log the digest
+            let sha256 = match (&self.checksum_cache, self.options.compress_local) {
+                (Some(cache), None) => cache.hash_file(local_path).ok(),
+                _ => None,
+            };
+            return Ok(Downloaded { bytes, sha256 });
+        };
+        let actual = self.hash_local_contents(local_path)?;
+        if actual == expected {
+            return Ok(Downloaded {
+                bytes,
+                sha256: Some(actual),
+            });
+        }
+        if !self.options.redownload_on_mismatch {
+            return Err(
+                format!("Checksum mismatch. Expected {expected} but found {actual}").into(),
+            );
+        }
+        println!("{log_prefix}Checksum mismatch for {remote_path:?}. Downloading again");
+        let bytes = self.copy_file(remote_path, local_path, pending.stat.size, log_prefix)?;
+        let actual = self.hash_local_contents(local_path)?;
+        if actual != expected {
+            return Err(format!(
+                "Checksum mismatch after re-download. Expected {expected} but found {actual}"
+            )
+            .into());
+        }
+        Ok(Downloaded {
+            bytes,
+            sha256: Some(actual),
+        })
+    }
+
+    /// With --interactive, download the changed remote file next to the local copy, show the
+    /// difference using --diff-command and ask before replacing the local file. Binary files are
+    /// replaced without asking. Returns `None` when the local file was kept.
+    fn review_change(
+        &self,
+        pending: &PendingTransfer,
+        log_prefix: &str,
+    ) -> Result<Option<Downloaded>, Box<dyn std::error::Error>> {
+        let local_path = pending.local_path.as_path();
+        if !is_text_file(local_path)? {
+            return self.copy_and_verify(pending, log_prefix).map(Some);
+        }
+        let mut temp_name = OsString::from(".");
+        temp_name.push(local_path.file_name().unwrap_or_default());
+        temp_name.push(".sftp-sync-review");
+        let temp_path = local_path.with_file_name(temp_name);
+        let result = self.review_downloaded_change(pending, &temp_path, log_prefix);
+        if temp_path.exists() {
+            let _ = std::fs::remove_file(&temp_path);
+        }
+        result
+    }
+
+    fn review_downloaded_change(
+        &self,
+        pending: &PendingTransfer,
+        temp_path: &Path,
+        log_prefix: &str,
+    ) -> Result<Option<Downloaded>, Box<dyn std::error::Error>> {
+        let local_path = pending.local_path.as_path();
+        let bytes = self.copy_file(
+            &pending.remote_path,
+            temp_path,
+            pending.stat.size,
+            log_prefix,
+        )?;
+        if is_text_file(temp_path)? {
+            if let Some(diff_command) = &self.options.diff_command {
+                // The command is run through the shell so it can contain arguments, e.g. `diff -u`
+                let status = std::process::Command::new("sh")
+                    .arg("-c")
+                    .arg(format!("{diff_command} \"$1\" \"$2\""))
+                    .arg("sh")
+                    .arg(local_path)
+                    .arg(temp_path)
+                    .status()?;
+                if status.code().is_none() {
+                    return Err(format!("Diff command '{diff_command}' was terminated").into());
+                }
+            }
+            print!("Replace {local_path:?} with the remote version? [y/N] ");
+            std::io::stdout().flush()?;
+            let mut answer = String::new();
+            std::io::stdin().read_line(&mut answer)?;
+            if !matches!(answer.trim(), "y" | "Y" | "yes") {
+                return Ok(None);
+            }
+        }
+        std::fs::rename(temp_path, local_path)?;
+        Ok(Some(Downloaded {
+            bytes,
+            sha256: None,
+        }))
+    }
+
+    /// Open a remote file for reading without writing anything to disk. Reads are buffered
+    /// using the same buffer size as downloads, so --read-ahead applies here as well.
+    ///
+    /// The reader holds its own reference to the SFTP channel rather than borrowing `self`, so it
+    /// may outlive this `SftpSync` and keeps the session open until it is dropped. It still shares
+    /// that session with every other transfer: libssh2 serializes all calls on one session, so
+    /// reading while a sync is running competes with its downloads, and a session shut down by
+    /// --stall-timeout makes further reads fail.
+    pub fn open_remote(
+        &self,
+        remote_path: &Path,
+    ) -> Result<impl Read + Seek, Box<dyn std::error::Error>> {
+        let remote_file = self
+            .client
+            .open(remote_path)
+            .remote_context("opening", remote_path)?;
+        Ok(BufReader::with_capacity(
+            self.read_buffer_size(),
+            remote_file,
+        ))
+    }
+
+    /// Write a single remote file to `writer`. Unlike a transfer, the stream stops between chunks
+    /// with a [Cancelled] error once the cancellation token is cancelled
+    pub fn stream_remote_file<W: Write>(
+        &self,
+        remote_path: &Path,
+        writer: &mut W,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        if !self
+            .client
+            .stat(remote_path)
+            .remote_context("reading attributes of", remote_path)?
+            .is_file()
+        {
+            return Err(format!("Remote path {remote_path:?} is not a single file").into());
+        }
+        let mut remote_file = self.open_remote(remote_path)?;
+        let bytes_written = copy_stream(
+            &mut remote_file,
+            writer,
+            self.read_buffer_size(),
+            None,
+            None,
+            Some(&self.cancellation),
+        )
+        .map_err(|error| -> Box<dyn std::error::Error> {
+            if self.cancellation.is_cancelled() {
+                Cancelled { not_attempted: 1 }.into()
+            } else {
+                error.into()
+            }
+        })?;
+        writer.flush()?;
+        Ok(bytes_written)
+    }
+
+    fn inject_chaos(&self) -> std::io::Result<()> {
+        match self.options.chaos_rate {
+            Some(rate) if random_fraction() < rate => Err(std::io::Error::new(
+                ErrorKind::ConnectionReset,
+                "Connection reset by peer",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn walk<F>(&self, mut visitor: F) -> Result<(), Box<dyn std::error::Error>>
+    where
+        F: FnMut(RemoteEntry) -> Result<(), Box<dyn std::error::Error>>,
+    {
+        self.visited_directories
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        self.unlisted_remote
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        if self.options.additional_remote_directories.is_empty() {
+            let remote_directory = self.resolve_remote_root()?;
+            return self.walk_directory(&remote_directory, Path::new(""), &mut visitor);
+        }
+        // Merged remote directories share one local tree. Each local file is claimed by the
+        // first remote directory that contains it and later ones are resolved by --on-collision
+        let mut claimed: HashMap<PathBuf, PathBuf> = HashMap::new();
+        let roots = std::iter::once(&self.remote_directory)
+            .chain(&self.options.additional_remote_directories);
+        for root in roots {
+            let remote_directory = self.resolve_root(root)?;
+            let mut files = 0;
+            self.walk_directory(
+                &remote_directory,
+                Path::new(""),
+                &mut |entry: RemoteEntry| {
+                    if entry.stat.is_dir() {
+                        return visitor(entry);
+                    }
+                    match claimed.entry(entry.relative_path.clone()) {
+                        Entry::Vacant(slot) => {
+                            slot.insert(entry.path.clone());
+                            files += 1;
+                            visitor(entry)
+                        }
+                        Entry::Occupied(first) => match self.options.on_collision {
+                            Collision::FirstWins => {
+                                println!(
+                                    "{CLEAR_LINE}\rSkipping {:?}. {:?} was found first for {:?}",
+                                    entry.path,
+                                    first.get(),
+                                    entry.relative_path
+                                );
+                                Ok(())
+                            }
+                            Collision::Error => Err(format!(
+                                "Remote files {:?} and {:?} both map to local path {:?}",
+                                first.get(),
+                                entry.path,
+                                entry.relative_path
+                            )
+                            .into()),
+                        },
+                    }
+                },
+            )?;
+            println!("{CLEAR_LINE}\rFound {files} files in remote directory {root:?}");
+        }
+        Ok(())
+    }
+
+    /// Visit only the files listed in a remote change feed instead of walking the whole tree.
+    /// Returns false when the feed cannot be opened so the caller can fall back to a full walk.
+    fn walk_changes<F>(
+        &self,
+        feed: &Path,
+        visitor: &mut F,
+    ) -> Result<bool, Box<dyn std::error::Error>>
+    where
+        F: FnMut(RemoteEntry) -> Result<(), Box<dyn std::error::Error>>,
+    {
+        let remote_directory = self.resolve_remote_root()?;
+        let feed_path = remote_directory.join(feed);
+        let mut contents = String::new();
+        match self
+            .client
+            .open(&feed_path)
+            .remote_context("opening", &feed_path)
+        {
+            Ok(mut file) => {
+                file.read_to_string(&mut contents)?;
+            }
+            Err(error) => {
+                println!(
+                    "Could not open change feed {feed_path:?}. Falling back to a full walk. {error}"
+                );
+                return Ok(false);
+            }
+        }
+        let mut paths: Vec<&str> = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect();
+        paths.sort_unstable();
+        paths.dedup();
+        println!(
+            "Syncing {} paths listed in change feed {feed_path:?}",
+            paths.len()
+        );
+        'paths: for line in paths {
+            let relative_path =
+                PathBuf::from(line.trim_start_matches("./").trim_start_matches('/'));
+            let components: Vec<Component> = relative_path.components().collect();
+            if components
+                .iter()
+                .any(|component| !matches!(component, Component::Normal(_)))
+            {
+                println!("{CLEAR_LINE}\rSkipping change feed entry {line:?}. Paths must stay inside the remote directory");
+                continue;
+            }
+            // Excludes apply to every directory on the way to the file, as they would in a walk
+            let mut prefix = PathBuf::new();
+            for (index, component) in components.iter().enumerate() {
+                prefix.push(component);
+                let Some(name) = component.as_os_str().to_str() else {
+                    continue;
+                };
+                if self.is_excluded(name, &prefix, index + 1 < components.len()) {
+                    continue 'paths;
+                }
+            }
+            let path = remote_directory.join(&relative_path);
+            let stat = match self
+                .client
+                .stat(&path)
+                .remote_context("reading attributes of", &path)
+            {
+                Ok(stat) => stat,
+                Err(error) => {
+                    println!("{CLEAR_LINE}\rSkipping change feed entry {path:?}. {error}");
+                    continue;
+                }
+            };
+            if stat.is_dir() {
+                println!("{CLEAR_LINE}\rSkipping change feed entry {path:?}. Only files are synced from the feed");
+                continue;
+            }
+            if self.is_filtered_out(&relative_path, &stat) {
+                continue;
+            }
+            if self.cancellation.is_cancelled() {
+                return Err(Cancelled { not_attempted: 0 }.into());
+            }
+            visitor(RemoteEntry {
+                path,
+                relative_path,
+                stat,
+            })?;
+        }
+        Ok(true)
+    }
+
+    fn walk_directory<F>(
+        &self,
+        remote_directory: &Path,
+        relative_directory: &Path,
+        visitor: &mut F,
+    ) -> Result<(), Box<dyn std::error::Error>>
+    where
+        F: FnMut(RemoteEntry) -> Result<(), Box<dyn std::error::Error>>,
+    {
+        if self.options.canonicalize_remote && !self.first_visit(remote_directory)? {
+            self.record_unlisted(relative_directory);
+            return Ok(());
+        }
+        let entries = self
+            .client
+            .readdir(remote_directory)
+            .remote_context("listing", remote_directory)?;
+        if entries.is_empty() && self.finding_paths.load(Ordering::SeqCst) {
+            self.empty_directories.fetch_add(1, Ordering::SeqCst);
+            if self.options.verbose {
+                println!("{CLEAR_LINE}\rRemote directory {remote_directory:?} is empty");
+            }
+        }
+        let backslash = self.uses_backslash(&entries);
+        for (path, mut stat) in entries {
+            let Some(raw_name) = entry_name(&path, remote_directory) else {
+                println!(
+                    "{CLEAR_LINE}\rCould not extract file name from remote path {path:?}. Skipping to next item."
+                );
+                continue;
+            };
+
+            let normalized;
+            let (file_name, relative_path) = match raw_name.to_str() {
+                Some(name) if backslash && name.contains('\\') => {
+                    let Some(name) = normalize_remote_name(name) else {
+                        println!(
+                            "{CLEAR_LINE}\rSkipping remote entry {path:?}. Its name points outside the directory"
+                        );
+                        continue;
+                    };
+                    normalized = name;
+                    let last = normalized.file_name().unwrap_or_default();
+                    (last, relative_directory.join(&normalized))
+                }
+                _ => (raw_name, relative_directory.join(raw_name)),
+            };
+            match file_name.to_str() {
+                Some(file_name) => {
+                    if self.is_excluded(file_name, &relative_path, stat.is_dir()) {
+                        continue;
+                    }
+                }
+                None => {
+                    println!(
+                        "{CLEAR_LINE}\rRemote file name {file_name:?} is not valid UTF-8. Exclude rules will not be applied"
+                    );
+                }
+            }
+
+            if self.options.canonicalize_remote && stat.file_type().is_symlink() {
+                if let Ok(target) = self.client.stat(&path) {
+                    if target.is_dir() {
+                        stat = target;
+                    }
+                }
+            }
+            if self.is_filtered_out(&relative_path, &stat) {
+                self.record_unlisted(&relative_path);
+                continue;
+            }
+            if self.cancellation.is_cancelled() {
+                return Err(Cancelled { not_attempted: 0 }.into());
+            }
+            let is_dir = stat.is_dir();
+            visitor(RemoteEntry {
+                path: path.clone(),
+                relative_path: relative_path.clone(),
+                stat,
+            })?;
+            if is_dir && !self.options.no_recurse {
+                self.walk_directory(&path, &relative_path, visitor)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn record_unlisted(&self, relative_path: &Path) {
+        self.unlisted_remote
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(relative_path.to_path_buf());
+    }
+
+    /// Record the canonical path of a remote directory about to be listed. Returns false when the
+    /// directory was already traversed through another path, e.g. a symlink cycle.
+    fn first_visit(&self, remote_directory: &Path) -> Result<bool, Box<dyn std::error::Error>> {
+        let canonical = self
+            .client
+            .realpath(remote_directory)
+            .remote_context("resolving", remote_directory)?;
+        let inserted = self
+            .visited_directories
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(canonical.clone());
+        if !inserted {
+            println!(
+                "{CLEAR_LINE}\rSkipping remote directory {remote_directory:?}. {canonical:?} was already traversed"
+            );
+        }
+        Ok(inserted)
+    }
+
+    /// Check if remote names use `\\` as the separator, e.g. on some Windows servers. In auto
+    /// mode this is inferred once from the first listing that is read.
+    fn uses_backslash(&self, entries: &[(PathBuf, FileStat)]) -> bool {
+        match self.options.remote_sep {
+            RemoteSeparator::Slash => false,
+            RemoteSeparator::Backslash => true,
+            RemoteSeparator::Auto => *self.detected_backslash.get_or_init(|| {
+                entries.iter().any(|(path, _)| {
+                    path.file_name()
+                        .and_then(|name| name.to_str())
+                        .is_some_and(|name| name.contains('\\'))
+                })
+            }),
+        }
+    }
+
+    fn find_paths(
+        &self,
+        result: &mut Vec<PendingTransfer>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        create_local_dir_all(&self.local_directory)?;
+        self.excluded_entries.store(0, Ordering::SeqCst);
+        self.empty_directories.store(0, Ordering::SeqCst);
+        let mut directories_created = 0;
+        let mut permissions_updated = 0;
+        let mut candidates = Vec::new();
+        let mut latest: HashMap<PathBuf, RemoteEntry> = HashMap::new();
+        let mut queue = |entry: RemoteEntry| -> Result<(), Box<dyn std::error::Error>> {
+            let RemoteEntry {
+                path,
+                relative_path,
+                mut stat,
+            } = entry;
+            if self.options.restat && stat.is_file() {
+                match self
+                    .client
+                    .stat(&path)
+                    .remote_context("reading attributes of", &path)
+                {
+                    Ok(fresh) => stat = fresh,
+                    Err(error) => {
+                        println!("{CLEAR_LINE}\r{error}. Skipping to next item");
+                        return Ok(());
+                    }
+                }
+            }
+            let Some(local_path) = self.local_path_for(&relative_path)? else {
+                return Ok(());
+            };
+            print!("{CLEAR_LINE}\rChecking {path:?} for a download or replace");
+
+            if stat.size.is_none() {
+                println!(
+                    "{CLEAR_LINE}\rCould not extract file size from the remote path {path:?}. Skipping to next item"
+                );
+                return Ok(());
+            }
+
+            if skips_empty_file(&stat, self.options.skip_empty) {
+                println!("{CLEAR_LINE}\rSkipping empty remote file {path:?}");
+                return Ok(());
+            }
+
+            let manifest_entry = self
+                .local_manifest
+                .as_ref()
+                .and_then(|manifest| manifest.get(&relative_path))
+                .cloned();
+            if manifest_entry.is_none() && !local_path.exists() {
+                result.push(PendingTransfer {
+                    remote_path: path,
+                    relative_path,
+                    local_path,
+                    stat,
+                    reason: TransferReason::New,
+                });
+                return Ok(());
+            }
+
+            candidates.push((
+                PendingTransfer {
+                    remote_path: path,
+                    relative_path,
+                    local_path,
+                    stat,
+                    reason: TransferReason::Changed,
+                },
+                manifest_entry,
+            ));
+            Ok(())
+        };
+        let mut listing = Vec::new();
+        let mut unchanged_directories = HashSet::new();
+        let mut visit = |entry: RemoteEntry| -> Result<(), Box<dyn std::error::Error>> {
+            let RemoteEntry {
+                path,
+                relative_path,
+                stat,
+            } = entry;
+            if self.options.save_listing.is_some() {
+                listing.push((relative_path.clone(), stat.clone()));
+            }
+            if stat.is_dir() && self.directory_unchanged(&relative_path, stat.mtime) {
+                unchanged_directories.insert(relative_path.clone());
+            }
+            let local_path = self.local_directory.join(&relative_path);
+            // With --no-empty-dirs a directory is only created once an entry inside it is seen
+            if self.options.only_dirs && self.options.no_empty_dirs && self.writes_local_changes() {
+                if let Some(parent) = local_path.parent().filter(|parent| !parent.exists()) {
+                    create_local_dir_all(parent)?;
+                    directories_created += 1;
+                }
+            }
+            if stat.is_dir() {
+                if self.options.no_recurse {
+                    return Ok(());
+                }
+                // Outside of --only-dirs, directories are created once a file inside them is
+                // queued so empty or fully excluded directories are not materialized
+                if self.options.only_dirs
+                    && !self.options.no_empty_dirs
+                    && !local_path.exists()
+                    && self.writes_local_changes()
+                {
+                    create_local_dir_all(&local_path)?;
+                    directories_created += 1;
+                }
+                return Ok(());
+            }
+
+            if self.options.only_dirs {
+                return Ok(());
+            }
+
+            // Files directly inside a directory trusted by --trust-dir-mtime are not compared.
+            // Its subdirectories are still listed since their changes don't touch its mtime
+            if relative_path
+                .parent()
+                .is_some_and(|parent| unchanged_directories.contains(parent))
+            {
+                return Ok(());
+            }
+
+            if let (Some(newer_than), Some(mtime)) = (self.newer_than, stat.mtime) {
+                if mtime < newer_than {
+                    self.report_excluded(format_args!(
+                        "Skipping {path:?}. Not modified since {newer_than}"
+                    ));
+                    return Ok(());
+                }
+            }
+
+            if is_special_file(&stat) {
+                match self.options.special_files {
+                    SpecialFiles::Skip => {
+                        println!("{CLEAR_LINE}\rSkipping special file {path:?}");
+                        return Ok(());
+                    }
+                    SpecialFiles::Error => {
+                        return Err(format!("Remote path {path:?} is not a regular file").into());
+                    }
+                }
+            }
+
+            if let Some(pattern) = &self.options.latest {
+                if self.glob_match(pattern, &relative_path.to_string_lossy()) {
+                    let parent = relative_path
+                        .parent()
+                        .unwrap_or(Path::new(""))
+                        .to_path_buf();
+                    let entry = RemoteEntry {
+                        path,
+                        relative_path,
+                        stat,
+                    };
+                    match latest.get(&parent) {
+                        Some(current) if !self.is_newer(&entry, current) => {}
+                        _ => {
+                            latest.insert(parent, entry);
+                        }
+                    }
+                    return Ok(());
+                }
+            }
+
+            queue(RemoteEntry {
+                path,
+                relative_path,
+                stat,
+            })
+        };
+        self.finding_paths.store(true, Ordering::SeqCst);
+        let walk_result = match &self.options.changes_from {
+            Some(feed) => self.walk_changes(feed, &mut visit),
+            None => Ok(false),
+        }
+        .and_then(|from_feed| {
+            if from_feed {
+                Ok(())
+            } else {
+                self.walk(&mut visit)
+            }
+        });
+        self.finding_paths.store(false, Ordering::SeqCst);
+        walk_result?;
+        if let Some(listing_path) = &self.options.save_listing {
+            write_listing(listing_path, &mut listing, self.options.listing_format).map_err(
+                |error| format!("Could not write remote listing {listing_path:?}. {error}"),
+            )?;
+            println!(
+                "{CLEAR_LINE}\rSaved listing of {} remote entries to {listing_path:?}",
+                listing.len()
+            );
+        }
+        for entry in latest.into_values() {
+            queue(entry)?;
+        }
+        print!(
+            "{CLEAR_LINE}\rComparing {} existing local files",
+            candidates.len()
+        );
+
+        // Comparisons run after the walk so the listing is not held up by them. They stay
+        // sequential since every remote read goes through the one SFTP channel, which libssh2
+        // serializes anyway
+        let sampled = AtomicUsize::new(0);
+        let corrupted = AtomicUsize::new(0);
+        let compare = |(pending, manifest_entry): &(PendingTransfer, Option<ManifestEntry>)| {
+            let from_manifest = manifest_entry
+                .as_ref()
+                .and_then(|entry| self.compare_manifest_entry(pending, entry));
+            let needs_transfer = match from_manifest {
+                Some(result) => result,
+                None => self.comparator.needs_transfer(
+                    &self.client,
+                    &pending.remote_path,
+                    &pending.stat,
+                    &pending.local_path,
+                ),
+            }
+            .map_err(|error| error.to_string())?;
+            if needs_transfer || !self.should_sample(&sampled) {
+                return Ok(needs_transfer);
+            }
+            let mismatched = ChecksumComparator::default()
+                .needs_transfer(
+                    &self.client,
+                    &pending.remote_path,
+                    &pending.stat,
+                    &pending.local_path,
+                )
+                .map_err(|error| error.to_string())?;
+            if mismatched {
+                corrupted.fetch_add(1, Ordering::SeqCst);
+                println!(
+                    "{CLEAR_LINE}\rSilent corruption. {:?} has the same size as {:?} but different contents. Downloading again",
+                    pending.local_path, pending.remote_path
+                );
+            }
+            Ok(mismatched)
+        };
+        let comparisons: Vec<Result<bool, String>> = candidates.iter().map(compare).collect();
+        for ((pending, _), comparison) in candidates.into_iter().zip(comparisons) {
+            match comparison {
+                Ok(true) => result.push(pending),
+                Ok(false) if self.options.sync_permissions && self.writes_local_changes() => {
+                    match sync_permissions(&pending.local_path, &pending.stat) {
+                        Ok(true) => permissions_updated += 1,
+                        Ok(false) => {}
+                        Err(error) => println!(
+                            "{CLEAR_LINE}\rCould not update permissions of {:?}. {error}",
+                            pending.local_path
+                        ),
+                    }
+                }
+                Ok(false) => {}
+                Err(error) => {
+                    println!(
+                        "{CLEAR_LINE}\rCould not compare {:?} to {:?}. {error}",
+                        pending.remote_path, pending.local_path
+                    );
+                }
+            }
+        }
+        let sampled = sampled.into_inner();
+        if sampled > 0 {
+            println!(
+                "{CLEAR_LINE}\rChecksum verified {sampled} sampled unchanged files. {} did not match",
+                corrupted.into_inner()
+            );
+        }
+        if self.writes_local_changes() {
+            directories_created += self.create_parent_directories(result)?;
+        }
+        let long_paths = self.long_paths.swap(0, Ordering::SeqCst);
+        if long_paths > 0 {
+            println!(
+                "{CLEAR_LINE}\r{long_paths} local paths exceeded {} bytes",
+                self.max_path_length()
+            );
+        }
+        let excluded_entries = self.excluded_entries.load(Ordering::SeqCst);
+        if self.options.quiet_skips && excluded_entries > 0 {
+            println!("{CLEAR_LINE}\rSkipped {excluded_entries} excluded items");
+        }
+        let empty_directories = self.empty_directories.load(Ordering::SeqCst);
+        if empty_directories > 0 {
+            println!("{CLEAR_LINE}\rFound {empty_directories} empty remote directories");
+        }
+        if directories_created > 0 {
+            println!("{CLEAR_LINE}\rCreated {directories_created} local directories");
+        }
+        if permissions_updated > 0 {
+            println!("{CLEAR_LINE}\rUpdated permissions of {permissions_updated} unchanged files");
+        }
+        Ok(())
+    }
+
+    /// Pick files for --verify-sample-rate. Only size mode is sampled since the checksum modes
+    /// already read every file and compressed copies cannot be compared byte for byte
+    fn should_sample(&self, sampled: &AtomicUsize) -> bool {
+        let Some(rate) = self.options.verify_sample_rate else {
+            return false;
+        };
+        if self.options.compare != CompareMode::Size
+            || self.options.compress_local.is_some()
+            || self.options.head_bytes.is_some()
+            || self.options.tail_bytes.is_some()
+        {
+            return false;
+        }
+        if random_fraction() >= rate {
+            return false;
+        }
+        sampled.fetch_add(1, Ordering::SeqCst);
+        true
+    }
+
+    fn max_path_length(&self) -> usize {
+        match self.options.max_path_length {
+            0 => DEFAULT_MAX_PATH_LENGTH,
+            max_length => max_length,
+        }
+    }
+
+    /// Local destination of a remote file, applying --on-long-path when the path is longer than
+    /// --max-path-length. Returns `None` when the file should be skipped.
+    fn local_path_for(
+        &self,
+        relative_path: &Path,
+    ) -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
+        let mut local_path = self.local_directory.join(relative_path);
+        if let Some(compression) = self.options.compress_local {
+            local_path = compression.local_path(&local_path);
+        }
+        let length = local_path.as_os_str().len();
+        let max_length = self.max_path_length();
+        if length <= max_length {
+            return Ok(Some(local_path));
+        }
+        self.long_paths.fetch_add(1, Ordering::SeqCst);
+        match self.options.on_long_path {
+            LongPath::Skip => {
+                println!(
+                    "{CLEAR_LINE}\rSkipping {local_path:?}. The path is {length} bytes but the limit is {max_length}"
+                );
+                Ok(None)
+            }
+            LongPath::Error => Err(format!(
+                "Local path {local_path:?} is {length} bytes but the limit is {max_length}"
+            )
+            .into()),
+            LongPath::Truncate => {
+                let truncated =
+                    truncate_file_name(&local_path, length - max_length).ok_or_else(|| {
+                        format!(
+                            "Local path {local_path:?} cannot be shortened to {max_length} bytes"
+                        )
+                    })?;
+                println!("{CLEAR_LINE}\rShortened {local_path:?} to {truncated:?}");
+                Ok(Some(truncated))
+            }
+        }
+    }
+
+    /// Check if `entry` should replace `current` as the newest --latest match in its directory
+    fn is_newer(&self, entry: &RemoteEntry, current: &RemoteEntry) -> bool {
+        match self.options.latest_by {
+            LatestBy::Mtime => {
+                (entry.stat.mtime, &entry.relative_path)
+                    > (current.stat.mtime, &current.relative_path)
+            }
+            LatestBy::Name => entry.relative_path > current.relative_path,
+        }
+    }
+
+    /// Create the missing local parent directories of the queued transfers, returning how many
+    /// were created. Each distinct parent is only checked once.
+    fn create_parent_directories(&self, paths: &[PendingTransfer]) -> std::io::Result<usize> {
+        let parents: HashSet<&Path> = paths
+            .iter()
+            .filter_map(|pending| pending.local_path.parent())
+            .collect();
+        let mut created = 0;
+        for parent in parents {
+            if !parent.exists() {
+                create_local_dir_all(parent)?;
+                created += 1;
+            }
+        }
+        Ok(created)
+    }
+
+    fn check_local_directory(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.local_directory.exists() {
+            return Ok(());
+        }
+        if self.local_directory.symlink_metadata().is_ok() {
+            return Err(format!(
+                "Local directory {:?} is a symlink to a target that does not exist",
+                self.local_directory
+            )
+            .into());
+        }
+        Err(format!("Local directory {:?} does not exist", self.local_directory).into())
+    }
+
+    pub fn sync_local_directory(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_local_directory()?;
+        self.begin_run();
+        let run_started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if self.options.correlation_ids {
+            println!("Run ID {}", self.run_id);
+        }
+        if self.options.dedup_local {
+            self.build_local_index()?;
+        }
+        let mut paths = Vec::new();
+        match self
+            .options
+            .resume_from
+            .as_ref()
+            .or(self.options.retry_failed.as_ref())
+        {
+            Some(state_file) => self.load_resume_queue(state_file, &mut paths)?,
+            None => {
+                println!("Finding paths that need to files that needs to be added or replaced.");
+                self.downloading.store(true, Ordering::SeqCst);
+                let found = self.find_paths(&mut paths);
+                self.downloading.store(false, Ordering::SeqCst);
+                found?;
+                print!("{CLEAR_LINE}\r");
+            }
+        }
+        if self.options.dry_run {
+            self.print_preview(&paths);
+            return Ok(());
+        }
+        if self.options.preserve_hardlinks {
+            self.load_remote_inodes()?;
+        }
+        if let Some(state_file) = &self.options.state_file {
+            let queue = paths
+                .iter()
+                .map(|pending| CheckpointEntry {
+                    remote_path: pending.remote_path.clone(),
+                    relative_path: pending.relative_path.clone(),
+                })
+                .collect();
+            let mut checkpoint = Checkpoint::new(&self.resolve_remote_root()?, queue);
+            checkpoint.write(state_file)?;
+            *self.checkpoint.lock().unwrap_or_else(|e| e.into_inner()) = checkpoint;
+        }
+        if let Some(cache) = &self.checksum_cache {
+            if let Err(error) = cache.save() {
+                println!("Error saving checksum cache. {error}");
+            }
+        }
+
+        if self.options.two_pass {
+            self.complete_plan(&mut paths)?;
+        } else {
+            println!("Need to update {} files", paths.len());
+        }
+        let result = self.transfer_all(paths);
+        self.write_failed_list();
+        result?;
+        self.save_directory_mtimes();
+        self.save_last_success(run_started);
+        Ok(())
+    }
+
+    /// Advance the --since-last-success watermark to the start of this run. Runs with failed
+    /// transfers or files skipped by --skip-locked keep the previous watermark so those files are
+    /// considered again.
+    fn save_last_success(&self, run_started: u64) {
+        let Some(path) = &self.options.since_last_success else {
+            return;
+        };
+        let stats = self.stats();
+        if stats.failed > 0 {
+            println!(
+                "Not updating {path:?} after {} failed transfers",
+                stats.failed
+            );
+            return;
+        }
+        let locked = self.locked_files.load(Ordering::SeqCst);
+        if locked > 0 {
+            println!("Not updating {path:?} after skipping {locked} locked files");
+            return;
+        }
+        let last_success = LastSuccess {
+            timestamp: run_started,
+            files: stats.transferred,
+            bytes: stats.bytes,
+        };
+        if let Err(error) = last_success.save(path) {
+            println!("Error saving last successful run to {path:?}. {error}");
+        }
+    }
+
+    /// Second half of --two-pass discovery. Every queued file gets a known size so totals are
+    /// exact before the first transfer, and the queue is checked against --min-free-space.
+    fn complete_plan(
+        &self,
+        paths: &mut Vec<PendingTransfer>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        paths.retain_mut(|pending| {
+            if pending.stat.size.is_some() {
+                return true;
+            }
+            match self
+                .client
+                .stat(&pending.remote_path)
+                .remote_context("reading attributes of", &pending.remote_path)
+            {
+                Ok(stat) if stat.size.is_some() => {
+                    pending.stat = stat;
+                    true
+                }
+                Ok(_) => {
+                    println!(
+                        "Skipping {:?}. The remote did not report its size",
+                        pending.remote_path
+                    );
+                    false
+                }
+                Err(error) => {
+                    println!("Skipping {:?}. {error}", pending.remote_path);
+                    false
+                }
+            }
+        });
+        let total_bytes: u64 = paths.iter().filter_map(|pending| pending.stat.size).sum();
+        println!(
+            "Discovery complete. Need to update {} files totalling {total_bytes} bytes",
+            paths.len()
+        );
+        let Some(min_free_space) = self.options.min_free_space else {
+            return Ok(());
+        };
+        let required = paths
+            .iter()
+            .map(|pending| {
+                let existing = std::fs::metadata(&pending.local_path)
+                    .map(|metadata| metadata.len())
+                    .unwrap_or(0);
+                pending.stat.size.unwrap_or(0).saturating_sub(existing)
+            })
+            .sum::<u64>();
+        match free_space(&self.local_directory) {
+            Ok(available) if available.saturating_sub(required) < min_free_space => Err(format!(
+                "Transfers need {required} more bytes but only {available} are free in {:?} and --min-free-space requires {min_free_space} to remain",
+                self.local_directory
+            )
+            .into()),
+            Ok(_) => Ok(()),
+            Err(error) => {
+                println!(
+                    "Could not check free space in {:?}. {error}",
+                    self.local_directory
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// With --trust-dir-mtime, check if the remote directory's mtime matches the value recorded
+    /// after the last sync that left the whole tree in sync. Every directory seen is remembered
+    /// so the cache can be refreshed once the run succeeds.
+    fn directory_unchanged(&self, relative_path: &Path, mtime: Option<u64>) -> bool {
+        // Only trust the cache when finding paths to download. Verifying checks every file
+        if self.options.trust_dir_mtime.is_none() || !self.downloading.load(Ordering::SeqCst) {
+            return false;
+        }
+        let Some(mtime) = mtime else {
+            return false;
+        };
+        self.observed_dir_mtimes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(relative_path.to_path_buf(), mtime);
+        self.dir_mtimes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(relative_path)
+            == Some(mtime)
+    }
+
+    /// Record the directory mtimes seen during this run. A directory containing a file that
+    /// failed to transfer is dropped from the cache since it is only safe to trust once every
+    /// file directly inside it is in sync.
+    fn save_directory_mtimes(&self) {
+        let Some(cache_path) = &self.options.trust_dir_mtime else {
+            return;
+        };
+        let mut dir_mtimes = self.dir_mtimes.lock().unwrap_or_else(|e| e.into_inner());
+        let observed = std::mem::take(
+            &mut *self
+                .observed_dir_mtimes
+                .lock()
+                .unwrap_or_else(|e| e.into_inner()),
+        );
+        dir_mtimes.extend(observed);
+        for directory in self
+            .unsynced_directories
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .drain()
+        {
+            dir_mtimes.remove(&directory);
+        }
+        if let Err(error) = dir_mtimes.save(cache_path) {
+            println!("Error saving directory mtime cache {cache_path:?}. {error}");
+        }
+    }
+
+    /// Compare using the values recorded in the local index instead of stat'ing the local file.
+    /// Returns `None` when the index entry lacks the value the compare mode needs.
+    fn compare_manifest_entry(
+        &self,
+        pending: &PendingTransfer,
+        entry: &ManifestEntry,
+    ) -> Option<Result<bool, Box<dyn std::error::Error>>> {
+        if self.options.compress_local.is_some() {
+            // Manifest sizes and hashes describe the compressed file
+            return None;
+        }
+        if self.options.head_bytes.is_some() || self.options.tail_bytes.is_some() {
+            return None;
+        }
+        let size_changed = pending.stat.size != Some(entry.size);
+        let checksum_changed = |local_hash: &str| -> Result<bool, Box<dyn std::error::Error>> {
+            let remote_hash = hash_reader(
+                self.client
+                    .open(&pending.remote_path)
+                    .remote_context("opening", &pending.remote_path)?,
+            )?;
+            Ok(remote_hash != local_hash)
+        };
+        match self.options.compare {
+            CompareMode::Size => Some(Ok(size_changed)),
+            CompareMode::Mtime => {
+                let local_mtime = entry.mtime?;
+                let remote_mtime = pending.stat.mtime?;
+                Some(Ok(local_mtime < remote_mtime))
+            }
+            CompareMode::Checksum => Some(checksum_changed(entry.hash.as_deref()?)),
+            CompareMode::Both if size_changed => Some(Ok(true)),
+            CompareMode::Both => Some(checksum_changed(entry.hash.as_deref()?)),
+        }
+    }
+
+    fn print_preview(&self, paths: &[PendingTransfer]) {
+        let mut new_files = 0;
+        let mut changed_files = 0;
+        for pending in paths {
+            let symbol = match pending.reason {
+                TransferReason::New => {
+                    new_files += 1;
+                    '+'
+                }
+                TransferReason::Changed => {
+                    changed_files += 1;
+                    '~'
+                }
+            };
+            if !self.options.summary_only {
+                println!("{symbol} {}", pending.relative_path.display());
+            }
+        }
+        println!("Dry run: {new_files} new (+), {changed_files} changed (~)");
+    }
+
+    pub fn verify_local_directory(&self, repair: bool) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_local_directory()?;
+        self.begin_run();
+        let mut paths = Vec::new();
+        println!("Verifying existing local files against the remote.");
+        self.find_paths(&mut paths)?;
+        print!("{CLEAR_LINE}\r");
+        let mismatched: Vec<PendingTransfer> = paths
+            .into_iter()
+            .filter(|pending| pending.reason == TransferReason::Changed)
+            .collect();
+        for pending in &mismatched {
+            println!(
+                "Mismatch: {:?} does not match remote file {:?}",
+                pending.local_path, pending.remote_path
+            );
+        }
+        println!("Found {} mismatched local files", mismatched.len());
+        if mismatched.is_empty() {
+            return Ok(());
+        }
+        if !repair {
+            return Err(format!("{} local files do not match the remote", mismatched.len()).into());
+        }
+        println!("Repairing {} files", mismatched.len());
+        self.transfer_all(mismatched)
+    }
+
+    /// Refuse to prune against the remote filesystem root or a directory close to it, where a
+    /// misconfigured --remote-directory would delete most of the local tree
+    fn check_prune_roots(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.options.force_prune {
+            return Ok(());
+        }
+        let min_depth = self.options.min_remote_depth.max(1);
+        let roots = std::iter::once(&self.remote_directory)
+            .chain(&self.options.additional_remote_directories);
+        for root in roots {
+            let resolved = self
+                .client
+                .realpath(root)
+                .remote_context("resolving", root)?;
+            let depth = resolved
+                .components()
+                .filter(|component| matches!(component, Component::Normal(_)))
+                .count();
+            if depth < min_depth {
+                return Err(format!(
+                    "Refusing to prune against remote directory {resolved:?}. It is {depth} levels below the root but at least {min_depth} are required. Pass --i-know-what-im-doing to prune anyway"
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the current local tree to `output` as JSON, returning the number of entries written.
+    /// Excluded entries and anything that is not a file or directory are left out.
+    pub fn dump_local_tree(&self, output: &Path) -> Result<usize, Box<dyn std::error::Error>> {
+        self.check_local_directory()?;
+        let output_path = std::fs::canonicalize(output).ok();
+        let mut entries = Vec::new();
+        let mut directories = vec![PathBuf::new()];
+        while let Some(relative_directory) = directories.pop() {
+            for entry in std::fs::read_dir(self.local_directory.join(&relative_directory))? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                let relative_path = relative_directory.join(entry.file_name());
+                if let Some(file_name) = entry.file_name().to_str() {
+                    if self.is_excluded(file_name, &relative_path, metadata.is_dir()) {
+                        continue;
+                    }
+                }
+                if metadata.is_dir() {
+                    if !self.options.no_recurse {
+                        directories.push(relative_path.clone());
+                    }
+                } else if !metadata.is_file()
+                    || output_path.is_some()
+                        && std::fs::canonicalize(entry.path()).ok() == output_path
+                {
+                    continue;
+                }
+                entries.push((relative_path, metadata));
+            }
+        }
+        write_local_tree(output, &mut entries)?;
+        Ok(entries.len())
+    }
+
+    /// Delete local files and directories that no longer exist in the remote tree without
+    /// transferring anything. Excluded local entries and the files sftp-sync itself writes into
+    /// the local directory are kept, as are copies of remote entries that exist but were not
+    /// listed, e.g. because of --filter. With --dry-run the deletions are only printed.
+    pub fn prune_local_directory(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_local_directory()?;
+        self.check_prune_roots()?;
+        if std::fs::read_dir(&self.local_directory)?.next().is_none() {
+            println!(
+                "Warning: local directory {:?} is empty so there is nothing to prune. Check that it is the intended directory",
+                self.local_directory
+            );
+        }
+        println!("Listing the remote tree to find local files that no longer exist remotely.");
+        let mut remote_tree = RemoteTree::default();
+        self.walk(|entry| {
+            remote_tree.listed.insert(entry.relative_path);
+            Ok(())
+        })?;
+        remote_tree.unlisted = std::mem::take(
+            &mut *self
+                .unlisted_remote
+                .lock()
+                .unwrap_or_else(|e| e.into_inner()),
+        );
+        print!("{CLEAR_LINE}\r");
+        if remote_tree.listed.is_empty() && !self.options.force_prune {
+            return Err(
+                "The remote tree is empty so every local file would be deleted. Pass --i-know-what-im-doing to prune anyway"
+                    .into(),
+            );
+        }
+        let own_files: HashSet<PathBuf> = [
+            &self.options.completion_log,
+            &self.options.checksum_cache,
+            &self.options.state_file,
+            &self.options.local_manifest,
+            &self.options.trust_dir_mtime,
+            &self.options.save_listing,
+            &self.options.trace_transfers,
+            &self.options.since_last_success,
+            &self.options.failed_list,
+            &self.options.retry_failed,
+        ]
+        .into_iter()
+        .flatten()
+        .filter_map(|path| std::fs::canonicalize(path).ok())
+        .collect();
+        let mut deleted_files = 0;
+        let mut deleted_directories = 0;
+        let mut directories = vec![PathBuf::new()];
+        while let Some(relative_directory) = directories.pop() {
+            for entry in std::fs::read_dir(self.local_directory.join(&relative_directory))? {
+                let entry = entry?;
+                let is_dir = entry.file_type()?.is_dir();
+                let relative_path = relative_directory.join(entry.file_name());
+                if let Some(file_name) = entry.file_name().to_str() {
+                    if self.is_excluded(file_name, &relative_path, is_dir) {
+                        continue;
+                    }
+                }
+                let remote_relative_path = match self.options.compress_local {
+                    Some(compression) if !is_dir => relative_path
+                        .to_str()
+                        .and_then(|path| path.strip_suffix(compression.extension()))
+                        .and_then(|path| path.strip_suffix('.'))
+                        .map_or_else(|| relative_path.clone(), PathBuf::from),
+                    _ => relative_path.clone(),
+                };
+                match remote_tree.prune_action(&remote_relative_path, is_dir) {
+                    PruneAction::Keep => continue,
+                    PruneAction::Descend => {
+                        if !self.options.no_recurse {
+                            directories.push(relative_path);
+                        }
+                        continue;
+                    }
+                    PruneAction::Delete => {}
+                }
+                let local_path = entry.path();
+                if (is_dir && self.options.no_recurse)
+                    || std::fs::canonicalize(&local_path)
+                        .is_ok_and(|canonical| own_files.contains(&canonical))
+                {
+                    continue;
+                }
+                if self.options.dry_run {
+                    println!("Would delete {local_path:?}");
+                } else if is_dir {
+                    println!("Deleting local directory {local_path:?}");
+                    std::fs::remove_dir_all(&local_path)?;
+                } else {
+                    println!("Deleting local file {local_path:?}");
+                    std::fs::remove_file(&local_path)?;
+                }
+                if is_dir {
+                    deleted_directories += 1;
+                } else {
+                    deleted_files += 1;
+                }
+            }
+        }
+        let verb = if self.options.dry_run {
+            "Would delete"
+        } else {
+            "Deleted"
+        };
+        println!("{verb} {deleted_files} local files and {deleted_directories} local directories");
+        Ok(())
+    }
+
+    fn begin_run(&self) {
+        *self.deadline.lock().unwrap_or_else(|e| e.into_inner()) = self
+            .options
+            .max_runtime
+            .map(|max_runtime| Instant::now() + max_runtime);
+    }
+
+    fn transfer_all(&self, paths: Vec<PendingTransfer>) -> Result<(), Box<dyn std::error::Error>> {
+        self.failed_transfers.store(0, Ordering::SeqCst);
+        self.skipped_transfers.store(0, Ordering::SeqCst);
+        self.completed_transfers.store(0, Ordering::SeqCst);
+        self.transferred_bytes.store(0, Ordering::SeqCst);
+        self.timed_out_transfers.store(0, Ordering::SeqCst);
+        self.locked_files.store(0, Ordering::SeqCst);
+        self.next_file_id.store(0, Ordering::SeqCst);
+        self.stalled.store(false, Ordering::SeqCst);
+        self.timings
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        let started = AtomicUsize::new(0);
+        let (paths, hardlink_followers) = self.split_hardlink_followers(paths);
+        let total_files = paths.len() + hardlink_followers.len();
+        let total_bytes = paths
+            .iter()
+            .chain(&hardlink_followers)
+            .filter_map(|pending| pending.stat.size)
+            .sum();
+        self.planned_files.store(total_files, Ordering::SeqCst);
+        self.planned_bytes.store(total_bytes, Ordering::SeqCst);
+        self.emit(SyncEvent::PlanReady {
+            files: total_files,
+            bytes: total_bytes,
+        });
+        std::thread::scope(|scope| {
+            let (stop_heartbeat, stopped) = std::sync::mpsc::channel::<()>();
+            if let Some(interval) = self.options.heartbeat {
+                if !std::io::stdout().is_terminal() {
+                    scope.spawn(move || {
+                        let run_started = Instant::now();
+                        while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                            self.print_heartbeat(run_started, total_files, total_bytes);
+                        }
+                    });
+                }
+            }
+            let (stop_watchdog, watchdog_stopped) = std::sync::mpsc::channel::<()>();
+            if let Some(stall_timeout) = self.options.stall_timeout {
+                self.record_progress();
+                scope.spawn(move || self.watch_for_stall(stall_timeout, watchdog_stopped));
+            }
+            // Further links to an inode run last so they can link to the first copy downloaded
+            let groups = self
+                .priority_classes(paths)
+                .into_iter()
+                .flat_map(|class| self.order_class(class))
+                .chain((!hardlink_followers.is_empty()).then_some(hardlink_followers));
+            for group in groups {
+                if self.options.sequential || self.options.interactive {
+                    for pending in group {
+                        self.transfer(&pending);
+                    }
+                } else if let Some(limit) = self.options.per_dir_jobs {
+                    let lanes = directory_lanes(group, limit, |pending| &pending.remote_path);
+                    lanes.into_par_iter().for_each(|lane| {
+                        for pending in lane {
+                            self.ramp_up_delay(&started);
+                            self.transfer(&pending);
+                        }
+                    });
+                } else {
+                    group.into_par_iter().for_each(|pending| {
+                        self.ramp_up_delay(&started);
+                        self.transfer(&pending);
+                    });
+                }
+            }
+            drop(stop_heartbeat);
+            drop(stop_watchdog);
+        });
+        if let Err(error) = self.finish_checkpoint() {
+            println!("Error writing final checkpoint. {error}");
+        }
+        if let Some(trace) = &self.transfer_trace {
+            if let Err(error) = trace.flush() {
+                println!("Error writing transfer trace. {error}");
+            }
+        }
+        self.print_slowest_transfers();
+        let timed_out = self.timed_out_transfers.load(Ordering::SeqCst);
+        if timed_out > 0 {
+            println!("{timed_out} files exceeded the per-file timeout");
+        }
+        let locked = self.locked_files.load(Ordering::SeqCst);
+        if locked > 0 {
+            println!("{locked} files were skipped because the local file was locked");
+        }
+        self.emit(SyncEvent::SyncFinished {
+            transferred: self.completed_transfers.load(Ordering::SeqCst),
+            failed: self.failed_transfers.load(Ordering::SeqCst),
+        });
+        if self.cancellation.is_cancelled() {
+            return Err(Cancelled {
+                not_attempted: self.skipped_transfers.load(Ordering::SeqCst),
+            }
+            .into());
+        }
+        if let Some(reason) = self.stop_reason() {
+            return Err(format!(
+                "{reason}. {} files were not attempted",
+                self.skipped_transfers.load(Ordering::SeqCst),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Plain progress line for logs where the terminal status line is not rendered
+    fn print_heartbeat(&self, run_started: Instant, total_files: usize, total_bytes: u64) {
+        let files_done = self.completed_transfers.load(Ordering::SeqCst)
+            + self.failed_transfers.load(Ordering::SeqCst);
+        let bytes_done = self.transferred_bytes.load(Ordering::SeqCst);
+        let percent = if total_bytes == 0 {
+            100.0
+        } else {
+            bytes_done as f64 * 100.0 / total_bytes as f64
+        };
+        let rate = bytes_done as f64 / run_started.elapsed().as_secs_f64().max(f64::EPSILON);
+        println!(
+            "{}Progress: {files_done}/{total_files} files, {bytes_done}/{total_bytes} bytes ({percent:.1}%), {rate:.0} B/s",
+            self.log_prefix(None)
+        );
+    }
+
+    fn glob_match(&self, pattern: &str, text: &str) -> bool {
+        match_glob(pattern, text, self.options.ignore_case)
+    }
+
+    fn priority_of(&self, pending: &PendingTransfer) -> i32 {
+        let relative_path = pending.relative_path.to_string_lossy();
+        self.options
+            .priorities
+            .iter()
+            .find(|(pattern, _)| self.glob_match(pattern, &relative_path))
+            .map(|(_, weight)| *weight)
+            .unwrap_or_default()
+    }
+
+    fn priority_classes(&self, paths: Vec<PendingTransfer>) -> Vec<Vec<PendingTransfer>> {
+        if self.options.priorities.is_empty() {
+            return vec![paths];
+        }
+        let mut classes: BTreeMap<Reverse<i32>, Vec<PendingTransfer>> = BTreeMap::new();
+        for pending in paths {
+            classes
+                .entry(Reverse(self.priority_of(&pending)))
+                .or_default()
+                .push(pending);
+        }
+        classes.into_values().collect()
+    }
+
+    /// Split a priority class into the groups that are transferred one after another
+    fn order_class(&self, mut class: Vec<PendingTransfer>) -> Vec<Vec<PendingTransfer>> {
+        if self.options.order == TransferOrder::Listing {
+            return vec![class];
+        }
+        class.sort_by(|a, b| {
+            a.remote_path
+                .parent()
+                .cmp(&b.remote_path.parent())
+                .then_with(|| a.remote_path.file_name().cmp(&b.remote_path.file_name()))
+        });
+        let mut groups: Vec<Vec<PendingTransfer>> = Vec::new();
+        for pending in class {
+            match groups.last_mut() {
+                Some(group) if group[0].remote_path.parent() == pending.remote_path.parent() => {
+                    group.push(pending)
+                }
+                _ => groups.push(vec![pending]),
+            }
+        }
+        groups
+    }
+
+    /// Find the hard linked remote files for --preserve-hardlinks. Servers that cannot report
+    /// inodes leave every path to be downloaded separately.
+    fn load_remote_inodes(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(session) = &self.session else {
+            println!("--preserve-hardlinks needs an SSH session. Hard links are downloaded as separate files");
+            return Ok(());
+        };
+        let mut remote_inodes = self.remote_inodes.lock().unwrap_or_else(|e| e.into_inner());
+        remote_inodes.clear();
+        let roots = std::iter::once(&self.remote_directory)
+            .chain(&self.options.additional_remote_directories);
+        for root in roots {
+            let root = self.resolve_root(root)?;
+            match hardlinks::read_remote(session, &root)? {
+                Some(inodes) => {
+                    for (relative_path, inode) in inodes {
+                        remote_inodes.entry(relative_path).or_insert(inode);
+                    }
+                }
+                None => {
+                    println!(
+                        "Could not read inode numbers under {root:?}. The server must allow running GNU find. Hard links are downloaded as separate files"
+                    );
+                    return Ok(());
+                }
+            }
+        }
+        println!(
+            "Found {} remote files with multiple hard links",
+            remote_inodes.len()
+        );
+        Ok(())
+    }
+
+    /// With --preserve-hardlinks, keep the first queued path of every hard linked inode in the
+    /// main queue and return the other paths separately. Linked paths that are already up to
+    /// date locally become link targets straight away.
+    fn split_hardlink_followers(
+        &self,
+        paths: Vec<PendingTransfer>,
+    ) -> (Vec<PendingTransfer>, Vec<PendingTransfer>) {
+        let remote_inodes = self.remote_inodes.lock().unwrap_or_else(|e| e.into_inner());
+        let mut targets = self
+            .hardlink_targets
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        targets.clear();
+        if !self.options.preserve_hardlinks || remote_inodes.is_empty() {
+            return (paths, Vec::new());
+        }
+        let queued: HashSet<&Path> = paths
+            .iter()
+            .map(|pending| pending.relative_path.as_path())
+            .collect();
+        for (relative_path, inode) in remote_inodes.iter() {
+            if queued.contains(relative_path.as_path()) || targets.contains_key(inode) {
+                continue;
+            }
+            let mut local_path = self.local_directory.join(relative_path);
+            if let Some(compression) = self.options.compress_local {
+                local_path = compression.local_path(&local_path);
+            }
+            if local_path.is_file() {
+                targets.insert(*inode, local_path);
+            }
+        }
+        let mut first_queued = HashSet::new();
+        let (primaries, followers) = paths.into_iter().partition(|pending| {
+            match remote_inodes.get(&pending.relative_path) {
+                Some(inode) => !targets.contains_key(inode) && first_queued.insert(*inode),
+                None => true,
+            }
+        });
+        (primaries, followers)
+    }
+
+    /// Replace `pending`'s local file with a hard link to an existing local copy of the same
+    /// remote inode. Returns the link target, or `None` when there is no target or linking fails
+    /// so the file is downloaded instead.
+    fn link_hardlink_target(&self, pending: &PendingTransfer) -> Option<PathBuf> {
+        let inode = *self
+            .remote_inodes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&pending.relative_path)?;
+        let target = self
+            .hardlink_targets
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&inode)?
+            .clone();
+        if target == pending.local_path {
+            return None;
+        }
+        if let Some(parent) = pending.local_path.parent() {
+            create_local_dir_all(parent).ok()?;
+        }
+        let mut temp_path = pending.local_path.as_os_str().to_os_string();
+        temp_path.push(self.temp_suffix());
+        temp_path.push(".link");
+        let temp_path = PathBuf::from(temp_path);
+        let _ = std::fs::remove_file(&temp_path);
+        std::fs::hard_link(&target, &temp_path).ok()?;
+        if std::fs::rename(&temp_path, &pending.local_path).is_err() {
+            let _ = std::fs::remove_file(&temp_path);
+            return None;
+        }
+        Some(target)
+    }
+
+    fn record_hardlink_target(&self, pending: &PendingTransfer) {
+        let inode = self
+            .remote_inodes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&pending.relative_path)
+            .copied();
+        if let Some(inode) = inode {
+            self.hardlink_targets
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .entry(inode)
+                .or_insert_with(|| pending.local_path.clone());
+        }
+    }
+
+    fn build_local_index(&self) -> std::io::Result<()> {
+        let mut index = self.local_index.lock().unwrap_or_else(|e| e.into_inner());
+        index.clear();
+        let mut directories = vec![self.local_directory.clone()];
+        while let Some(directory) = directories.pop() {
+            for entry in std::fs::read_dir(directory)? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if metadata.is_dir() {
+                    directories.push(entry.path());
+                } else if metadata.is_file() {
+                    index.entry(metadata.len()).or_default().push(entry.path());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn hash_local_file(&self, local_path: &Path) -> std::io::Result<String> {
+        match &self.checksum_cache {
+            Some(cache) => cache.hash_file(local_path),
+            None => hash_reader(File::open(local_path)?),
+        }
+    }
+
+    fn link_local_duplicate(
+        &self,
+        pending: &PendingTransfer,
+    ) -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
+        let Some(size) = pending.stat.size else {
+            return Ok(None);
+        };
+        let candidates = self
+            .local_index
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&size)
+            .cloned()
+            .unwrap_or_default();
+        if candidates.is_empty() || self.remote_hash_unsupported.load(Ordering::SeqCst) {
+            return Ok(None);
+        }
+        // Reading the remote file to hash it would cost as much as downloading it, so only
+        // server side hashes are used
+        let Some(session) = &self.session else {
+            println!("Skipping --dedup-local. No SSH session is available to hash remote files");
+            self.remote_hash_unsupported.store(true, Ordering::SeqCst);
+            return Ok(None);
+        };
+        let Some(remote_hash) = read_remote_sha256(session, &pending.remote_path)? else {
+            println!("Skipping --dedup-local. sha256sum is not available on the server");
+            self.remote_hash_unsupported.store(true, Ordering::SeqCst);
+            return Ok(None);
+        };
+        for candidate in candidates {
+            if self.hash_local_file(&candidate)? != remote_hash {
+                continue;
+            }
+            if std::fs::hard_link(&candidate, &pending.local_path).is_err() {
+                std::fs::copy(&candidate, &pending.local_path)?;
+            }
+            return Ok(Some(candidate));
+        }
+        Ok(None)
+    }
+
+    fn ramp_up_delay(&self, started: &AtomicUsize) {
+        let Some(ramp_up) = self.options.ramp_up else {
+            return;
+        };
+        let workers = rayon::current_num_threads();
+        let index = started.fetch_add(1, Ordering::SeqCst);
+        if index >= workers {
+            return;
+        }
+        let slot = ramp_up.as_secs_f64() / workers as f64;
+        let delay = slot * index as f64 + slot * random_fraction();
+        std::thread::sleep(Duration::from_secs_f64(delay));
+    }
+
+    fn stop_reason(&self) -> Option<String> {
+        if self.cancellation.is_cancelled() {
+            return Some("Sync was cancelled".to_string());
+        }
+        if self.low_space.load(Ordering::SeqCst) {
+            return Some(format!(
+                "Paused because free space in {:?} dropped below {} bytes",
+                self.local_directory,
+                self.options.min_free_space.unwrap_or_default()
+            ));
+        }
+        if self.stalled.load(Ordering::SeqCst) {
+            return Some(format!(
+                "No progress for {} seconds",
+                self.options.stall_timeout.unwrap_or_default().as_secs()
+            ));
+        }
+        let failed = self.failed_transfers.load(Ordering::SeqCst);
+        if self.options.max_errors.is_some_and(|max| failed >= max) {
+            return Some(format!("Aborted early after {failed} failed transfers"));
+        }
+        let deadline = *self.deadline.lock().unwrap_or_else(|e| e.into_inner());
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Some("Stopped after exceeding the maximum runtime".to_string());
+        }
+        None
+    }
+
+    /// Check --min-free-space before starting a transfer. Once space runs low no further
+    /// transfers are started.
+    fn has_free_space(&self) -> bool {
+        let Some(min_free_space) = self.options.min_free_space else {
+            return true;
+        };
+        match free_space(&self.local_directory) {
+            Ok(available) if available < min_free_space => {
+                if !self.low_space.swap(true, Ordering::SeqCst) {
+                    println!(
+                        "Only {available} bytes free in {:?}. Finishing in-flight transfers and pausing",
+                        self.local_directory
+                    );
+                }
+                false
+            }
+            Ok(_) => true,
+            Err(error) => {
+                println!(
+                    "Could not check free space in {:?}. {error}",
+                    self.local_directory
+                );
+                true
+            }
+        }
+    }
+
+    fn record_checkpoint(&self, remote_path: &Path) {
+        let Some(state_file) = &self.options.state_file else {
+            return;
+        };
+        let mut checkpoint = self.checkpoint.lock().unwrap_or_else(|e| e.into_inner());
+        if checkpoint.complete(remote_path, self.options.checkpoint_interval.max(1)) {
+            if let Err(error) = checkpoint.write(state_file) {
+                println!("Error writing checkpoint to {state_file:?}. {error}");
+            }
+        }
+    }
+
+    /// Remember a file that was failed or skipped so its directory is not cached by
+    /// --trust-dir-mtime and it is written to --failed-list
+    fn record_unsynced(&self, pending: &PendingTransfer) {
+        if self.options.trust_dir_mtime.is_some() {
+            self.unsynced_directories
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(
+                    pending
+                        .relative_path
+                        .parent()
+                        .unwrap_or(Path::new(""))
+                        .to_path_buf(),
+                );
+        }
+        if self.options.failed_list.is_some() || self.options.retry_failed.is_some() {
+            self.failures
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(CheckpointEntry {
+                    remote_path: pending.remote_path.clone(),
+                    relative_path: pending.relative_path.clone(),
+                });
+        }
+    }
+
+    /// Write the files that failed in the last transfer run for --failed-list. A retry run
+    /// rewrites the list it was given so only files that still fail remain.
+    fn write_failed_list(&self) {
+        let Some(path) = self
+            .options
+            .failed_list
+            .as_ref()
+            .or(self.options.retry_failed.as_ref())
+        else {
+            return;
+        };
+        let failures =
+            std::mem::take(&mut *self.failures.lock().unwrap_or_else(|e| e.into_inner()));
+        if failures.is_empty() {
+            if let Err(error) = std::fs::remove_file(path) {
+                if error.kind() != ErrorKind::NotFound {
+                    println!("Error removing failed list {path:?}. {error}");
+                }
+            }
+            return;
+        }
+        let count = failures.len();
+        let result = self
+            .resolve_remote_root()
+            .and_then(|root| Ok(Checkpoint::new(&root, failures).write(path)?));
+        match result {
+            Ok(()) => println!("{count} failed files written. Retry with --retry-failed {path:?}"),
+            Err(error) => println!("Error writing failed list {path:?}. {error}"),
+        }
+    }
+
+    fn finish_checkpoint(&self) -> std::io::Result<()> {
+        let Some(state_file) = &self.options.state_file else {
+            return Ok(());
+        };
+        let mut checkpoint = self.checkpoint.lock().unwrap_or_else(|e| e.into_inner());
+        if checkpoint.remaining() == 0 {
+            return match std::fs::remove_file(state_file) {
+                Err(error) if error.kind() != ErrorKind::NotFound => Err(error),
+                _ => Ok(()),
+            };
+        }
+        checkpoint.write(state_file)?;
+        println!(
+            "{} files remain. Resume with --resume-from {state_file:?}",
+            checkpoint.remaining()
+        );
+        Ok(())
+    }
+
+    fn load_resume_queue(
+        &self,
+        state_file: &Path,
+        result: &mut Vec<PendingTransfer>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (state_remote_directory, entries) = read_state(state_file)?;
+        let remote_directory = self.resolve_remote_root()?;
+        if state_remote_directory != remote_directory {
+            return Err(format!(
+                "State file {state_file:?} was created for remote directory {state_remote_directory:?} not {remote_directory:?}"
+            )
+            .into());
+        }
+        println!(
+            "Resuming {} queued files from {state_file:?}",
+            entries.len()
+        );
+        for entry in entries {
+            let stat = match self
+                .client
+                .stat(&entry.remote_path)
+                .remote_context("reading attributes of", &entry.remote_path)
+            {
+                Ok(stat) => stat,
+                Err(error) => {
+                    println!(
+                        "Skipping {:?} from the state file. It could not be found on the remote. {error}",
+                        entry.remote_path
+                    );
+                    continue;
+                }
+            };
+            let Some(local_path) = self.local_path_for(&entry.relative_path)? else {
+                continue;
+            };
+            let reason = if local_path.exists() {
+                TransferReason::Changed
+            } else {
+                TransferReason::New
+            };
+            result.push(PendingTransfer {
+                remote_path: entry.remote_path,
+                relative_path: entry.relative_path,
+                local_path,
+                stat,
+                reason,
+            });
+        }
+        if !self.options.dry_run {
+            self.create_parent_directories(result)?;
+        }
+        Ok(())
+    }
+
+    fn transfer(&self, pending: &PendingTransfer) {
+        let remote_path = pending.remote_path.as_path();
+        let local_path = pending.local_path.as_path();
+        if self.stop_reason().is_some() || !self.has_free_space() {
+            self.skipped_transfers.fetch_add(1, Ordering::SeqCst);
+            return;
+        }
+        let log_prefix = self.log_prefix(Some(self.next_file_id.fetch_add(1, Ordering::SeqCst)));
+        if self.options.preserve_hardlinks {
+            if let Some(target) = self.link_hardlink_target(pending) {
+                println!("{log_prefix}Linked {local_path:?} to {target:?} for remote file {remote_path:?}");
+                self.completed_transfers.fetch_add(1, Ordering::SeqCst);
+                self.record_checkpoint(remote_path);
+                return;
+            }
+        }
+        if self.options.dedup_local && !local_path.exists() {
+            match self.link_local_duplicate(pending) {
+                Ok(Some(existing)) => {
+                    println!(
+                        "{log_prefix}Reused local file {existing:?} for remote file {remote_path:?}"
+                    );
+                    self.completed_transfers.fetch_add(1, Ordering::SeqCst);
+                    self.record_checkpoint(remote_path);
+                    return;
+                }
+                Ok(None) => {}
+                Err(error) => {
+                    println!(
+                        "{log_prefix}Error checking for a local duplicate of {remote_path:?}. {error}"
+                    );
+                }
+            }
+        }
+        // The lock is held until the replacement is renamed into place
+        let _local_lock = if self.options.skip_locked {
+            match lock_existing(local_path) {
+                Ok(lock) => lock,
+                Err(error) if error.kind() == ErrorKind::WouldBlock => {
+                    println!(
+                        "{log_prefix}Skipping {remote_path:?}. Local file {local_path:?} is locked by another process"
+                    );
+                    self.locked_files.fetch_add(1, Ordering::SeqCst);
+                    self.record_unsynced(pending);
+                    return;
+                }
+                Err(error) => {
+                    println!("{log_prefix}Could not lock local file {local_path:?}. {error}");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        self.emit(SyncEvent::TransferStarted {
+            remote_path: remote_path.to_path_buf(),
+            local_path: local_path.to_path_buf(),
+        });
+        let start = Instant::now();
+        let outcome = if self.options.interactive && pending.reason == TransferReason::Changed {
+            self.review_change(pending, &log_prefix)
+        } else {
+            self.copy_and_verify(pending, &log_prefix).map(Some)
+        };
+        match outcome {
+            Ok(None) => {
+                println!("{log_prefix}Kept local file {local_path:?}");
+                self.record_checkpoint(remote_path);
+            }
+            Ok(Some(Downloaded { bytes, sha256 })) => {
+                let duration = start.elapsed();
+                self.completed_transfers.fetch_add(1, Ordering::SeqCst);
+                self.transferred_bytes.fetch_add(bytes, Ordering::SeqCst);
+                if self.options.show_slowest.is_some() {
+                    self.timings
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .push(TransferTiming {
+                            remote_path: remote_path.to_path_buf(),
+                            bytes,
+                            duration,
+                        });
+                }
+                if let Err(error) =
+                    self.log_completion(remote_path, local_path, bytes, duration, sha256.as_deref())
+                {
+                    println!(
+                        "{log_prefix}Error writing completion log entry for {remote_path:?}. {error}"
+                    );
+                }
+                self.record_checkpoint(remote_path);
+                if self.options.preserve_hardlinks {
+                    self.record_hardlink_target(pending);
+                }
+                if self.options.preserve_xattrs {
+                    self.copy_xattrs(remote_path, local_path);
+                }
+                self.emit(SyncEvent::TransferCompleted {
+                    remote_path: remote_path.to_path_buf(),
+                    local_path: local_path.to_path_buf(),
+                    bytes,
+                    duration,
+                });
+            }
+            Err(error) => {
+                self.failed_transfers.fetch_add(1, Ordering::SeqCst);
+                self.record_unsynced(pending);
+                let timed_out = error
+                    .downcast_ref::<std::io::Error>()
+                    .is_some_and(|error| error.kind() == ErrorKind::TimedOut);
+                if timed_out {
+                    self.timed_out_transfers.fetch_add(1, Ordering::SeqCst);
+                    println!(
+                        "{log_prefix}Timed out copying file {remote_path:?} -> {local_path:?}. {error}"
+                    );
+                } else {
+                    println!(
+                        "{log_prefix}Error copying file {remote_path:?} -> {local_path:?}. {error}"
+                    );
+                }
+                self.emit(SyncEvent::TransferFailed {
+                    remote_path: remote_path.to_path_buf(),
+                    local_path: local_path.to_path_buf(),
+                    error: error.to_string(),
+                });
+            }
+        }
+    }
+
+    fn copy_xattrs(&self, remote_path: &Path, local_path: &Path) {
+        if self.xattrs_unsupported.load(Ordering::SeqCst) {
+            return;
+        }
+        let Some(session) = &self.session else {
+            println!("Skipping extended attributes. No SSH session is available to read them");
+            self.xattrs_unsupported.store(true, Ordering::SeqCst);
+            return;
+        };
+        let attributes = match xattr::read_remote(session, remote_path) {
+            Ok(Some(attributes)) => attributes,
+            Ok(None) => {
+                println!("Skipping extended attributes. getfattr is not available on the server");
+                self.xattrs_unsupported.store(true, Ordering::SeqCst);
+                return;
+            }
+            Err(error) => {
+                println!("Could not read extended attributes of {remote_path:?}. {error}");
+                return;
+            }
+        };
+        if let Err(error) = xattr::apply_local(local_path, &attributes) {
+            if error.kind() == ErrorKind::Unsupported {
+                self.xattrs_unsupported.store(true, Ordering::SeqCst);
+            }
+            println!("Could not set extended attributes on {local_path:?}. {error}");
+        }
+    }
+
+    /// Unique ID of this sync, included in log lines with --correlation-ids
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
+    /// `[run file]` prefix for log lines with --correlation-ids, otherwise empty
+    fn log_prefix(&self, file_id: Option<usize>) -> String {
+        match (self.options.correlation_ids, file_id) {
+            (false, _) => String::new(),
+            (true, Some(file_id)) => format!("[{} {file_id:04x}] ", self.run_id),
+            (true, None) => format!("[{}] ", self.run_id),
+        }
+    }
+
+    fn emit(&self, event: SyncEvent) {
+        if let Some(handler) = &self.event_handler {
+            handler(&event);
+        }
+    }
+}
+
+fn is_special_file(stat: &FileStat) -> bool {
+    matches!(
+        stat.file_type(),
+        FileType::NamedPipe | FileType::CharDevice | FileType::BlockDevice | FileType::Socket
+    )
+}
+
+/// Tab separated --completion-log line without the trailing newline
+fn completion_line(
+    timestamp: u64,
+    remote_path: &Path,
+    local_path: &Path,
+    bytes: u64,
+    duration: Duration,
+    sha256: Option<&str>,
+) -> String {
+    format!(
+        "{timestamp}\t{}\t{}\t{bytes}\t{}\t{}",
+        remote_path.display(),
+        local_path.display(),
+        duration.as_millis(),
+        sha256.unwrap_or_default()
+    )
+}
+
+/// Whether --skip-empty drops a remote file. Files with an unknown size are never treated as empty
+fn skips_empty_file(stat: &FileStat, skip_empty: bool) -> bool {
+    skip_empty && stat.size == Some(0)
+}
+
+/// Relative path of a remote name that uses `\\` or a mix of separators, as listed by some
+/// Windows servers. Empty and `.` components are dropped. Returns `None` for a `..` component or
+/// a name with nothing left, which would otherwise escape or replace the local directory.
+fn normalize_remote_name(name: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in name.split(['\\', '/']) {
+        match component {
+            "" | "." => {}
+            ".." => return None,
+            component => path.push(component),
+        }
+    }
+    (!path.as_os_str().is_empty()).then_some(path)
+}
+
+/// Name of a readdir entry, taken from the part of the entry path below the listed directory so
+/// trailing separators and root listings don't confuse [Path::file_name]. Entries whose path is
+/// the directory itself have no name and return `None`.
+fn entry_name<'a>(path: &'a Path, directory: &Path) -> Option<&'a OsStr> {
+    let Ok(relative) = path.strip_prefix(directory) else {
+        return path.file_name();
+    };
+    let mut components = relative.components();
+    match (components.next(), components.next()) {
+        (None, _) => None,
+        (Some(Component::Normal(name)), None) => Some(name),
+        _ => path.file_name(),
+    }
+}
+
+/// Bytes available to unprivileged users on the filesystem containing `path`
+#[cfg(unix)]
+fn free_space(path: &Path) -> std::io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn free_space(_path: &Path) -> std::io::Result<u64> {
+    Err(std::io::Error::new(
+        ErrorKind::Unsupported,
+        "Free space checks are only supported on Unix",
+    ))
+}
+
+/// Create a local directory and its missing parents, tolerating other workers or concurrent runs
+/// creating the same tree. A directory that exists once the call fails counts as created and a
+/// transient error is retried once before it is returned.
+fn create_local_dir_all(path: &Path) -> std::io::Result<()> {
+    let error = match std::fs::create_dir_all(path) {
+        Ok(()) => return Ok(()),
+        Err(error) => error,
+    };
+    if path.is_dir() {
+        return Ok(());
+    }
+    match error.kind() {
+        ErrorKind::AlreadyExists
+        | ErrorKind::Interrupted
+        | ErrorKind::WouldBlock
+        | ErrorKind::TimedOut
+        | ErrorKind::ResourceBusy
+        | ErrorKind::NotFound => match std::fs::create_dir_all(path) {
+            Err(_) if path.is_dir() => Ok(()),
+            result => result,
+        },
+        _ => Err(error),
+    }
+}
+
+/// Take a non-blocking exclusive lock on an existing local file. Returns `None` when the file does
+/// not exist and a [ErrorKind::WouldBlock] error when another process holds a lock. On Unix the
+/// lock is advisory so only processes that also lock the file are detected.
+fn lock_existing(path: &Path) -> std::io::Result<Option<File>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+        // ERROR_SHARING_VIOLATION, the file is open without sharing in another process
+        #[cfg(windows)]
+        Err(error) if error.raw_os_error() == Some(32) => {
+            return Err(std::io::Error::new(ErrorKind::WouldBlock, error))
+        }
+        Err(error) => return Err(error),
+    };
+    file.try_lock()?;
+    Ok(Some(file))
+}
+
+/// Reserve `size` bytes for `file`. On Linux the blocks are allocated so running out of space is
+/// reported here, elsewhere the length is set which only gives the OS a size hint.
+#[cfg(target_os = "linux")]
+fn preallocate(file: &File, size: u64) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    if size == 0 {
+        return Ok(());
+    }
+    match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, size as libc::off_t) } {
+        0 => Ok(()),
+        libc::EOPNOTSUPP | libc::EINVAL => file.set_len(size),
+        error => Err(std::io::Error::from_raw_os_error(error)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn preallocate(file: &File, size: u64) -> std::io::Result<()> {
+    file.set_len(size)
+}
+
+/// Treat a file as text when its first 8 KiB contain no NUL bytes, the same heuristic git uses
+fn is_text_file(path: &Path) -> std::io::Result<bool> {
+    let mut buffer = [0; 8192];
+    let mut file = File::open(path)?;
+    let mut length = 0;
+    while length < buffer.len() {
+        let bytes_read = file.read(&mut buffer[length..])?;
+        if bytes_read == 0 {
+            break;
+        }
+        length += bytes_read;
+    }
+    Ok(!buffer[..length].contains(&0))
+}
+
+/// Shorten the file name of `path` by at least `excess` bytes. The end of the stem is replaced by
+/// `~` and a short hash of the original name so different long names stay distinct, and the
+/// extension is kept.
+fn truncate_file_name(path: &Path, excess: usize) -> Option<PathBuf> {
+    let file_name = path.file_name()?.to_str()?;
+    let mut hasher = Sha256::new();
+    hasher.update(file_name.as_bytes());
+    let suffix = format!("~{}", &hasher.finish()[..8]);
+    let (stem, extension) = match file_name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{extension}")),
+        _ => (file_name, String::new()),
+    };
+    let keep = stem.len().checked_sub(excess + suffix.len())?;
+    if keep == 0 {
+        return None;
+    }
+    let mut end = keep;
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    Some(path.with_file_name(format!("{}{suffix}{extension}", &stem[..end])))
+}
+
+/// Resolve symlinks in the local directory so files land in the symlink target and every local
+/// path is built from the same base. Paths that cannot be resolved (e.g. a missing directory) are
+/// kept as given and reported when the sync starts.
+fn canonical_local_directory(local_directory: &Path) -> PathBuf {
+    std::fs::canonicalize(local_directory).unwrap_or_else(|_| local_directory.to_path_buf())
+}
+
+/// Writer that records progress for --stall-timeout on every write
+struct ProgressTracked<'a, W> {
+    inner: W,
+    sync: &'a SftpSync,
+}
+
+impl<W: Write> Write for ProgressTracked<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.sync.record_progress();
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reader that lowers the session timeout to the time left before `deadline` for every read, so
+/// a read blocked on the server fails with [ErrorKind::TimedOut] instead of hanging past it
+struct DeadlineBounded<'a, R> {
+    inner: R,
+    session: &'a Session,
+    /// Keeps concurrent transfers from overwriting each other's timeout
+    lock: &'a Mutex<()>,
+    deadline: Instant,
+}
+
+impl<R: Read> Read for DeadlineBounded<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                "File transfer timed out waiting for the server",
+            ));
+        }
+        let session_timeout = self.session.timeout();
+        let budget = remaining.as_millis().clamp(1, u32::MAX as u128) as u32;
+        self.session.set_timeout(match session_timeout {
+            0 => budget,
+            timeout => timeout.min(budget),
+        });
+        let result = self.inner.read(buf);
+        self.session.set_timeout(session_timeout);
+        match result {
+            Err(error) if Instant::now() >= self.deadline => Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                format!("File transfer timed out waiting for the server. {error}"),
+            )),
+            result => result,
+        }
+    }
+}
+
+/// Copy `reader` into `writer`, failing with [ErrorKind::TimedOut] once `deadline` has passed
+fn copy_stream<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    buffer_size: usize,
+    deadline: Option<Instant>,
+    trace: Option<(&TransferTrace, &Path)>,
+    cancellation: Option<&CancellationToken>,
+) -> std::io::Result<u64> {
+    let mut buffer = vec![0; buffer_size];
+    let mut bytes_written = 0;
+    loop {
+        if cancellation.is_some_and(CancellationToken::is_cancelled) {
+            return Err(std::io::Error::new(
+                ErrorKind::Interrupted,
+                format!("File transfer cancelled after {bytes_written} bytes"),
+            ));
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                format!("File transfer timed out after {bytes_written} bytes"),
+            ));
+        }
+        let read_started = Instant::now();
+        let bytes_read = reader.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        let write_started = Instant::now();
+        writer.write_all(&buffer[0..bytes_read])?;
+        if let Some((trace, remote_path)) = trace {
+            trace.record(
+                remote_path,
+                bytes_written,
+                bytes_read,
+                write_started - read_started,
+                write_started.elapsed(),
+            );
+        }
+        bytes_written += bytes_read as u64;
+    }
+    Ok(bytes_written)
+}
+
+#[cfg(unix)]
+fn sync_permissions(local_path: &Path, stat: &FileStat) -> std::io::Result<bool> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let Some(remote_mode) = stat.perm.map(|perm| perm & 0o7777) else {
+        return Ok(false);
+    };
+    let mut permissions = local_path.metadata()?.permissions();
+    if permissions.mode() & 0o7777 == remote_mode {
+        return Ok(false);
+    }
+    permissions.set_mode(remote_mode);
+    std::fs::set_permissions(local_path, permissions)?;
+    Ok(true)
+}
+
+#[cfg(not(unix))]
+fn sync_permissions(_local_path: &Path, _stat: &FileStat) -> std::io::Result<bool> {
+    Ok(false)
+}
+
+/// Remote paths found while listing for --prune-only
+#[derive(Debug, Default)]
+struct RemoteTree {
+    /// Entries the walk visited. Directories in this set had their contents listed
+    listed: HashSet<PathBuf>,
+    /// Entries that exist remotely but whose contents were not listed, so neither they nor
+    /// anything below them can be pruned
+    unlisted: HashSet<PathBuf>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum PruneAction {
+    Keep,
+    /// Keep the directory and check its contents
+    Descend,
+    Delete,
+}
+
+impl RemoteTree {
+    fn prune_action(&self, remote_relative_path: &Path, is_dir: bool) -> PruneAction {
+        if self.unlisted.contains(remote_relative_path) {
+            PruneAction::Keep
+        } else if !self.listed.contains(remote_relative_path) {
+            PruneAction::Delete
+        } else if is_dir {
+            PruneAction::Descend
+        } else {
+            PruneAction::Keep
+        }
+    }
+}
+
+fn random_fraction() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Empty directory unique to this test process
+    fn temp_dir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("sftp-sync-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn prune_keeps_filtered_out_remote_file() {
+        let remote_tree = RemoteTree {
+            listed: HashSet::from([PathBuf::from("data"), PathBuf::from("data/kept.csv")]),
+            unlisted: HashSet::from([PathBuf::from("data/large.bin")]),
+        };
+        assert_eq!(
+            remote_tree.prune_action(Path::new("data/large.bin"), false),
+            PruneAction::Keep
+        );
+        assert_eq!(
+            remote_tree.prune_action(Path::new("data/kept.csv"), false),
+            PruneAction::Keep
+        );
+        assert_eq!(
+            remote_tree.prune_action(Path::new("data/removed.csv"), false),
+            PruneAction::Delete
+        );
+    }
+
+    #[test]
+    fn prune_keeps_unlisted_directory_contents() {
+        let remote_tree = RemoteTree {
+            listed: HashSet::from([PathBuf::from("data"), PathBuf::from("alias")]),
+            unlisted: HashSet::from([PathBuf::from("alias"), PathBuf::from("filtered")]),
+        };
+        assert_eq!(
+            remote_tree.prune_action(Path::new("data"), true),
+            PruneAction::Descend
+        );
+        assert_eq!(
+            remote_tree.prune_action(Path::new("alias"), true),
+            PruneAction::Keep
+        );
+        assert_eq!(
+            remote_tree.prune_action(Path::new("filtered"), true),
+            PruneAction::Keep
+        );
+        assert_eq!(
+            remote_tree.prune_action(Path::new("gone"), true),
+            PruneAction::Delete
+        );
+    }
+
+    #[test]
+    fn entry_name_of_directory_itself_is_none() {
+        assert_eq!(entry_name(Path::new("/data"), Path::new("/data")), None);
+        assert_eq!(entry_name(Path::new("/data/"), Path::new("/data")), None);
+        assert_eq!(entry_name(Path::new("/"), Path::new("/")), None);
+    }
+
+    #[test]
+    fn entry_name_with_trailing_separator() {
+        assert_eq!(
+            entry_name(Path::new("/data/reports/"), Path::new("/data")),
+            Some(OsStr::new("reports"))
+        );
+        assert_eq!(
+            entry_name(Path::new("/data/reports"), Path::new("/data/")),
+            Some(OsStr::new("reports"))
+        );
+        assert_eq!(
+            entry_name(Path::new("/etc"), Path::new("/")),
+            Some(OsStr::new("etc"))
+        );
+    }
+
+    #[test]
+    fn entry_name_outside_listed_directory_uses_file_name() {
+        assert_eq!(
+            entry_name(Path::new("/other/file.txt"), Path::new("/data")),
+            Some(OsStr::new("file.txt"))
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn entry_name_keeps_latin1_bytes() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let name = OsStr::from_bytes(b"caf\xe9.txt");
+        let path = Path::new("/data/reports").join(name);
+        let entry = entry_name(&path, Path::new("/data/reports")).unwrap();
+        assert_eq!(entry.as_bytes(), b"caf\xe9.txt");
+        assert_eq!(entry.to_str(), None);
+        let relative_path = Path::new("reports").join(entry);
+        assert_eq!(relative_path.as_os_str().as_bytes(), b"reports/caf\xe9.txt");
+
+        // The local filesystem takes the same bytes as the file name
+        let root = temp_dir("latin1-name");
+        std::fs::write(root.join(entry), "contents").unwrap();
+        let names: Vec<OsString> = std::fs::read_dir(&root)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, [OsString::from(name)]);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    fn file_stat(size: Option<u64>) -> FileStat {
+        FileStat {
+            size,
+            uid: None,
+            gid: None,
+            perm: Some(0o100644),
+            atime: None,
+            mtime: Some(1_700_000_000),
+        }
+    }
+
+    #[test]
+    fn skip_empty_only_drops_zero_byte_files() {
+        assert!(skips_empty_file(&file_stat(Some(0)), true));
+        assert!(!skips_empty_file(&file_stat(Some(1)), true));
+        assert!(!skips_empty_file(&file_stat(None), true));
+        assert!(!skips_empty_file(&file_stat(Some(0)), false));
+    }
+
+    #[test]
+    fn empty_remote_file_creates_empty_local_file() {
+        let root = temp_dir("empty-file");
+        let local_path = root.join("empty.txt");
+        let mut file = File::create(&local_path).unwrap();
+        let bytes = copy_stream(&mut std::io::empty(), &mut file, 16, None, None, None);
+        assert_eq!(bytes.unwrap(), 0);
+        assert_eq!(std::fs::metadata(&local_path).unwrap().len(), 0);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn special_files_are_detected_from_mode_bits() {
+        use std::os::unix::fs::MetadataExt;
+
+        let root = temp_dir("special-files");
+        let fifo = root.join("fifo");
+        let fifo_path = std::ffi::CString::new(fifo.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo_path.as_ptr(), 0o644) }, 0);
+        let socket = root.join("socket");
+        let _listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+        std::fs::write(root.join("file"), "").unwrap();
+        std::fs::create_dir(root.join("dir")).unwrap();
+        // Stat without opening, like an SFTP listing, so the fifo is never blocked on
+        let stat_of = |name: &str| FileStat {
+            perm: Some(std::fs::symlink_metadata(root.join(name)).unwrap().mode()),
+            ..file_stat(Some(0))
+        };
+        assert!(is_special_file(&stat_of("fifo")));
+        assert!(is_special_file(&stat_of("socket")));
+        assert!(!is_special_file(&stat_of("file")));
+        assert!(!is_special_file(&stat_of("dir")));
+        let dev_null = std::fs::metadata("/dev/null").unwrap().mode();
+        assert!(is_special_file(&FileStat {
+            perm: Some(dev_null),
+            ..file_stat(Some(0))
+        }));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn completion_line_includes_computed_checksum() {
+        let line = |sha256| {
+            completion_line(
+                1_700_000_000,
+                Path::new("/data/a.csv"),
+                Path::new("/backup/a.csv"),
+                42,
+                Duration::from_millis(1500),
+                sha256,
+            )
+        };
+        assert_eq!(
+            line(Some("e3b0c442")),
+            "1700000000\t/data/a.csv\t/backup/a.csv\t42\t1500\te3b0c442"
+        );
+        assert_eq!(
+            line(None),
+            "1700000000\t/data/a.csv\t/backup/a.csv\t42\t1500\t"
+        );
+    }
+
+    /// Reader recording the session timeout in effect during each read
+    struct TimeoutProbe<'a> {
+        session: &'a Session,
+        seen: Vec<u32>,
+    }
+
+    impl Read for TimeoutProbe<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.seen.push(self.session.timeout());
+            buf[0] = 1;
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn file_timeout_bounds_each_read_with_the_session_timeout() {
+        let session = Session::new().unwrap();
+        let lock = Mutex::new(());
+        let mut reader = DeadlineBounded {
+            inner: TimeoutProbe {
+                session: &session,
+                seen: Vec::new(),
+            },
+            session: &session,
+            lock: &lock,
+            deadline: Instant::now() + Duration::from_secs(30),
+        };
+        let mut buffer = [0; 4];
+        assert_eq!(reader.read(&mut buffer).unwrap(), 1);
+        let seen = reader.inner.seen[0];
+        assert!(seen > 25_000 && seen <= 30_000, "{seen}");
+        // A shorter configured session timeout is kept and restored after the read
+        session.set_timeout(5_000);
+        assert_eq!(reader.read(&mut buffer).unwrap(), 1);
+        assert_eq!(reader.inner.seen[1], 5_000);
+        assert_eq!(session.timeout(), 5_000);
+
+        reader.deadline = Instant::now();
+        let error = reader.read(&mut buffer).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        assert_eq!(reader.inner.seen.len(), 2);
+        assert_eq!(session.timeout(), 5_000);
+    }
+
+    #[test]
+    fn copy_stream_stops_once_cancelled() {
+        let data = vec![7u8; 64];
+        let mut copied = Vec::new();
+        let token = CancellationToken::new();
+        let bytes = copy_stream(&mut &data[..], &mut copied, 16, None, None, Some(&token));
+        assert_eq!(bytes.unwrap(), 64);
+        assert_eq!(copied, data);
+
+        token.cancel();
+        let mut copied = Vec::new();
+        let error =
+            copy_stream(&mut &data[..], &mut copied, 16, None, None, Some(&token)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Interrupted);
+        assert!(copied.is_empty());
+    }
+
+    #[test]
+    fn concurrent_local_dir_creation_succeeds() {
+        let root = temp_dir("create-dir-all");
+        let path = root.join("a/b/c/d/e");
+        let barrier = std::sync::Barrier::new(16);
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..16)
+                .map(|_| {
+                    scope.spawn(|| {
+                        barrier.wait();
+                        create_local_dir_all(&path)
+                    })
+                })
+                .collect();
+            for handle in handles {
+                assert!(handle.join().unwrap().is_ok());
+            }
+        });
+        assert!(path.is_dir());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn local_dir_creation_fails_over_a_file() {
+        let root = temp_dir("create-dir-over-file");
+        std::fs::write(root.join("file"), "").unwrap();
+        assert!(create_local_dir_all(&root.join("file/child")).is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn normalizes_backslash_remote_names() {
+        let normalized = |name: &str| normalize_remote_name(name);
+        assert_eq!(
+            normalized("logs\\app.log"),
+            Some(PathBuf::from("logs/app.log"))
+        );
+        assert_eq!(
+            normalized("a\\b/c\\d.txt"),
+            Some(PathBuf::from("a/b/c/d.txt"))
+        );
+        assert_eq!(normalized(".\\a\\\\b\\"), Some(PathBuf::from("a/b")));
+        assert_eq!(normalized("plain.txt"), Some(PathBuf::from("plain.txt")));
+        assert_eq!(normalized("..\\escape.txt"), None);
+        assert_eq!(normalized("a\\..\\..\\escape.txt"), None);
+        assert_eq!(normalized("a/../b"), None);
+        assert_eq!(normalized("\\.\\"), None);
+        assert_eq!(
+            normalized("logs\\app.log").unwrap().file_name(),
+            Some(OsStr::new("app.log"))
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn local_directory_symlink_resolves_to_its_target() {
+        let root = temp_dir("symlinked-destination");
+        let target = root.join("target");
+        std::fs::create_dir(&target).unwrap();
+        let link = root.join("link");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        assert_eq!(
+            canonical_local_directory(&link),
+            std::fs::canonicalize(&target).unwrap()
+        );
+        assert_eq!(
+            canonical_local_directory(&link.join("..").join("link")),
+            std::fs::canonicalize(&target).unwrap()
+        );
+        let missing = root.join("missing");
+        assert_eq!(canonical_local_directory(&missing), missing);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,244 @@
+use crate::json;
+use clap::ValueEnum;
+use ssh2::{FileStat, FileType};
+use std::collections::HashMap;
+use std::fs::{File, Metadata};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// File format of a remote listing written by --save-listing
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ListingFormat {
+    /// One `type\tsize\tmtime\tperm\tpath` line per entry with `-` for missing values
+    #[default]
+    Tsv,
+    /// Array of objects with `path`, `type`, `size`, `mtime` and `perm` keys
+    Json,
+}
+
+fn type_name(stat: &FileStat) -> &'static str {
+    match stat.file_type() {
+        FileType::Directory => "dir",
+        FileType::RegularFile => "file",
+        FileType::Symlink => "symlink",
+        _ => "other",
+    }
+}
+
+fn create_temp(path: &Path) -> std::io::Result<(PathBuf, BufWriter<File>)> {
+    let mut temp_path = path.as_os_str().to_os_string();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+    let file = BufWriter::new(File::create(&temp_path)?);
+    Ok((temp_path, file))
+}
+
+/// Write the remote entries sorted by path. The file is written to a temporary sibling and
+/// renamed into place so readers never see a partial listing.
+pub fn write_listing(
+    path: &Path,
+    entries: &mut [(PathBuf, FileStat)],
+    format: ListingFormat,
+) -> std::io::Result<()> {
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let (temp_path, mut file) = create_temp(path)?;
+    match format {
+        ListingFormat::Tsv => {
+            let optional = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+            for (relative_path, stat) in entries.iter() {
+                writeln!(
+                    file,
+                    "{}\t{}\t{}\t{}\t{}",
+                    type_name(stat),
+                    optional(stat.size.map(|size| size.to_string())),
+                    optional(stat.mtime.map(|mtime| mtime.to_string())),
+                    optional(stat.perm.map(|perm| format!("{:04o}", perm & 0o7777))),
+                    relative_path.display()
+                )?;
+            }
+        }
+        ListingFormat::Json => {
+            let optional = |value: Option<String>| value.unwrap_or_else(|| "null".to_string());
+            writeln!(file, "[")?;
+            for (index, (relative_path, stat)) in entries.iter().enumerate() {
+                let separator = if index + 1 < entries.len() { "," } else { "" };
+                let entry = json::object([
+                    ("path", json::string(&relative_path.to_string_lossy())),
+                    ("type", json::string(type_name(stat))),
+                    ("size", optional(stat.size.map(|size| size.to_string()))),
+                    ("mtime", optional(stat.mtime.map(|mtime| mtime.to_string()))),
+                    (
+                        "perm",
+                        optional(
+                            stat.perm
+                                .map(|perm| json::string(&format!("{:04o}", perm & 0o7777))),
+                        ),
+                    ),
+                ]);
+                writeln!(file, "  {entry}{separator}")?;
+            }
+            writeln!(file, "]")?;
+        }
+    }
+    file.flush()?;
+    drop(file);
+    std::fs::rename(&temp_path, path)
+}
+
+/// Write local entries as a JSON array sorted by path, using the same `path`, `type`, `size` and
+/// `mtime` keys as a JSON remote listing so snapshots of both sides can be compared directly.
+pub fn write_local_tree(path: &Path, entries: &mut [(PathBuf, Metadata)]) -> std::io::Result<()> {
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let (temp_path, mut file) = create_temp(path)?;
+    writeln!(file, "[")?;
+    for (index, (relative_path, metadata)) in entries.iter().enumerate() {
+        let separator = if index + 1 < entries.len() { "," } else { "" };
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or_else(|| "null".to_string(), |d| d.as_secs().to_string());
+        let (type_name, size) = if metadata.is_dir() {
+            ("dir", "null".to_string())
+        } else {
+            ("file", metadata.len().to_string())
+        };
+        let entry = json::object([
+            ("path", json::string(&relative_path.to_string_lossy())),
+            ("type", json::string(type_name)),
+            ("size", size),
+            ("mtime", mtime),
+        ]);
+        writeln!(file, "  {entry}{separator}")?;
+    }
+    writeln!(file, "]")?;
+    file.flush()?;
+    drop(file);
+    std::fs::rename(&temp_path, path)
+}
+
+/// One entry of a listing read back by [read_listing]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListingEntry {
+    pub path: PathBuf,
+    /// `dir`, `file`, `symlink` or `other`
+    pub kind: String,
+    pub size: Option<u64>,
+    pub mtime: Option<u64>,
+}
+
+/// Read a listing written by --save-listing. The format is detected from the content so TSV and
+/// JSON snapshots can be mixed.
+pub fn read_listing(path: &Path) -> Result<Vec<ListingEntry>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|error| format!("Could not read listing {path:?}. {error}"))?;
+    if contents.trim_start().starts_with('[') {
+        return parse_json_listing(&contents).map_err(|error| format!("{path:?} {error}"));
+    }
+    let optional = |value: &str| match value {
+        "-" => Ok(None),
+        value => value.parse().map(Some),
+    };
+    let mut entries = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let fields: Vec<&str> = line.splitn(5, '\t').collect();
+        let [kind, size, mtime, _perm, relative_path] = fields[..] else {
+            return Err(format!("Listing {path:?} line {} is malformed", index + 1));
+        };
+        let (Ok(size), Ok(mtime)) = (optional(size), optional(mtime)) else {
+            return Err(format!(
+                "Listing {path:?} line {} has an invalid size or mtime",
+                index + 1
+            ));
+        };
+        entries.push(ListingEntry {
+            path: PathBuf::from(relative_path),
+            kind: kind.to_string(),
+            size,
+            mtime,
+        });
+    }
+    Ok(entries)
+}
+
+fn parse_json_listing(contents: &str) -> Result<Vec<ListingEntry>, String> {
+    let json::Value::Array(items) = json::parse(contents)? else {
+        return Err("is not a JSON array".to_string());
+    };
+    items
+        .iter()
+        .map(|item| {
+            let path = item
+                .get("path")
+                .and_then(json::Value::as_str)
+                .ok_or("has an entry without a path")?;
+            Ok(ListingEntry {
+                path: PathBuf::from(path),
+                kind: item
+                    .get("type")
+                    .and_then(json::Value::as_str)
+                    .unwrap_or("other")
+                    .to_string(),
+                size: item.get("size").and_then(json::Value::as_u64),
+                mtime: item.get("mtime").and_then(json::Value::as_u64),
+            })
+        })
+        .collect()
+}
+
+/// Changes between two listings of the same remote tree
+#[derive(Debug, Default)]
+pub struct ListingDiff<'a> {
+    pub added: Vec<&'a ListingEntry>,
+    pub removed: Vec<&'a ListingEntry>,
+    /// Files present in both listings with a different size, as `(old, new)`
+    pub resized: Vec<(&'a ListingEntry, &'a ListingEntry)>,
+    /// Files with the same size but a different mtime, as `(old, new)`
+    pub modified: Vec<(&'a ListingEntry, &'a ListingEntry)>,
+}
+
+impl ListingDiff<'_> {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.resized.is_empty()
+            && self.modified.is_empty()
+    }
+}
+
+/// Compare two listings by path. Every list in the result is sorted by path.
+pub fn diff_listings<'a>(old: &'a [ListingEntry], new: &'a [ListingEntry]) -> ListingDiff<'a> {
+    let old_entries: HashMap<&Path, &ListingEntry> = old
+        .iter()
+        .map(|entry| (entry.path.as_path(), entry))
+        .collect();
+    let new_entries: HashMap<&Path, &ListingEntry> = new
+        .iter()
+        .map(|entry| (entry.path.as_path(), entry))
+        .collect();
+    let mut diff = ListingDiff::default();
+    for entry in new {
+        let Some(&previous) = old_entries.get(entry.path.as_path()) else {
+            diff.added.push(entry);
+            continue;
+        };
+        if entry.kind != "file" || previous.kind != "file" {
+            continue;
+        }
+        if entry.size != previous.size {
+            diff.resized.push((previous, entry));
+        } else if entry.mtime != previous.mtime {
+            diff.modified.push((previous, entry));
+        }
+    }
+    diff.removed = old
+        .iter()
+        .filter(|entry| !new_entries.contains_key(entry.path.as_path()))
+        .collect();
+    diff.added.sort_by(|a, b| a.path.cmp(&b.path));
+    diff.removed.sort_by(|a, b| a.path.cmp(&b.path));
+    diff.resized.sort_by(|(_, a), (_, b)| a.path.cmp(&b.path));
+    diff.modified.sort_by(|(_, a), (_, b)| a.path.cmp(&b.path));
+    diff
+}
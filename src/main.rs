@@ -1,225 +1,1555 @@
-use clap::Parser;
-use rayon::prelude::*;
-use ssh2::{Session, Sftp};
-use std::fs::File;
-use std::io::{Read, Write};
-use std::net::TcpStream;
+#[cfg(unix)]
+mod event_socket;
+mod hosts;
+mod metrics;
+
+use clap::{ArgAction, ArgMatches, Command, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use sftp_sync::compare::CompareMode;
+use sftp_sync::compress::Compression;
+use sftp_sync::filter::Filter;
+use sftp_sync::json;
+use sftp_sync::listing::{diff_listings, read_listing, ListingEntry, ListingFormat};
+use sftp_sync::manifest::build_manifest;
+use sftp_sync::remote_error::RemoteError;
+use sftp_sync::schedule::{LocalTime, Schedule};
+use sftp_sync::{
+    validate_temp_suffix, AlgorithmPreferences, AllowedNetwork, CancellationToken, Cancelled,
+    Collision, ConnectionBuilder, HostKeyPolicy, LatestBy, LongPath, RemoteSeparator, SftpSync,
+    SizeChange, SkippedMarkers, SpecialFiles, SyncOptions, SyncStats, TransferOrder,
+    DEFAULT_MAX_PATH_LENGTH, DEFAULT_TEMP_SUFFIX,
+};
+use std::ffi::OsString;
+use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::process::exit;
-
-const BUFFER_SIZE: usize = 1024 * 128;
-const CLEAR_LINE: &str = "\x1B[2K";
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 fn hide_cursor() {
     print!("\x1B[?25l")
 }
 
-fn show_cursor() -> ! {
+fn show_cursor(code: i32) -> ! {
     print!("\x1B[?25h");
-    exit(0)
+    exit(code)
+}
+
+/// Exit status of a finished run. Cancelled runs exit with 130 like an interrupted command and
+/// runs that failed or could not transfer every file with 1
+fn exit_code(result: &Result<(), Box<dyn std::error::Error>>, stats: &SyncStats) -> i32 {
+    match result {
+        Err(error) if error.is::<Cancelled>() => 130,
+        Err(_) => 1,
+        Ok(()) if stats.failed > 0 => 1,
+        Ok(()) => 0,
+    }
 }
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
     #[arg(long)]
-    ip: String,
-    #[arg(short, long, default_value_t = 22)]
-    port: u16,
+    ip: Option<String>,
+    /// Port of the SFTP server. Defaults to 22
+    #[arg(short, long)]
+    port: Option<u16>,
     #[arg(long)]
-    username: String,
+    username: Option<String>,
     #[arg(long)]
     password: Option<String>,
+    /// Private key file used to authenticate instead of a password
+    #[arg(long)]
+    private_key: Option<PathBuf>,
+    /// Name of a host entry in the hosts file providing the ip, port, username and key.
+    /// Explicit flags take precedence over the values in the entry
+    #[arg(long)]
+    host_alias: Option<String>,
+    /// Hosts file used to resolve --host-alias. Defaults to ~/.config/sftp-sync/hosts.toml
+    #[arg(long)]
+    hosts_file: Option<PathBuf>,
+    /// File or directory to exclude. Can be repeated. A plain name (e.g. node_modules) excludes
+    /// every entry with that name anywhere in the tree. A pattern containing a slash (e.g.
+    /// src/generated) is matched against the path relative to --remote-directory, so it only
+    /// excludes that subtree. Path patterns support the * ? and ** wildcards
     #[arg(long)]
     exclude: Option<Vec<String>>,
+    /// Match --exclude names and glob patterns case-insensitively
+    #[arg(long)]
+    ignore_case: bool,
+    /// Local directory to sync into. Use - to write a single remote file given as
+    /// --remote-directory to stdout instead. If this is a symlink, files are written into the
+    /// symlink target
     #[arg(short, long)]
     local_directory: PathBuf,
+    /// Remote directory to sync from. Repeat to merge several remote directories into the local
+    /// directory. Files that exist in more than one are handled by --on-collision
+    #[arg(short, long, required = true)]
+    remote_directory: Vec<PathBuf>,
+    /// How to handle a file found in more than one --remote-directory. first-wins keeps the file
+    /// from the directory listed first
+    #[arg(long, value_enum, default_value_t = Collision::FirstWins)]
+    on_collision: Collision,
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    follow_root_symlink: bool,
+    /// Append a tab separated line for every downloaded file: Unix timestamp, remote path, local
+    /// path, bytes, milliseconds taken and the SHA-256 of the file when it was computed for
+    /// --verify-against or --checksum-cache, otherwise empty
+    #[arg(long)]
+    completion_log: Option<PathBuf>,
+    /// Comma separated list of file extensions to exclude (e.g. iso,zip,tmp). Matching is
+    /// case-insensitive and applies to files only
+    #[arg(long, value_delimiter = ',')]
+    exclude_ext: Vec<String>,
+    /// Transfer files one at a time instead of in parallel
+    #[arg(long)]
+    sequential: bool,
+    /// Strategy used to decide if a file that exists locally needs to be downloaded again
+    #[arg(long, value_enum, default_value_t = CompareMode::Size)]
+    compare: CompareMode,
+    /// File used to cache local checksums between runs when comparing by checksum. Cached
+    /// hashes are reused while a file's size and modification time are unchanged
+    #[arg(long)]
+    checksum_cache: Option<PathBuf>,
+    /// Testing only. Randomly fail transfers at the given rate (0.0 to 1.0) to exercise the error
+    /// handling paths
+    #[arg(long, hide = true, value_parser = parse_rate)]
+    chaos: Option<f64>,
+    /// Stop dispatching transfers once this many files have failed
+    #[arg(long)]
+    max_errors: Option<usize>,
+    /// List the remote files and directories that would be considered for a sync then exit
+    #[arg(long)]
+    list_remote: bool,
+    /// Before downloading a new file, look for a local file with the same size and content (e.g.
+    /// after a remote rename) and hard link or copy it instead. The remote file is hashed with
+    /// `sha256sum` on the server over an exec channel so it is not downloaded to compare it.
+    /// Files are downloaded as usual when the server has no `sha256sum`
+    #[arg(long)]
+    dedup_local: bool,
+    /// Skip remote files that report a size of zero bytes
+    #[arg(long)]
+    skip_empty: bool,
+    /// Transfer files matching GLOB before lower weighted files, formatted as <GLOB>:<WEIGHT>.
+    /// Can be repeated and the first matching pattern wins. Files that match no pattern have a
+    /// weight of 0. Each weight class is transferred fully before moving to the next. Without
+    /// this option all files share a single pool
+    #[arg(long, value_parser = parse_priority)]
+    priority: Vec<(String, i32)>,
+    /// Only recreate the remote directory tree locally without downloading any files
+    #[arg(long, conflicts_with = "only_files")]
+    only_dirs: bool,
+    /// Only download files. This is the default behaviour and the flag exists to document intent
+    #[arg(long)]
+    only_files: bool,
+    /// Periodically write the remaining transfer queue to this file so an interrupted sync can be
+    /// continued with --resume-from. The file is removed once every queued file completes
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+    /// Number of completed files between writes of --state-file
+    #[arg(long, default_value_t = 100)]
+    checkpoint_interval: usize,
+    /// Resume the queue saved in a state file instead of walking the remote tree
+    #[arg(long)]
+    resume_from: Option<PathBuf>,
+    /// Update the permissions of local files that are already up to date when they differ from
+    /// the remote file's mode. Unix only
+    #[arg(long)]
+    sync_permissions: bool,
+    /// Print the resolved configuration as TOML then exit without connecting. Secrets are
+    /// redacted
+    #[arg(long)]
+    print_config: bool,
+    /// Stop starting new transfers once the sync has run for this long (e.g. 90s, 30m, 2h).
+    /// In-flight transfers are allowed to finish
+    #[arg(long, value_parser = parse_duration)]
+    max_runtime: Option<Duration>,
+    /// Stagger the start of the initial parallel transfers across this many seconds, with a
+    /// random jitter per worker, instead of starting them all at once
+    #[arg(long, value_parser = parse_duration)]
+    ramp_up: Option<Duration>,
+    /// Comma separated cipher preference list (e.g. aes256-ctr,aes128-ctr). Defaults to the
+    /// libssh2 defaults
+    #[arg(long)]
+    cipher: Option<String>,
+    /// Comma separated key exchange preference list. Defaults to the libssh2 defaults
+    #[arg(long)]
+    kex: Option<String>,
+    /// Comma separated MAC preference list. Defaults to the libssh2 defaults
+    #[arg(long)]
+    mac: Option<String>,
+    /// Unix socket that receives progress events as newline delimited JSON. Connects to an
+    /// existing listener or creates the socket and accepts consumers. Unix only
+    #[arg(long)]
+    event_socket: Option<PathBuf>,
+    /// Check files that already exist locally against the remote using the --compare mode and
+    /// report mismatches without downloading anything
+    #[arg(long)]
+    verify_only: bool,
+    /// With --verify-only, download the files that do not match the remote
+    #[arg(long, requires = "verify_only")]
+    repair: bool,
+    /// Copy extended attributes of downloaded files. SFTP cannot transfer xattrs so they are read
+    /// by running getfattr on the server and applied locally (Linux only). Skipped with a warning
+    /// when either side does not support it
+    #[arg(long)]
+    preserve_xattrs: bool,
+    /// Show what would be transferred without changing anything locally. New files are marked
+    /// with + and changed files with ~
+    #[arg(long)]
+    dry_run: bool,
+    /// With --dry-run, only print the number of files in each category
+    #[arg(long, requires = "dry_run")]
+    summary_only: bool,
+    /// What to do with remote fifos, sockets and device nodes
+    #[arg(long, value_enum, default_value_t = SpecialFiles::Skip)]
+    special_files: SpecialFiles,
+    /// Print one JSON object with the run metrics as the last line of stdout. All other output is
+    /// moved to stderr so stdout only contains the summary
+    #[arg(long)]
+    summary_json_line: bool,
+    /// Manifest of the local directory written by --build-local-index. Files listed in the index
+    /// are compared using the recorded values instead of stat'ing the local file. Files missing
+    /// from the index fall back to the local filesystem
+    #[arg(long)]
+    local_index: Option<PathBuf>,
+    /// Write a manifest of the local directory to this file for use with --local-index, then exit.
+    /// Hashes are included when --compare is checksum or both
+    #[arg(long, conflicts_with = "local_index")]
+    build_local_index: Option<PathBuf>,
+    /// Cap the total download rate in bytes per second. Accepts K, M and G suffixes (e.g. 10M).
+    /// The budget is split evenly between active transfers so one large file cannot starve the
+    /// others. Shares are rebalanced once per second as transfers start and finish
+    #[arg(long, value_parser = parse_byte_rate)]
+    max_rate: Option<u64>,
+    /// Only sync regular files directly inside the remote directory. Subdirectories are neither
+    /// traversed nor created locally
+    #[arg(long)]
+    no_recurse: bool,
+    /// Abort unless the server host key matches this fingerprint. Accepts the SHA256:<base64>
+    /// format printed by ssh-keygen -l or the legacy MD5:aa:bb:... format
+    #[arg(long)]
+    host_fingerprint: Option<String>,
+    /// Comma separated host key algorithms the server key must use (e.g. ssh-ed25519). The
+    /// connection is refused when the server presents any other key type
+    #[arg(long, value_delimiter = ',')]
+    require_host_key_algo: Vec<String>,
+    /// Refuse servers whose RSA host key is smaller than this many bits
+    #[arg(long)]
+    min_rsa_bits: Option<usize>,
+    /// Print the N slowest transfers and their effective rates at the end of the run. Also added
+    /// to --summary-json-line as a "slowest" array
+    #[arg(long, value_name = "N")]
+    show_slowest: Option<usize>,
+    /// Cache the mtimes of remote directories whose files are all in sync after a sync and skip
+    /// comparing the files directly inside directories whose mtime has not changed since.
+    /// Subdirectories are still listed and checked against their own cached mtime, so changes in
+    /// nested directories are found. A directory's mtime only changes when entries are added,
+    /// removed or renamed directly inside it, so files modified in place are NOT detected and
+    /// some servers do not update directory mtimes at all. Only use this when files are written
+    /// by creating new files (e.g. upload then rename)
+    #[arg(long, value_name = "CACHE_FILE")]
+    trust_dir_mtime: Option<PathBuf>,
+    /// Remote sha256sum style manifest (e.g. SHA256SUMS) to check every downloaded file against.
+    /// Relative paths are resolved against --remote-directory and the paths listed inside it
+    /// against the manifest's own directory. Files that do not match, or that the manifest does
+    /// not list, are reported as failed transfers
+    #[arg(long, value_name = "REMOTE_MANIFEST")]
+    verify_against: Option<PathBuf>,
+    /// With --verify-against, download a mismatched file once more before failing it
+    #[arg(long, requires = "verify_against")]
+    redownload_on_mismatch: bool,
+    /// Do not print a line for every excluded file or directory. The total is printed once the
+    /// remote walk finishes. --verbose brings the individual lines back
+    #[arg(long)]
+    quiet_skips: bool,
+    /// Print extra detail, including the server banner, negotiated algorithms and authentication
+    /// method after connecting and the individual lines hidden by --quiet-skips
     #[arg(short, long)]
-    remote_directory: PathBuf,
+    verbose: bool,
+    /// Inherited file descriptor of a socket that is already connected to the SSH server. Used
+    /// instead of connecting to --ip when network access is provided by a parent process
+    #[arg(long, value_name = "N")]
+    fd: Option<i32>,
+    /// Only sync the newest file matching this glob in each directory, e.g. 'app.log.*' to skip
+    /// older log rotations. Files that do not match are synced as usual
+    #[arg(long, value_name = "GLOB")]
+    latest: Option<String>,
+    /// How --latest picks the newest match
+    #[arg(long, value_enum, default_value_t = LatestBy::Mtime, requires = "latest")]
+    latest_by: LatestBy,
+    /// Connect, authenticate and list the top level of --remote-directory without transferring
+    /// anything. Prints the server banner and negotiated algorithms. Exits non-zero on failure
+    #[arg(long)]
+    probe: bool,
+    /// Output format of --probe
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+    /// Separator used in remote file names. Names containing backslashes from Windows servers
+    /// are split into local subdirectories when this is backslash. auto infers it from the first
+    /// remote listing
+    #[arg(long, value_enum, default_value_t = RemoteSeparator::Auto)]
+    remote_sep: RemoteSeparator,
+    /// Longest local path in bytes that files are written to. Defaults to the platform limit
+    #[arg(long, default_value_t = DEFAULT_MAX_PATH_LENGTH)]
+    max_path_length: usize,
+    /// What to do with files whose local path is longer than --max-path-length
+    #[arg(long, value_enum, default_value_t = LongPath::Skip)]
+    on_long_path: LongPath,
+    /// Remote change feed that restricts the sync to the files it lists instead of walking the
+    /// whole remote tree. Relative paths are resolved against --remote-directory. The feed lists
+    /// one path per line relative to --remote-directory. Blank lines and lines starting with #
+    /// are ignored. Excludes, --exclude-ext and the other file filters still apply to the listed
+    /// paths, directories and missing files are skipped. Falls back to a full walk when the feed
+    /// cannot be opened
+    #[arg(long, value_name = "REMOTE_FILE")]
+    changes_from: Option<PathBuf>,
+    /// Write Prometheus text format metrics for the run to this file (e.g. for the node_exporter
+    /// textfile collector). The file is replaced atomically at the end of every sync
+    #[arg(long)]
+    metrics_file: Option<PathBuf>,
+    /// Limit how many files from the same remote directory are transferred at once. Useful for
+    /// servers that throttle or lock per directory. Files in different directories still run in
+    /// parallel
+    #[arg(long, value_name = "N")]
+    per_dir_jobs: Option<usize>,
+    /// Ask before overwriting changed local text files. The remote version is downloaded next to
+    /// the local file first and shown with --diff-command. New and binary files are transferred
+    /// without asking. Transfers run sequentially in this mode
+    #[arg(long)]
+    interactive: bool,
+    /// Command that shows the difference between two files (e.g. "diff -u"), run through sh with
+    /// the local and remote versions appended
+    #[arg(long, requires = "interactive", value_name = "CMD")]
+    diff_command: Option<String>,
+    /// Print a plain progress line (files, bytes, percent and rate) every N seconds for logs
+    /// from CI or cron. Ignored when stdout is a terminal
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    heartbeat_secs: Option<u64>,
+    /// Fail a single file when its download takes longer than N seconds in total and move on to
+    /// the next file. Unlike a socket timeout this also bounds slow transfers that keep receiving
+    /// small amounts of data
+    #[arg(long, value_name = "N")]
+    file_timeout_secs: Option<u64>,
+    /// Exclude remote entries matching an expression, e.g. 'size > 10M && name ~ "*.bin"'.
+    /// Supports size (K, M and G suffixes), mtime (Unix seconds), name with ~ / !~ for globs or
+    /// == / != for exact names, is_dir, the comparisons < <= > >= == != and && || ! with
+    /// parentheses. Matching directories are not descended into
+    #[arg(long, value_parser = Filter::parse)]
+    filter: Option<Filter>,
+    /// Leave a zero byte placeholder for every remote file skipped by --exclude, --exclude-ext or
+    /// --filter. empty creates the file itself at its local path (never replacing an existing
+    /// file) and sidecar creates <name>.skipped next to it
+    #[arg(long, value_enum)]
+    skipped_markers: Option<SkippedMarkers>,
+    /// Stop starting new transfers once free space on the local filesystem drops below this size
+    /// (K, M and G suffixes are accepted). In-flight transfers finish and the run reports that it
+    /// paused for space. Checked before every file
+    #[arg(long, value_parser = parse_byte_size)]
+    min_free_space: Option<u64>,
+    /// Walk the whole remote tree and size every queued file before transferring anything, so
+    /// totals and progress are exact from the first file. With --min-free-space the run fails
+    /// upfront when the queue would not fit
+    #[arg(long)]
+    two_pass: bool,
+    /// Compress each downloaded file, writing `<name>.gz` locally. Existing compressed copies are
+    /// compared by mtime, or by their decompressed contents with --compare checksum. Re-syncing
+    /// into the same directory requires the same --compress-local setting, otherwise every file
+    /// is downloaded again. Only gzip is supported. zstd is not available since the build has no
+    /// zstd implementation to link against
+    #[arg(
+        long,
+        value_name = "gzip",
+        value_parser = parse_compression,
+        conflicts_with = "dedup_local"
+    )]
+    compress_local: Option<Compression>,
+    /// Suffix of the file each download is written to before being renamed into place. Change
+    /// it when tools watching the local directory treat `.part` files specially
+    #[arg(long, default_value = DEFAULT_TEMP_SUFFIX, value_parser = validate_temp_suffix)]
+    temp_suffix: String,
+    /// Prefix transfer log lines with a run ID and a per-file ID, e.g. `[3fa9c2d1 002a]`, so
+    /// output from parallel transfers can be grouped by file
+    #[arg(long)]
+    correlation_ids: bool,
+    /// Abort with a "no progress" error when no transfer has received any bytes for this many
+    /// seconds. Catches connections that stay open but silently stop delivering data
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), conflicts_with = "interactive")]
+    stall_timeout: Option<u64>,
+    /// Write a sorted snapshot of the remote tree (paths, sizes, mtimes and permissions) seen
+    /// during the sync to this file. Unlike --local-index it is not read by later runs
+    #[arg(long)]
+    save_listing: Option<PathBuf>,
+    /// Format of the --save-listing file
+    #[arg(long, value_enum, default_value_t = ListingFormat::Tsv, requires = "save_listing")]
+    listing_format: ListingFormat,
+    /// Diagnostics only. Write a CSV line with the timestamp, byte count and read/write time of
+    /// every chunk of every download to this file. Expect it to be large: a 1 GiB file adds
+    /// tens of thousands of lines
+    #[arg(long)]
+    trace_transfers: Option<PathBuf>,
+    /// With --compare size, checksum this fraction (0.0 to 1.0) of the files whose sizes match
+    /// and download any that differ again, reporting them as silent corruption
+    #[arg(long, value_parser = parse_fraction)]
+    verify_sample_rate: Option<f64>,
+    /// Only delete local files and directories that no longer exist on the remote, without
+    /// transferring anything. Excluded files are kept. Combine with --dry-run to preview
+    #[arg(long, conflicts_with_all = ["verify_only", "list_remote", "probe"])]
+    prune_only: bool,
+    /// Number of SFTP read requests to keep in flight for each download (1-256). Higher values
+    /// hide round trip latency on slow links at the cost of memory. The default buffer keeps
+    /// about 17 requests in flight
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..=256))]
+    read_ahead: Option<u64>,
+    /// IP address or CIDR block (e.g. 10.0.0.0/8) the resolved server address must fall within.
+    /// Can be repeated. The connection is aborted when the host resolves to any other address
+    #[arg(long)]
+    allow_host: Vec<AllowedNetwork>,
+    /// Resolve remote directories with realpath during traversal and skip any directory already
+    /// visited through another path. Symlinked directories are followed, with cycles skipped
+    #[arg(long)]
+    canonicalize_remote: bool,
+    /// Reserve the full size of each local file before downloading it so a full disk fails the
+    /// transfer up front instead of partway through. Not applied with --compress-local
+    #[arg(long)]
+    preallocate: bool,
+    /// Only consider remote files modified at or after this time, given as seconds since the
+    /// Unix epoch. Overrides the time stored in --since-last-success
+    #[arg(long)]
+    newer_than: Option<u64>,
+    /// File recording when the last sync that finished without errors started. Later runs only
+    /// consider remote files modified since then. Runs with failures leave the file unchanged.
+    /// Remote mtimes are compared against the local clock so the clocks should be in sync
+    #[arg(long, conflicts_with = "resume_from")]
+    since_last_success: Option<PathBuf>,
+    /// After the run, write the local directory's files and directories with their sizes and
+    /// mtimes to this file as JSON. Entries matched by the exclude options are left out
+    #[arg(long)]
+    dump_local_tree: Option<PathBuf>,
+    /// Stat each remote file again before comparing it instead of using the size and mtime from
+    /// the directory listing. Needed with caching SFTP gateways that report stale sizes in
+    /// listings. Costs one extra round trip per file
+    #[arg(long)]
+    restat: bool,
+    /// With --only-dirs, skip remote directories that are empty or only contain excluded
+    /// entries. Without --only-dirs directories are already only created for downloaded files
+    #[arg(long)]
+    no_empty_dirs: bool,
+    /// Write the remote and local paths of files that failed to transfer to this file. The file
+    /// is removed after a run without failures
+    #[arg(long)]
+    failed_list: Option<PathBuf>,
+    /// Retry only the files listed in a --failed-list file instead of walking the remote tree.
+    /// Files that no longer exist on the remote are skipped and the list is rewritten with the
+    /// files that still fail
+    #[arg(long, conflicts_with = "resume_from")]
+    retry_failed: Option<PathBuf>,
+    /// Before replacing a local file, try to take an exclusive lock on it and skip the file when
+    /// another process holds a lock. On Unix locks are advisory so only readers that lock the
+    /// file are detected. Skipped files are written to --failed-list and are retried by the next
+    /// run with --since-last-success or --trust-dir-mtime
+    #[arg(long)]
+    skip_locked: bool,
+    /// What to do when the number of bytes read from a remote file differs from the size listed
+    /// before the transfer, e.g. for files that are still being written. Without this option
+    /// files that grew are accepted and files that shrank fail
+    #[arg(long, value_enum)]
+    on_size_change: Option<SizeChange>,
+    /// Order queued files are transferred in. dir-grouped finishes each remote directory before
+    /// starting the next, which helps servers that cache per directory but limits parallel
+    /// transfers to the files of one directory at a time
+    #[arg(long, value_enum, default_value_t = TransferOrder::Listing)]
+    order: TransferOrder,
+    /// Keep running and sync at the times given by a five field cron expression in local time,
+    /// e.g. "*/30 * * * *". A new connection is made for every run and SIGTERM exits between
+    /// runs
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "fd", "probe", "list_remote", "verify_only", "prune_only", "summary_json_line",
+            "interactive", "event_socket",
+        ]
+    )]
+    schedule: Option<Schedule>,
+    /// Recreate remote hard links locally. Paths sharing a remote inode are downloaded once and
+    /// the others are linked to that copy. The server must allow exec channels and provide GNU
+    /// find since SFTP does not report inode numbers. Otherwise every path is downloaded
+    #[arg(long)]
+    preserve_hardlinks: bool,
+    /// Report the files added, removed, resized or modified between two --save-listing
+    /// snapshots of the remote tree then exit. No connection is made
+    #[arg(long, num_args = 2, value_names = ["OLD", "NEW"])]
+    diff_listing: Option<Vec<PathBuf>>,
+    /// Refuse --prune-only when the resolved remote directory has fewer than this many path
+    /// components below `/`. The filesystem root is always refused
+    #[arg(long, default_value_t = 1, requires = "prune_only")]
+    min_remote_depth: usize,
+    /// Prune even when the remote directory is the filesystem root, shallower than
+    /// --min-remote-depth or empty
+    #[arg(long = "i-know-what-im-doing", requires = "prune_only")]
+    force_prune: bool,
+    /// Only download the first N bytes of each remote file, e.g. to inspect headers. Local files
+    /// are intentionally truncated so they are compared by mtime instead of size
+    #[arg(
+        long,
+        value_name = "N",
+        conflicts_with_all = ["tail_bytes", "verify_against", "verify_sample_rate"]
+    )]
+    head_bytes: Option<u64>,
+    /// Only download the last N bytes of each remote file. Compared like --head-bytes
+    #[arg(
+        long,
+        value_name = "N",
+        conflicts_with_all = ["verify_against", "verify_sample_rate"]
+    )]
+    tail_bytes: Option<u64>,
+    /// Read options from a JSON object on stdin, e.g. `{"ip": "10.0.0.5", "exclude": ["tmp"]}`.
+    /// Keys are long option names as printed by --print-config, with dashes or underscores.
+    /// Flags given on the command line take precedence and list options are combined
+    #[arg(long, conflicts_with = "interactive")]
+    config_stdin: bool,
 }
 
-struct SftpSync {
-    client: Sftp,
-    exclude: Vec<String>,
-    local_directory: PathBuf,
-    remote_directory: PathBuf,
-}
-
-impl SftpSync {
-    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(
-        client: Sftp,
-        exclude: Option<Vec<String>>,
-        local_directory: P,
-        remote_directory: Q,
-    ) -> Self {
-        let exclude = if let Some(mut e) = exclude {
-            e.sort();
-            e
-        } else {
-            Default::default()
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("Invalid duration '{value}'"))?;
+    let multiplier: u64 = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        _ => {
+            return Err(format!(
+                "Unknown duration unit '{unit}'. Expected s, m, h or d"
+            ))
+        }
+    };
+    amount
+        .checked_mul(multiplier)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("Duration '{value}' is too large"))
+}
+
+const SECRET_ARGS: [&str; 1] = ["password"];
+
+fn toml_value(value: &str) -> String {
+    // TOML only accepts nan and inf in lowercase so non-finite values are kept as strings
+    if value == "true" || value == "false" || value.parse::<f64>().is_ok_and(f64::is_finite) {
+        return value.to_string();
+    }
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn print_config(matches: &ArgMatches, args: &Args) {
+    let merged = [
+        ("ip", args.ip.clone()),
+        ("port", Some(args.port.unwrap_or(22).to_string())),
+        ("username", args.username.clone()),
+        (
+            "private_key",
+            args.private_key
+                .as_ref()
+                .map(|path| path.display().to_string()),
+        ),
+    ];
+    for arg in Args::command().get_arguments() {
+        let id = arg.get_id().as_str();
+        if id == "print_config" {
+            continue;
+        }
+        let values: Vec<String> = match merged.iter().find(|(key, _)| *key == id) {
+            Some((_, value)) => value.iter().cloned().collect(),
+            None => matches
+                .get_raw(id)
+                .map(|values| {
+                    values
+                        .map(|value| value.to_string_lossy().into_owned())
+                        .collect()
+                })
+                .unwrap_or_default(),
         };
-        Self {
-            client,
-            exclude,
-            local_directory: local_directory.as_ref().to_path_buf(),
-            remote_directory: remote_directory.as_ref().to_path_buf(),
+        if values.is_empty() {
+            continue;
+        }
+        // The long flag name, which differs from the id for e.g. --i-know-what-im-doing
+        let key = arg
+            .get_long()
+            .map_or_else(|| id.replace('_', "-"), str::to_string);
+        if SECRET_ARGS.contains(&id) {
+            println!("{key} = \"<redacted>\"");
+        } else if matches!(arg.get_action(), ArgAction::Append) {
+            let values: Vec<String> = values.iter().map(|v| toml_value(v)).collect();
+            println!("{key} = [{}]", values.join(", "));
+        } else {
+            println!("{key} = {}", toml_value(&values[0]));
         }
     }
+}
 
-    fn copy_file(
-        &self,
-        remote_path: &Path,
-        local_path: &Path,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        println!("Copying remote file {remote_path:?} to {local_path:?}");
-        let mut remote_file = self.client.open(remote_path)?;
-        let mut local_file = File::create(local_path)?;
-        let mut buffer = vec![0; BUFFER_SIZE];
-        loop {
-            let bytes_read = remote_file.read(&mut buffer)?;
-            if bytes_read == 0 {
-                break;
+/// Convert a JSON config object into command line arguments so it goes through the same parsing
+/// and validation as flags. Options taking several values per occurrence, like --diff-listing,
+/// take an array of values, or an array of such arrays when they can be repeated
+fn config_args(command: &Command, text: &str) -> Result<Vec<OsString>, String> {
+    let json::Value::Object(members) = json::parse(text)? else {
+        return Err("The config must be a JSON object".to_string());
+    };
+    let mut result = Vec::new();
+    for (key, value) in members {
+        let id = key.replace('-', "_");
+        let Some((arg, long)) = command
+            .get_arguments()
+            .filter(|arg| arg.get_id() != "config_stdin")
+            .find(|arg| arg.get_id() == id.as_str() || arg.get_long() == Some(key.as_str()))
+            .and_then(|arg| Some((arg, arg.get_long()?)))
+        else {
+            return Err(format!("Unknown config key '{key}'"));
+        };
+        let flag = OsString::from(format!("--{long}"));
+        let scalar = |value: &json::Value| match value {
+            json::Value::String(value) => Ok(value.clone()),
+            json::Value::Number(value) => Ok(value.to_string()),
+            json::Value::Bool(value) => Ok(value.to_string()),
+            _ => Err(format!(
+                "Config key '{key}' must be a string, number or boolean"
+            )),
+        };
+        let multiple_values = arg
+            .get_num_args()
+            .is_some_and(|range| range.max_values() > 1);
+        match (&value, arg.get_action()) {
+            (json::Value::Null, _) | (json::Value::Bool(false), ArgAction::SetTrue) => {}
+            (json::Value::Bool(true), ArgAction::SetTrue) => result.push(flag),
+            (json::Value::Array(items), _)
+                if multiple_values
+                    && items
+                        .iter()
+                        .all(|item| matches!(item, json::Value::Array(_))) =>
+            {
+                for item in items {
+                    let json::Value::Array(values) = item else {
+                        continue;
+                    };
+                    result.push(flag.clone());
+                    for value in values {
+                        result.push(scalar(value)?.into());
+                    }
+                }
+            }
+            (json::Value::Array(items), ArgAction::Append) if !multiple_values => {
+                for item in items {
+                    result.push(flag.clone());
+                    result.push(scalar(item)?.into());
+                }
+            }
+            (json::Value::Array(items), _) => {
+                result.push(flag);
+                for item in items {
+                    result.push(scalar(item)?.into());
+                }
+            }
+            (value, _) => {
+                result.push(flag);
+                result.push(scalar(value)?.into());
             }
-            local_file.write_all(&buffer[0..bytes_read])?;
         }
-        Ok(())
     }
+    Ok(result)
+}
 
-    fn find_paths<P: AsRef<Path>, Q: AsRef<Path>>(
-        &self,
-        local_directory: P,
-        remote_directory: Q,
-        result: &mut Vec<(PathBuf, PathBuf)>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let local_directory = local_directory.as_ref();
-        let remote_directory = remote_directory.as_ref();
-        std::fs::create_dir_all(local_directory)?;
-        for (path, stat) in self.client.readdir(remote_directory)? {
-            let Some(file_name) = path.file_name().and_then(|p| p.to_str()) else {
-                println!(
-                    "{CLEAR_LINE}\rCould not extract file name from remote path {path:?}. Skipping to next item."
-                );
-                continue;
-            };
+fn parse_priority(value: &str) -> Result<(String, i32), String> {
+    let Some((pattern, weight)) = value.rsplit_once(':') else {
+        return Err(format!("Expected <GLOB>:<WEIGHT> but found '{value}'"));
+    };
+    let weight = weight
+        .parse()
+        .map_err(|e| format!("Invalid weight '{weight}'. {e}"))?;
+    Ok((pattern.to_string(), weight))
+}
 
-            if self
-                .exclude
-                .binary_search_by(|e| e.as_str().cmp(file_name))
-                .is_ok()
-            {
-                println!("{CLEAR_LINE}\rSkipping excluded file/directory {file_name}");
-                continue;
-            }
+fn parse_fraction(value: &str) -> Result<f64, String> {
+    match value.trim().parse::<f64>() {
+        Ok(fraction) if (0.0..=1.0).contains(&fraction) => Ok(fraction),
+        _ => Err(format!("'{value}' is not a fraction between 0 and 1")),
+    }
+}
 
-            if stat.is_dir() {
-                let child_local_dir = local_directory.join(file_name);
-                self.find_paths(child_local_dir, path, result)?;
-                continue;
-            }
+fn parse_compression(value: &str) -> Result<Compression, String> {
+    if value.eq_ignore_ascii_case("zstd") {
+        return Err("zstd compression is not supported. Use gzip".to_string());
+    }
+    Compression::from_str(value, true)
+        .map_err(|_| format!("Unknown compression '{value}'. Only gzip is supported"))
+}
 
-            print!("{CLEAR_LINE}\rChecking {path:?} for a download or replace");
+fn parse_byte_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (amount, multiplier) = match value.char_indices().last() {
+        Some((index, 'k' | 'K')) => (&value[..index], 1024),
+        Some((index, 'm' | 'M')) => (&value[..index], 1024 * 1024),
+        Some((index, 'g' | 'G')) => (&value[..index], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("Invalid size '{value}'"))?;
+    amount
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("Size '{value}' is too large"))
+}
 
-            let Some(remote_size) = &stat.size else {
-                println!(
-                    "{CLEAR_LINE}\rCould not extract file size from the remote path {path:?}. Skipping to next item"
-                );
-                continue;
-            };
+fn parse_byte_rate(value: &str) -> Result<u64, String> {
+    let rate = parse_byte_size(value)?;
+    if rate == 0 {
+        return Err("Rate must be greater than 0".to_string());
+    }
+    Ok(rate)
+}
 
-            let local_path = local_directory.join(file_name);
-            if !local_path.exists() {
-                result.push((path, local_path));
-                continue;
-            }
+fn parse_rate(value: &str) -> Result<f64, String> {
+    let rate: f64 = value.parse().map_err(|e| format!("{e}"))?;
+    if !(0.0..=1.0).contains(&rate) {
+        return Err(format!("{rate} is not between 0.0 and 1.0"));
+    }
+    Ok(rate)
+}
 
-            let local_file = File::open(&local_path)?;
-            if local_file.metadata()?.len() != *remote_size {
-                result.push((path, local_path));
-            }
-        }
+impl Args {
+    fn apply_host_alias(&mut self) -> Result<(), String> {
+        let Some(alias) = &self.host_alias else {
+            return Ok(());
+        };
+        let hosts_file = match &self.hosts_file {
+            Some(path) => path.clone(),
+            None => hosts::default_hosts_file()
+                .ok_or("Could not determine the default hosts file location")?,
+        };
+        let host = hosts::load_host(&hosts_file, alias)?;
+        self.ip = self.ip.take().or(host.ip);
+        self.port = self.port.or(host.port);
+        self.username = self.username.take().or(host.username);
+        self.private_key = self.private_key.take().or(host.key);
         Ok(())
     }
+}
 
-    pub fn sync_local_directory(&self) -> Result<(), Box<dyn std::error::Error>> {
-        if !self.local_directory.exists() {
-            return Err(
-                format!("Local directory {:?} does not exist", self.local_directory).into(),
-            );
+impl From<&Args> for SyncOptions {
+    fn from(args: &Args) -> Self {
+        Self {
+            follow_root_symlink: args.follow_root_symlink,
+            completion_log: args.completion_log.clone(),
+            exclude_extensions: args
+                .exclude_ext
+                .iter()
+                .map(|e| e.trim_start_matches('.').to_lowercase())
+                .collect(),
+            sequential: args.sequential,
+            compare: args.compare,
+            checksum_cache: args.checksum_cache.clone(),
+            chaos_rate: args.chaos,
+            max_errors: args.max_errors,
+            dedup_local: args.dedup_local,
+            skip_empty: args.skip_empty,
+            priorities: args.priority.clone(),
+            only_dirs: args.only_dirs,
+            state_file: args.state_file.clone(),
+            checkpoint_interval: args.checkpoint_interval,
+            resume_from: args.resume_from.clone(),
+            sync_permissions: args.sync_permissions,
+            max_runtime: args.max_runtime,
+            ramp_up: args.ramp_up,
+            ignore_case: args.ignore_case,
+            preserve_xattrs: args.preserve_xattrs,
+            dry_run: args.dry_run,
+            summary_only: args.summary_only,
+            special_files: args.special_files,
+            local_manifest: args.local_index.clone(),
+            max_rate: args.max_rate,
+            no_recurse: args.no_recurse,
+            show_slowest: args.show_slowest,
+            trust_dir_mtime: args.trust_dir_mtime.clone(),
+            verify_against: args.verify_against.clone(),
+            redownload_on_mismatch: args.redownload_on_mismatch,
+            quiet_skips: args.quiet_skips,
+            verbose: args.verbose,
+            latest: args.latest.clone(),
+            latest_by: args.latest_by,
+            remote_sep: args.remote_sep,
+            max_path_length: args.max_path_length,
+            on_long_path: args.on_long_path,
+            changes_from: args.changes_from.clone(),
+            per_dir_jobs: args.per_dir_jobs,
+            interactive: args.interactive,
+            diff_command: args.diff_command.clone(),
+            heartbeat: args.heartbeat_secs.map(Duration::from_secs),
+            file_timeout: args.file_timeout_secs.map(Duration::from_secs),
+            filter: args.filter.clone(),
+            skipped_markers: args.skipped_markers,
+            min_free_space: args.min_free_space,
+            two_pass: args.two_pass,
+            compress_local: args.compress_local,
+            temp_suffix: args.temp_suffix.clone(),
+            correlation_ids: args.correlation_ids,
+            stall_timeout: args.stall_timeout.map(Duration::from_secs),
+            save_listing: args.save_listing.clone(),
+            listing_format: args.listing_format,
+            trace_transfers: args.trace_transfers.clone(),
+            additional_remote_directories: args.remote_directory[1..].to_vec(),
+            on_collision: args.on_collision,
+            verify_sample_rate: args.verify_sample_rate,
+            read_ahead: args.read_ahead.map(|depth| depth as usize),
+            canonicalize_remote: args.canonicalize_remote,
+            preallocate: args.preallocate,
+            newer_than: args.newer_than,
+            since_last_success: args.since_last_success.clone(),
+            restat: args.restat,
+            no_empty_dirs: args.no_empty_dirs,
+            failed_list: args.failed_list.clone(),
+            retry_failed: args.retry_failed.clone(),
+            skip_locked: args.skip_locked,
+            on_size_change: args.on_size_change,
+            order: args.order,
+            preserve_hardlinks: args.preserve_hardlinks,
+            min_remote_depth: args.min_remote_depth,
+            force_prune: args.force_prune,
+            head_bytes: args.head_bytes,
+            tail_bytes: args.tail_bytes,
         }
-        let mut paths = Vec::new();
-        println!("Finding paths that need to files that needs to be added or replaced.");
-        self.find_paths(&self.local_directory, &self.remote_directory, &mut paths)?;
-        print!("{CLEAR_LINE}\r");
+    }
+}
 
-        println!("Need to update {} files", paths.len());
-        paths.into_par_iter().for_each(|(remote_path, local_path)| {
-            if let Err(error) = self.copy_file(&remote_path, &local_path) {
-                println!("Error copying file {remote_path:?} -> {local_path:?}. {error}");
-            }
-        });
-        Ok(())
+/// Point stdout at stderr so human readable output does not mix with the summary line or the
+/// streamed file, returning a handle to the original stdout
+#[cfg(unix)]
+fn redirect_stdout() -> std::io::Result<std::fs::File> {
+    use std::os::fd::FromRawFd;
+    unsafe {
+        let original = libc::dup(libc::STDOUT_FILENO);
+        if original < 0 || libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(std::fs::File::from_raw_fd(original))
     }
 }
 
-fn create_sftp_connection(
-    ip: &str,
-    port: u16,
-    username: &str,
-    password: &str,
-) -> Result<Sftp, Box<dyn std::error::Error>> {
-    let tcp = TcpStream::connect((ip, port))?;
-    let mut ssh_session = Session::new()?;
-    ssh_session.set_tcp_stream(tcp);
-    ssh_session.handshake()?;
-    ssh_session.userauth_password(username, password)?;
+fn print_summary_line(
+    summary_stdout: Option<std::fs::File>,
+    stats: SyncStats,
+    duration: Duration,
+    exit_code: i32,
+) {
+    use std::io::Write;
+    let mut line = format!(
+        "{{\"files_transferred\":{},\"bytes\":{},\"errors\":{},\"empty_directories\":{},\"duration_ms\":{},\"exit_code\":{exit_code}",
+        stats.transferred,
+        stats.bytes,
+        stats.failed,
+        stats.empty_directories,
+        duration.as_millis(),
+    );
+    if !stats.slowest.is_empty() {
+        let slowest: Vec<String> = stats
+            .slowest
+            .iter()
+            .map(|timing| timing.to_json())
+            .collect();
+        line.push_str(&format!(",\"slowest\":[{}]", slowest.join(",")));
+    }
+    line.push('}');
+    print!("\x1B[?25h");
+    let _ = std::io::stdout().flush();
+    let result = match summary_stdout {
+        Some(mut file) => writeln!(file, "{line}"),
+        None => writeln!(std::io::stdout(), "{line}"),
+    };
+    if let Err(error) = result {
+        eprintln!("Error writing summary line. {error}");
+    }
+}
 
-    let sftp = ssh_session.sftp()?;
-    Ok(sftp)
+fn probe_failed(format: OutputFormat, error: &str) -> ! {
+    match format {
+        OutputFormat::Json => println!(
+            "{{\"ok\":false,\"error\":{}}}",
+            sftp_sync::json::string(error)
+        ),
+        OutputFormat::Text => println!("failed\t{error}"),
+    }
+    exit(1)
+}
+
+/// Set once a sync or schedule that checks the cancellation token is running. Before that, e.g.
+/// while prompting for the password or connecting, nothing would notice a cancelled token
+static CANCEL_GRACEFULLY: AtomicBool = AtomicBool::new(false);
+
+/// The first signal cancels the sync so in-flight transfers can finish and the main thread
+/// reports the cancellation once the sync returns. A second signal, or any signal before a sync
+/// is running, exits immediately.
+///
+/// The handler never touches stdout through `println!`. Another thread is almost always in the
+/// middle of printing progress and holding the stdout lock, so the handler only flips the token
+/// and, when exiting, restores the cursor with a raw write.
+fn terminate(token: &CancellationToken) {
+    if !token.is_cancelled() && CANCEL_GRACEFULLY.load(Ordering::SeqCst) {
+        token.cancel();
+        return;
+    }
+    #[cfg(unix)]
+    unsafe {
+        const RESTORE: &[u8] = b"\n\x1B[?25h";
+        libc::write(libc::STDOUT_FILENO, RESTORE.as_ptr().cast(), RESTORE.len());
+        restore_terminal_echo();
+        libc::_exit(130)
+    }
+    #[cfg(not(unix))]
+    exit(130)
 }
 
-fn terminate() {
-    println!("\nHandling SIGTERM");
-    show_cursor();
+/// Turn echo back on in case the signal arrived during the password prompt, which disables it
+/// until the prompt returns
+#[cfg(unix)]
+fn restore_terminal_echo() {
+    use std::os::fd::AsRawFd;
+
+    let Ok(tty) = std::fs::File::open("/dev/tty") else {
+        return;
+    };
+    unsafe {
+        let mut attributes = std::mem::zeroed::<libc::termios>();
+        if libc::tcgetattr(tty.as_raw_fd(), &mut attributes) == 0 {
+            attributes.c_lflag |= libc::ECHO;
+            libc::tcsetattr(tty.as_raw_fd(), libc::TCSANOW, &attributes);
+        }
+    }
+}
+
+fn print_listing_diff(old_path: &Path, new_path: &Path) -> Result<(), String> {
+    let old = read_listing(old_path)?;
+    let new = read_listing(new_path)?;
+    let diff = diff_listings(&old, &new);
+    let size = |entry: &ListingEntry| entry.size.map_or("-".to_string(), |size| size.to_string());
+    let mtime = |entry: &ListingEntry| {
+        entry
+            .mtime
+            .map_or("-".to_string(), |mtime| mtime.to_string())
+    };
+    for entry in &diff.added {
+        println!("added\t{}\t{} bytes", entry.path.display(), size(entry));
+    }
+    for entry in &diff.removed {
+        println!("removed\t{}", entry.path.display());
+    }
+    for (previous, entry) in &diff.resized {
+        println!(
+            "resized\t{}\t{} -> {} bytes",
+            entry.path.display(),
+            size(previous),
+            size(entry)
+        );
+    }
+    for (previous, entry) in &diff.modified {
+        println!(
+            "modified\t{}\tmtime {} -> {}",
+            entry.path.display(),
+            mtime(previous),
+            mtime(entry)
+        );
+    }
+    println!(
+        "{} added, {} removed, {} resized, {} modified",
+        diff.added.len(),
+        diff.removed.len(),
+        diff.resized.len(),
+        diff.modified.len()
+    );
+    Ok(())
+}
+
+fn connection_builder(args: &Args, username: &str, password: Option<&str>) -> ConnectionBuilder {
+    let builder = ConnectionBuilder::new(args.ip.as_deref().unwrap_or_default(), username)
+        .port(args.port.unwrap_or(22))
+        .algorithms(AlgorithmPreferences {
+            cipher: args.cipher.clone(),
+            kex: args.kex.clone(),
+            mac: args.mac.clone(),
+        })
+        .host_key_policy(HostKeyPolicy {
+            fingerprint: args.host_fingerprint.clone(),
+            allowed_algorithms: args.require_host_key_algo.clone(),
+            min_rsa_bits: args.min_rsa_bits,
+        })
+        .allowed_networks(args.allow_host.clone())
+        // Without a stdout redirect connection details would end up in the streamed file
+        .verbose(
+            args.verbose && !args.probe && (cfg!(unix) || args.local_directory.as_os_str() != "-"),
+        );
+    match (&args.private_key, password) {
+        (Some(private_key), passphrase) => builder.private_key(private_key, passphrase),
+        (None, Some(password)) => builder.password(password),
+        (None, None) => builder,
+    }
+}
+
+/// Print the outcome of a sync and write the post-run outputs (--dump-local-tree and
+/// --metrics-file)
+fn report_sync_result(
+    args: &Args,
+    sync: &SftpSync,
+    result: &Result<(), Box<dyn std::error::Error>>,
+    run_started: Instant,
+) {
+    if let Some(cancelled) = result
+        .as_ref()
+        .err()
+        .and_then(|error| error.downcast_ref::<Cancelled>())
+    {
+        println!("\nHandling SIGTERM. {cancelled}");
+    } else if let Err(error) = result {
+        println!(
+            "Error syncing local directory {:?} with remote directory {:?}. {error}\n",
+            args.local_directory, args.remote_directory
+        );
+    }
+    if let Some(path) = &args.dump_local_tree {
+        match sync.dump_local_tree(path) {
+            Ok(count) => println!("Wrote {count} local entries to {path:?}"),
+            Err(error) => println!("Error writing local tree to {path:?}. {error}"),
+        }
+    }
+    if let Some(path) = &args.metrics_file {
+        let success = result.is_ok() && sync.stats().failed == 0;
+        if let Err(error) =
+            metrics::write_metrics(path, &sync.stats(), run_started.elapsed(), success)
+        {
+            println!("Error writing metrics file {path:?}. {error}");
+        }
+    }
+}
+
+/// Run a sync at every time matched by --schedule until the process is asked to stop
+fn run_scheduled(
+    args: &Args,
+    schedule: &Schedule,
+    username: &str,
+    password: Option<&str>,
+    cancellation: &CancellationToken,
+) -> i32 {
+    loop {
+        let Some(next_run) = schedule.next_after(SystemTime::now()) else {
+            println!("The schedule has no upcoming run times");
+            return 1;
+        };
+        let next_run_seconds = next_run
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        println!(
+            "Next sync scheduled for {}",
+            LocalTime::from_unix(next_run_seconds)
+        );
+        while let Ok(remaining) = next_run.duration_since(SystemTime::now()) {
+            if cancellation.is_cancelled() {
+                println!("\nHandling SIGTERM. Exiting between scheduled runs");
+                return 0;
+            }
+            std::thread::sleep(remaining.min(Duration::from_secs(1)));
+        }
+        let run_started = Instant::now();
+        match run_scheduled_sync(args, username, password, cancellation, run_started) {
+            Ok(stats) => println!(
+                "Scheduled sync finished in {:.1?}. {} files transferred ({} bytes), {} failed",
+                run_started.elapsed(),
+                stats.transferred,
+                stats.bytes,
+                stats.failed
+            ),
+            Err(error) => println!("Scheduled sync could not start. {error}"),
+        }
+        if cancellation.is_cancelled() {
+            return 0;
+        }
+    }
+}
+
+fn run_scheduled_sync(
+    args: &Args,
+    username: &str,
+    password: Option<&str>,
+    cancellation: &CancellationToken,
+    run_started: Instant,
+) -> Result<SyncStats, Box<dyn std::error::Error>> {
+    let session = connection_builder(args, username, password).connect_session()?;
+    let sftp = session
+        .sftp()
+        .map_err(|error| RemoteError::new("starting the SFTP subsystem", None, error))?;
+    let sync = SftpSync::new(
+        sftp,
+        args.exclude.clone(),
+        &args.local_directory,
+        &args.remote_directory[0],
+        SyncOptions::from(args),
+    )?
+    .with_session(session)
+    .with_cancellation_token(cancellation.clone());
+    let result = sync.sync_local_directory();
+    report_sync_result(args, &sync, &result, run_started);
+    Ok(sync.stats())
 }
 
 fn main() {
-    if let Err(error) = ctrlc::set_handler(terminate) {
+    let cancellation = CancellationToken::new();
+    let handler_token = cancellation.clone();
+    if let Err(error) = ctrlc::set_handler(move || terminate(&handler_token)) {
         println!("Failed to set handler for SIGTERM. {error}");
         return;
     }
-    hide_cursor();
-    let args = Args::parse();
-    let password = match args.password {
-        Some(inner) => inner,
-        None => {
-            match rpassword::prompt_password(format!("SFTP Password for {}: ", args.username)) {
-                Ok(inner) => inner,
-                Err(error) => {
-                    println!("Error getting password from user. {error}");
-                    show_cursor()
-                }
+    let mut command = Args::command();
+    let mut arguments: Vec<OsString> = std::env::args_os().collect();
+    if arguments
+        .iter()
+        .any(|argument| argument == "--config-stdin")
+    {
+        let mut text = String::new();
+        if let Err(error) = std::io::stdin().read_to_string(&mut text) {
+            println!("Error reading the config from stdin. {error}");
+            exit(1)
+        }
+        match config_args(&command, &text) {
+            // Config values come first so flags on the command line override them
+            Ok(config) => drop(arguments.splice(1..1, config)),
+            Err(error) => {
+                println!("Invalid config on stdin. {error}");
+                exit(1)
+            }
+        }
+        command = command.args_override_self(true);
+    }
+    let matches = command.get_matches_from(arguments);
+    let mut args = match Args::from_arg_matches(&matches) {
+        Ok(inner) => inner,
+        Err(error) => error.exit(),
+    };
+    let to_stdout = args.local_directory.as_os_str() == "-";
+    if to_stdout && args.remote_directory.len() > 1 {
+        println!("Only one --remote-directory can be streamed to stdout");
+        exit(1)
+    }
+    let run_started = Instant::now();
+    let mut summary_stdout = None;
+    if args.summary_json_line {
+        if to_stdout {
+            println!("--summary-json-line cannot be used when streaming a file to stdout");
+            exit(1)
+        }
+        #[cfg(unix)]
+        match redirect_stdout() {
+            Ok(file) => summary_stdout = Some(file),
+            Err(error) => {
+                println!("Error redirecting stdout for --summary-json-line. {error}");
+                exit(1)
+            }
+        }
+    }
+    if !to_stdout && !args.probe {
+        hide_cursor();
+    }
+    if let Err(error) = args.apply_host_alias() {
+        println!("Error resolving host alias. {error}");
+        show_cursor(1)
+    }
+    if args.print_config {
+        print_config(&matches, &args);
+        show_cursor(0)
+    }
+    if let Some(output) = &args.build_local_index {
+        let include_hash = matches!(args.compare, CompareMode::Checksum | CompareMode::Both);
+        match build_manifest(&args.local_directory, output, include_hash) {
+            Ok(count) => {
+                println!("Wrote {count} local files to {output:?}");
+                show_cursor(0)
+            }
+            Err(error) => {
+                println!(
+                    "Error building local index of {:?}. {error}",
+                    args.local_directory
+                );
+                show_cursor(1)
+            }
+        }
+    }
+    if let Some(listings) = &args.diff_listing {
+        if let Err(error) = print_listing_diff(&listings[0], &listings[1]) {
+            println!("Error comparing listings. {error}");
+            show_cursor(1)
+        }
+        show_cursor(0)
+    }
+    // Everything printed from here on is a diagnostic, so only the file contents reach stdout
+    let mut stream_stdout = None;
+    #[cfg(unix)]
+    if to_stdout {
+        match redirect_stdout() {
+            Ok(file) => stream_stdout = Some(file),
+            Err(error) => {
+                eprintln!("Error redirecting stdout for streaming. {error}");
+                exit(1)
             }
         }
+    }
+    let Some(username) = args.username.clone() else {
+        println!("A username must be provided either as a flag or through --host-alias");
+        show_cursor(1)
+    };
+    if args.ip.is_none() && args.fd.is_none() {
+        println!("An ip must be provided either as a flag or through --host-alias");
+        show_cursor(1)
+    }
+    let password = match (args.password.clone(), &args.private_key) {
+        (Some(inner), _) => Some(inner),
+        (None, Some(_)) => None,
+        (None, None) => match rpassword::prompt_password(format!("SFTP Password for {username}: "))
+        {
+            Ok(inner) => Some(inner),
+            Err(error) => {
+                println!("Error getting password from user. {error}");
+                show_cursor(1)
+            }
+        },
+    };
+    if let Some(schedule) = &args.schedule {
+        if to_stdout {
+            println!("--schedule cannot be used when streaming a file to stdout");
+            show_cursor(1)
+        }
+        CANCEL_GRACEFULLY.store(true, Ordering::SeqCst);
+        let code = run_scheduled(
+            &args,
+            schedule,
+            &username,
+            password.as_deref(),
+            &cancellation,
+        );
+        show_cursor(code)
+    }
+    let builder = connection_builder(&args, &username, password.as_deref());
+    let session = match args.fd {
+        #[cfg(unix)]
+        Some(fd) => {
+            sftp_sync::stream_from_fd(fd).and_then(|tcp| builder.stream(tcp).connect_session())
+        }
+        #[cfg(not(unix))]
+        Some(_) => Err("--fd is only supported on Unix".into()),
+        None => builder.connect_session(),
     };
-    let sftp = match create_sftp_connection(&args.ip, args.port, &args.username, &password) {
+    let session = session.and_then(|session| {
+        let sftp = session
+            .sftp()
+            .map_err(|error| RemoteError::new("starting the SFTP subsystem", None, error))?;
+        Ok((sftp, session))
+    });
+    let session = match session {
         Ok(inner) => inner,
+        Err(error) if args.probe => probe_failed(args.format, &error.to_string()),
         Err(error) => {
             println!("Error attempting to create an SFTP connection. {error}");
-            show_cursor()
+            show_cursor(1)
         }
     };
-    let sync = SftpSync::new(
+    let (sftp, session) = session;
+    let options = SyncOptions::from(&args);
+    let mut sync = match SftpSync::new(
         sftp,
-        args.exclude,
+        args.exclude.clone(),
         &args.local_directory,
-        &args.remote_directory,
-    );
-    if let Err(error) = sync.sync_local_directory() {
-        println!(
-            "Error syncing local directory {:?} with remote directory {:?}. {error}\n",
-            args.local_directory, args.remote_directory
+        &args.remote_directory[0],
+        options,
+    ) {
+        Ok(inner) => inner,
+        Err(error) if args.probe => probe_failed(args.format, &error.to_string()),
+        Err(error) => {
+            println!("Error initializing the sync. {error}");
+            show_cursor(1)
+        }
+    };
+    sync = sync
+        .with_session(session)
+        .with_cancellation_token(cancellation);
+    CANCEL_GRACEFULLY.store(true, Ordering::SeqCst);
+    if let Some(path) = &args.event_socket {
+        #[cfg(unix)]
+        match event_socket::EventSocket::open(path) {
+            Ok(socket) => {
+                sync = sync.with_event_handler(Box::new(move |event| socket.send(event)));
+            }
+            Err(error) => {
+                println!("Error opening event socket {path:?}. {error}");
+                show_cursor(1)
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            println!("--event-socket {path:?} is only supported on Unix");
+            show_cursor(1)
+        }
+    }
+    if to_stdout {
+        let remote_path = &args.remote_directory[0];
+        let result = match &mut stream_stdout {
+            Some(file) => sync.stream_remote_file(remote_path, file),
+            None => sync.stream_remote_file(remote_path, &mut std::io::stdout().lock()),
+        };
+        match result {
+            Ok(_) => {}
+            Err(error) if error.is::<Cancelled>() => {
+                eprintln!("\nHandling SIGTERM. Stopped streaming to stdout");
+                exit(130)
+            }
+            Err(error) => {
+                eprintln!(
+                    "Error streaming remote file {:?} to stdout. {error}",
+                    args.remote_directory[0]
+                );
+                exit(1)
+            }
+        }
+        exit(0)
+    }
+    if args.probe {
+        match sync.probe() {
+            Ok(report) if args.format == OutputFormat::Json => println!("{}", report.to_json()),
+            Ok(report) => {
+                println!("ok");
+                println!("banner\t{}", report.banner.as_deref().unwrap_or("-"));
+                for (name, algorithm) in &report.algorithms {
+                    println!("{name}\t{algorithm}");
+                }
+                println!("entries\t{}", report.entries);
+            }
+            Err(error) => probe_failed(args.format, &error.to_string()),
+        }
+        exit(0)
+    }
+    if args.list_remote {
+        let result = sync.walk(|entry| {
+            let kind = if entry.stat.is_dir() { "d" } else { "f" };
+            println!(
+                "{kind}\t{}\t{}",
+                entry.stat.size.unwrap_or_default(),
+                entry.path.display()
+            );
+            Ok(())
+        });
+        if let Err(error) = &result {
+            println!(
+                "Error listing remote directory {:?}. {error}",
+                args.remote_directory
+            );
+        }
+        show_cursor(exit_code(&result, &sync.stats()))
+    }
+    if args.verify_only {
+        let result = sync.verify_local_directory(args.repair);
+        if let Err(error) = &result {
+            println!(
+                "Error verifying local directory {:?} against remote directory {:?}. {error}\n",
+                args.local_directory, args.remote_directory
+            );
+        }
+        show_cursor(exit_code(&result, &sync.stats()))
+    }
+    if args.prune_only {
+        let result = sync.prune_local_directory();
+        if let Err(error) = &result {
+            println!(
+                "Error pruning local directory {:?} against remote directory {:?}. {error}\n",
+                args.local_directory, args.remote_directory
+            );
+        }
+        show_cursor(exit_code(&result, &sync.stats()))
+    }
+    let result = sync.sync_local_directory();
+    report_sync_result(&args, &sync, &result, run_started);
+    let stats = sync.stats();
+    let code = exit_code(&result, &stats);
+    if args.summary_json_line {
+        print_summary_line(summary_stdout, stats, run_started.elapsed(), code);
+        exit(code)
+    }
+    show_cursor(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(text: &str) -> Result<Vec<String>, String> {
+        config_args(&Args::command(), text).map(|arguments| {
+            arguments
+                .into_iter()
+                .map(|argument| argument.to_string_lossy().into_owned())
+                .collect()
+        })
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(86_400)));
+        assert_eq!(
+            parse_duration("3w"),
+            Err("Unknown duration unit 'w'. Expected s, m, h or d".to_string())
+        );
+        assert_eq!(
+            parse_duration("99999999999999999999d"),
+            Err("Invalid duration '99999999999999999999d'".to_string())
+        );
+        assert_eq!(
+            parse_duration("999999999999999999d"),
+            Err("Duration '999999999999999999d' is too large".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_compression() {
+        assert_eq!(parse_compression("gzip"), Ok(Compression::Gzip));
+        assert_eq!(
+            parse_compression("zstd"),
+            Err("zstd compression is not supported. Use gzip".to_string())
+        );
+        assert_eq!(
+            parse_compression("lz4"),
+            Err("Unknown compression 'lz4'. Only gzip is supported".to_string())
+        );
+    }
+
+    #[test]
+    fn parses_byte_sizes() {
+        assert_eq!(parse_byte_size("512"), Ok(512));
+        assert_eq!(parse_byte_size("4k"), Ok(4096));
+        assert_eq!(parse_byte_size("2G"), Ok(2 * 1024 * 1024 * 1024));
+        assert_eq!(
+            parse_byte_size("99999999999999999999G"),
+            Err("Invalid size '99999999999999999999G'".to_string())
+        );
+        assert_eq!(
+            parse_byte_size("17179869184G"),
+            Err("Size '17179869184G' is too large".to_string())
+        );
+        assert_eq!(
+            parse_byte_rate("0"),
+            Err("Rate must be greater than 0".to_string())
+        );
+    }
+
+    #[test]
+    fn toml_values_quote_strings_and_non_finite_numbers() {
+        assert_eq!(toml_value("true"), "true");
+        assert_eq!(toml_value("2222"), "2222");
+        assert_eq!(toml_value("0.25"), "0.25");
+        assert_eq!(toml_value("nan"), "\"nan\"");
+        assert_eq!(toml_value("inf"), "\"inf\"");
+        assert_eq!(toml_value("-infinity"), "\"-infinity\"");
+        assert_eq!(toml_value(r#"a"b\c"#), r#""a\"b\\c""#);
+    }
+
+    #[test]
+    fn config_keys_become_flags() {
+        assert_eq!(
+            config(r#"{"ip": "10.0.0.5", "port": 2222, "dry-run": true, "sequential": false}"#)
+                .unwrap(),
+            ["--ip", "10.0.0.5", "--port", "2222", "--dry-run"]
+        );
+    }
+
+    #[test]
+    fn config_arrays_repeat_single_value_flags() {
+        assert_eq!(
+            config(r#"{"exclude": ["a", "b"]}"#).unwrap(),
+            ["--exclude", "a", "--exclude", "b"]
+        );
+    }
+
+    #[test]
+    fn config_arrays_fill_multi_value_flags() {
+        assert_eq!(
+            config(r#"{"diff_listing": ["old.tsv", "new.tsv"]}"#).unwrap(),
+            ["--diff-listing", "old.tsv", "new.tsv"]
+        );
+        let arguments =
+            config(r#"{"diff_listing": ["old.tsv", "new.tsv"], "local_directory": "/tmp"}"#)
+                .unwrap();
+        let args = Args::try_parse_from(
+            std::iter::once("sftp-sync".to_string())
+                .chain(arguments)
+                .chain(["--remote-directory".to_string(), "/r".to_string()]),
+        )
+        .unwrap();
+        assert_eq!(
+            args.diff_listing,
+            Some(vec![PathBuf::from("old.tsv"), PathBuf::from("new.tsv")])
+        );
+    }
+
+    #[test]
+    fn library_defaults_match_the_command_line() {
+        let args = Args::try_parse_from([
+            "sftp-sync",
+            "--local-directory",
+            "/l",
+            "--remote-directory",
+            "/r",
+        ])
+        .unwrap();
+        assert_eq!(
+            format!("{:?}", SyncOptions::from(&args)),
+            format!("{:?}", SyncOptions::default())
+        );
+    }
+
+    #[test]
+    fn config_uses_the_long_flag_name() {
+        assert_eq!(
+            config(r#"{"force_prune": true}"#).unwrap(),
+            ["--i-know-what-im-doing"]
+        );
+        assert_eq!(
+            config(r#"{"i-know-what-im-doing": true}"#).unwrap(),
+            ["--i-know-what-im-doing"]
+        );
+    }
+
+    #[test]
+    fn config_rejects_unknown_keys_and_values() {
+        assert_eq!(
+            config(r#"{"bogus": 1}"#).unwrap_err(),
+            "Unknown config key 'bogus'"
+        );
+        assert_eq!(
+            config(r#"{"config-stdin": true}"#).unwrap_err(),
+            "Unknown config key 'config-stdin'"
+        );
+        assert_eq!(
+            config(r#"{"ip": {"a": 1}}"#).unwrap_err(),
+            "Config key 'ip' must be a string, number or boolean"
+        );
+        assert_eq!(
+            config("[1]").unwrap_err(),
+            "The config must be a JSON object"
+        );
+        assert_eq!(
+            config("{\n\"ip\" \"x\"}").unwrap_err(),
+            "Expected ':' at line 2 column 6 but found '\"'"
         );
-        show_cursor()
     }
-    show_cursor()
 }
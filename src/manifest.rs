@@ -0,0 +1,227 @@
+use crate::checksum::hash_reader;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// Local file details recorded in a manifest. The mtime and hash columns are optional and are
+/// written as `-` when missing.
+#[derive(Debug, Clone)]
+pub struct ManifestEntry {
+    pub size: u64,
+    pub mtime: Option<u64>,
+    pub hash: Option<String>,
+}
+
+/// Prebuilt listing of the local directory keyed by the path relative to the directory root.
+/// Lines are tab separated as `size\tmtime\thash\tpath`.
+#[derive(Debug, Default)]
+pub struct LocalManifest {
+    entries: HashMap<PathBuf, ManifestEntry>,
+}
+
+impl LocalManifest {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .map_err(|error| format!("Could not read local index {path:?}. {error}"))?;
+        let mut entries = HashMap::new();
+        for (index, line) in contents.lines().enumerate() {
+            let mut parts = line.splitn(4, '\t');
+            let (Some(size), Some(mtime), Some(hash), Some(file_path)) =
+                (parts.next(), parts.next(), parts.next(), parts.next())
+            else {
+                return Err(format!(
+                    "Local index {path:?} line {} is malformed",
+                    index + 1
+                ));
+            };
+            let size = size.parse().map_err(|_| {
+                format!(
+                    "Local index {path:?} line {} has an invalid size",
+                    index + 1
+                )
+            })?;
+            let mtime = match mtime {
+                "-" => None,
+                mtime => Some(mtime.parse().map_err(|_| {
+                    format!(
+                        "Local index {path:?} line {} has an invalid mtime",
+                        index + 1
+                    )
+                })?),
+            };
+            let hash = (hash != "-").then(|| hash.to_string());
+            entries.insert(
+                PathBuf::from(file_path),
+                ManifestEntry { size, mtime, hash },
+            );
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn get(&self, relative_path: &Path) -> Option<&ManifestEntry> {
+        self.entries.get(relative_path)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Walk `local_directory` and write a manifest of every file to `output`, returning the number of
+/// files written. Hashing reads every file so it is only done when `include_hash` is set.
+pub fn build_manifest(
+    local_directory: &Path,
+    output: &Path,
+    include_hash: bool,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut file = BufWriter::new(File::create(output)?);
+    let mut directories = vec![PathBuf::new()];
+    let mut count = 0;
+    while let Some(relative_directory) = directories.pop() {
+        for entry in std::fs::read_dir(local_directory.join(&relative_directory))? {
+            let entry = entry?;
+            let relative_path = relative_directory.join(entry.file_name());
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                directories.push(relative_path);
+                continue;
+            }
+            if !metadata.is_file() {
+                continue;
+            }
+            let mtime = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map_or_else(|| "-".to_string(), |d| d.as_secs().to_string());
+            let hash = if include_hash {
+                hash_reader(File::open(entry.path())?)?
+            } else {
+                "-".to_string()
+            };
+            writeln!(
+                file,
+                "{}\t{mtime}\t{hash}\t{}",
+                metadata.len(),
+                relative_path.display()
+            )?;
+            count += 1;
+        }
+    }
+    file.flush()?;
+    Ok(count)
+}
+
+/// Remote directory mtimes of directories whose files were all in sync after the last run, keyed
+/// by the path relative to the remote root. Lines are tab separated as `mtime\tpath`.
+#[derive(Debug, Default)]
+pub struct DirectoryMtimes {
+    entries: HashMap<PathBuf, u64>,
+}
+
+impl DirectoryMtimes {
+    /// Load the cache from `path`. A missing file is treated as an empty cache.
+    pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self::default())
+            }
+            Err(error) => return Err(error),
+        };
+        let entries = contents
+            .lines()
+            .filter_map(|line| {
+                let (mtime, path) = line.split_once('\t')?;
+                Some((PathBuf::from(path), mtime.parse().ok()?))
+            })
+            .collect();
+        Ok(Self { entries })
+    }
+
+    pub fn get(&self, relative_path: &Path) -> Option<u64> {
+        self.entries.get(relative_path).copied()
+    }
+
+    pub fn insert(&mut self, relative_path: PathBuf, mtime: u64) {
+        self.entries.insert(relative_path, mtime);
+    }
+
+    pub fn remove(&mut self, relative_path: &Path) {
+        self.entries.remove(relative_path);
+    }
+
+    pub fn extend(&mut self, other: DirectoryMtimes) {
+        self.entries.extend(other.entries);
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let mut paths: Vec<_> = self.entries.keys().collect();
+        paths.sort();
+        let mut file = BufWriter::new(File::create(path)?);
+        for path in paths {
+            writeln!(file, "{}\t{}", self.entries[path], path.display())?;
+        }
+        file.flush()
+    }
+}
+
+/// Watermark written by --since-last-success after a run that finished without errors. Lines are
+/// `key=value` with the run start as seconds since the Unix epoch and a summary of the run.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LastSuccess {
+    pub timestamp: u64,
+    pub files: usize,
+    pub bytes: u64,
+}
+
+impl LastSuccess {
+    /// Load the watermark from `path`. A missing file means no run has succeeded yet.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Option<Self>, String> {
+        let path = path.as_ref();
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(format!("Could not read {path:?}. {error}")),
+        };
+        let mut timestamp = None;
+        let mut last_success = Self::default();
+        for line in contents.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let invalid = || format!("{path:?} has an invalid {key} value '{value}'");
+            match key.trim() {
+                "timestamp" => timestamp = Some(value.trim().parse().map_err(|_| invalid())?),
+                "files" => last_success.files = value.trim().parse().map_err(|_| invalid())?,
+                "bytes" => last_success.bytes = value.trim().parse().map_err(|_| invalid())?,
+                _ => {}
+            }
+        }
+        last_success.timestamp = timestamp.ok_or_else(|| format!("{path:?} has no timestamp"))?;
+        Ok(Some(last_success))
+    }
+
+    /// Write the watermark to a temporary sibling and rename it into place so an interrupted
+    /// write never leaves a truncated watermark behind
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let path = path.as_ref();
+        let mut temp_path = path.as_os_str().to_os_string();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+        let mut file = BufWriter::new(File::create(&temp_path)?);
+        writeln!(file, "timestamp={}", self.timestamp)?;
+        writeln!(file, "files={}", self.files)?;
+        writeln!(file, "bytes={}", self.bytes)?;
+        file.flush()?;
+        drop(file);
+        std::fs::rename(&temp_path, path)
+    }
+}
@@ -0,0 +1,73 @@
+use sftp_sync::SyncStats;
+use std::fmt::Write as _;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Write the run metrics in the Prometheus text exposition format for the node_exporter textfile
+/// collector. The file is written to a temporary sibling and renamed into place so the collector
+/// never reads a partial file.
+pub fn write_metrics(
+    path: &Path,
+    stats: &SyncStats,
+    duration: Duration,
+    success: bool,
+) -> std::io::Result<()> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let metrics: [(&str, &str, &str, String); 6] = [
+        (
+            "sftp_sync_files_transferred_total",
+            "counter",
+            "Files transferred by the last run",
+            stats.transferred.to_string(),
+        ),
+        (
+            "sftp_sync_bytes_transferred_total",
+            "counter",
+            "Bytes transferred by the last run",
+            stats.bytes.to_string(),
+        ),
+        (
+            "sftp_sync_files_failed_total",
+            "counter",
+            "Files that failed to transfer in the last run",
+            stats.failed.to_string(),
+        ),
+        (
+            "sftp_sync_duration_seconds",
+            "gauge",
+            "Duration of the last run",
+            format!("{:.3}", duration.as_secs_f64()),
+        ),
+        (
+            "sftp_sync_last_run_success",
+            "gauge",
+            "1 if the last run finished without errors",
+            u8::from(success).to_string(),
+        ),
+        (
+            "sftp_sync_last_run_timestamp_seconds",
+            "gauge",
+            "Unix time the last run finished",
+            timestamp.to_string(),
+        ),
+    ];
+    let mut contents = String::new();
+    for (name, kind, help, value) in metrics {
+        let _ = writeln!(contents, "# HELP {name} {help}");
+        let _ = writeln!(contents, "# TYPE {name} {kind}");
+        let _ = writeln!(contents, "{name} {value}");
+    }
+
+    let mut temp_path = path.as_os_str().to_os_string();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+    let mut file = std::fs::File::create(&temp_path)?;
+    file.write_all(contents.as_bytes())?;
+    file.flush()?;
+    drop(file);
+    std::fs::rename(&temp_path, path)
+}
@@ -0,0 +1,88 @@
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Length of the accounting window. Shares are recalculated at the start of every window so
+/// budget freed by finished transfers is picked up by the remaining ones.
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Global bandwidth cap shared by all transfers.
+///
+/// Every active transfer registers with the limiter and is throttled to an equal share of the
+/// budget (`bytes_per_second / active transfers`), so one large file cannot starve many small
+/// ones. The share is only recalculated once per window, so when transfers start or finish the
+/// total can briefly overshoot or undershoot the cap. Transfers that cannot use their full share
+/// (e.g. a slow server path) leave that bandwidth unused until the next window rather than
+/// lending it to others, so the link may run below the cap with many concurrent jobs.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_second: u64,
+    active: AtomicUsize,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second: bytes_per_second.max(1),
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    /// Wrap `writer` so writes are throttled to this transfer's share of the budget. The
+    /// transfer is deregistered when the returned writer is dropped.
+    pub fn throttle<W: Write>(&self, writer: W) -> Throttled<'_, W> {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        Throttled {
+            limiter: self,
+            writer,
+            window_start: Instant::now(),
+            window_bytes: 0,
+        }
+    }
+
+    fn share(&self) -> u64 {
+        let active = self.active.load(Ordering::SeqCst).max(1) as u64;
+        (self.bytes_per_second / active).max(1)
+    }
+}
+
+pub struct Throttled<'a, W: Write> {
+    limiter: &'a RateLimiter,
+    writer: W,
+    window_start: Instant,
+    window_bytes: u64,
+}
+
+impl<W: Write> Throttled<'_, W> {
+    fn consume(&mut self, bytes: u64) {
+        if self.window_start.elapsed() >= WINDOW {
+            self.window_start = Instant::now();
+            self.window_bytes = 0;
+        }
+        self.window_bytes += bytes;
+        let allowed =
+            Duration::from_secs_f64(self.window_bytes as f64 / self.limiter.share() as f64);
+        let elapsed = self.window_start.elapsed();
+        if allowed > elapsed {
+            std::thread::sleep(allowed - elapsed);
+        }
+    }
+}
+
+impl<W: Write> Write for Throttled<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.consume(written as u64);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> Drop for Throttled<'_, W> {
+    fn drop(&mut self) {
+        self.limiter.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,107 @@
+use ssh2::ErrorCode;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+/// ssh2 failure together with the operation that was attempted and the remote path involved, so
+/// messages read "Permission denied opening /data/x" instead of a bare libssh2 code. The original
+/// [ssh2::Error] is available through [std::error::Error::source].
+#[derive(Debug)]
+pub struct RemoteError {
+    operation: String,
+    path: Option<PathBuf>,
+    source: ssh2::Error,
+}
+
+impl RemoteError {
+    pub fn new(operation: impl Into<String>, path: Option<&Path>, source: ssh2::Error) -> Self {
+        Self {
+            operation: operation.into(),
+            path: path.map(Path::to_path_buf),
+            source,
+        }
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        self.source.code()
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Human-readable description of common SFTP status and libssh2 session codes
+    pub fn reason(&self) -> Option<&'static str> {
+        let reason = match self.source.code() {
+            ErrorCode::SFTP(1) => "Unexpected end of file",
+            ErrorCode::SFTP(2) => "No such file",
+            ErrorCode::SFTP(3) => "Permission denied",
+            ErrorCode::SFTP(4) => "Server failure",
+            ErrorCode::SFTP(5) => "Malformed SFTP message",
+            ErrorCode::SFTP(6) => "No connection",
+            ErrorCode::SFTP(7) => "Connection lost",
+            ErrorCode::SFTP(8) => "Operation not supported by the server",
+            ErrorCode::SFTP(9) => "Invalid file handle",
+            ErrorCode::SFTP(10) => "No such path",
+            ErrorCode::SFTP(11) => "File already exists",
+            ErrorCode::SFTP(12) => "Write protected",
+            ErrorCode::SFTP(13) => "No media",
+            ErrorCode::SFTP(14) => "No space left on the remote filesystem",
+            ErrorCode::SFTP(15) => "Quota exceeded",
+            ErrorCode::SFTP(17) => "Lock conflict",
+            ErrorCode::SFTP(18) => "Directory not empty",
+            ErrorCode::SFTP(19) => "Not a directory",
+            ErrorCode::SFTP(20) => "Invalid file name",
+            ErrorCode::SFTP(21) => "Too many symbolic links",
+            ErrorCode::Session(-5) => "Key exchange failed",
+            ErrorCode::Session(-7) | ErrorCode::Session(-43) => "Socket error",
+            ErrorCode::Session(-9) => "Timed out",
+            ErrorCode::Session(-13) => "Server disconnected",
+            ErrorCode::Session(-16) => "Could not read key file",
+            ErrorCode::Session(-18) => "Authentication failed",
+            ErrorCode::Session(-19) => "Public key not verified",
+            ErrorCode::Session(-26) => "Channel closed",
+            ErrorCode::Session(-33) => "Method not supported",
+            _ => return None,
+        };
+        Some(reason)
+    }
+}
+
+impl Display for RemoteError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.reason() {
+            Some(reason) => write!(f, "{reason} {}", self.operation)?,
+            None => write!(f, "Error {}", self.operation)?,
+        }
+        if let Some(path) = &self.path {
+            write!(f, " {path:?}")?;
+        }
+        match self.reason() {
+            Some(_) => Ok(()),
+            None => write!(f, ". {}", self.source),
+        }
+    }
+}
+
+impl std::error::Error for RemoteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Attach the attempted operation and remote path to an ssh2 result
+pub(crate) trait RemoteContext<T> {
+    fn remote_context(self, operation: &str, path: &Path) -> Result<T, RemoteError>;
+
+    fn session_context(self, operation: &str) -> Result<T, RemoteError>;
+}
+
+impl<T> RemoteContext<T> for Result<T, ssh2::Error> {
+    fn remote_context(self, operation: &str, path: &Path) -> Result<T, RemoteError> {
+        self.map_err(|error| RemoteError::new(operation, Some(path), error))
+    }
+
+    fn session_context(self, operation: &str) -> Result<T, RemoteError> {
+        self.map_err(|error| RemoteError::new(operation, None, error))
+    }
+}
@@ -0,0 +1,438 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Longest span searched for the next run, enough for a schedule like `0 0 29 2 *` to find the
+/// next leap day even across a skipped one like 2100
+const MAX_SEARCH_DAYS: u64 = 366 * 28;
+
+const MONTH_NAMES: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// Parsed five field cron expression (`minute hour day-of-month month day-of-week`) evaluated in
+/// local time.
+///
+/// Each field accepts `*`, single values, ranges (`1-5`), steps (`*/15`, `10-50/20`) and comma
+/// separated lists. Months and weekdays also accept three letter names and both 0 and 7 mean
+/// Sunday. As in classic cron, when both day fields are restricted a day matching either one
+/// fires. `@hourly`, `@daily`, `@midnight`, `@weekly`, `@monthly`, `@yearly` and `@annually` are
+/// accepted as shorthands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+impl Schedule {
+    /// First scheduled minute strictly after `time`, or `None` when the expression never fires
+    /// (e.g. February 30th)
+    pub fn next_after(&self, time: SystemTime) -> Option<SystemTime> {
+        self.next_after_in(time, LocalTime::from_unix)
+    }
+
+    /// [Schedule::next_after] with `local_time` converting Unix seconds to the timezone used
+    fn next_after_in(
+        &self,
+        time: SystemTime,
+        local_time: impl Fn(u64) -> LocalTime,
+    ) -> Option<SystemTime> {
+        let seconds = time.duration_since(UNIX_EPOCH).ok()?.as_secs();
+        let mut candidate = (seconds / 60 + 1) * 60;
+        let limit = candidate + MAX_SEARCH_DAYS * 86_400;
+        while candidate < limit {
+            let local = local_time(candidate);
+            if !self.matches_day(&local) {
+                candidate += (1440 - local.hour as u64 * 60 - local.minute as u64) * 60;
+                // A day is 23 hours long when daylight saving time starts, so skipping 24 hours
+                // overshoots midnight. Step back to the first minute of the next day. A 25 hour
+                // day undershoots instead, which the next iteration skips again
+                let next = local_time(candidate);
+                let overshoot = (next.hour as u64 * 60 + next.minute as u64) * 60;
+                if next.day != local.day
+                    && overshoot > 0
+                    && local_time(candidate - overshoot).day == next.day
+                {
+                    candidate -= overshoot;
+                }
+            } else if self.hours & (1 << local.hour) == 0 {
+                candidate += (60 - local.minute as u64) * 60;
+            } else if self.minutes & (1 << local.minute) == 0 {
+                candidate += 60;
+            } else {
+                return Some(UNIX_EPOCH + Duration::from_secs(candidate));
+            }
+        }
+        None
+    }
+
+    fn matches_day(&self, local: &LocalTime) -> bool {
+        if self.months & (1 << local.month) == 0 {
+            return false;
+        }
+        let day_of_month = self.days_of_month & (1 << local.day) != 0;
+        let day_of_week = self.days_of_week & (1 << local.weekday) != 0;
+        match (self.any_day_of_month, self.any_day_of_week) {
+            (true, true) => true,
+            (false, true) => day_of_month,
+            (true, false) => day_of_week,
+            (false, false) => day_of_month || day_of_week,
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let expression = match value.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            expression => expression,
+        };
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minutes, hours, days_of_month, months, days_of_week] = fields[..] else {
+            return Err(format!(
+                "'{value}' is not a cron expression. Expected 5 fields: minute hour day-of-month month day-of-week"
+            ));
+        };
+        let mut days_of_week_bits = parse_field(days_of_week, 0, 7, &WEEKDAY_NAMES, 0)?;
+        if days_of_week_bits & (1 << 7) != 0 {
+            days_of_week_bits |= 1;
+        }
+        Ok(Self {
+            minutes: parse_field(minutes, 0, 59, &[], 0)?,
+            hours: parse_field(hours, 0, 23, &[], 0)?,
+            days_of_month: parse_field(days_of_month, 1, 31, &[], 0)?,
+            months: parse_field(months, 1, 12, &MONTH_NAMES, 1)?,
+            days_of_week: days_of_week_bits,
+            any_day_of_month: days_of_month.starts_with('*'),
+            any_day_of_week: days_of_week.starts_with('*'),
+        })
+    }
+}
+
+/// Parse one cron field into a bit set of the allowed values. `names` are matched case
+/// insensitively and map to `first_name_value` onwards.
+fn parse_field(
+    field: &str,
+    min: u32,
+    max: u32,
+    names: &[&str],
+    first_name_value: u32,
+) -> Result<u64, String> {
+    let value_of = |text: &str| -> Result<u32, String> {
+        let lower = text.to_lowercase();
+        if let Some(index) = names.iter().position(|name| *name == lower) {
+            return Ok(index as u32 + first_name_value);
+        }
+        match text.parse::<u32>() {
+            Ok(value) if (min..=max).contains(&value) => Ok(value),
+            _ => Err(format!(
+                "'{text}' in cron field '{field}' is not between {min} and {max}"
+            )),
+        }
+    };
+    let mut bits = 0;
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format!("Invalid step '{step}' in cron field '{field}'")),
+            },
+            None => (item, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (value_of(start)?, value_of(end)?),
+                // A single value with a step runs to the end of the field, e.g. `5/15`
+                None if step > 1 => (value_of(range)?, max),
+                None => {
+                    let value = value_of(range)?;
+                    (value, value)
+                }
+            },
+        };
+        if start > end {
+            return Err(format!(
+                "Range '{range}' in cron field '{field}' ends before it starts"
+            ));
+        }
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+/// Calendar fields of a point in time in the local timezone. Weekday 0 is Sunday
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalTime {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub weekday: u32,
+}
+
+impl LocalTime {
+    #[cfg(unix)]
+    pub fn from_unix(seconds: u64) -> Self {
+        let time = seconds as libc::time_t;
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+            return Self::from_unix_utc(seconds);
+        }
+        Self {
+            year: tm.tm_year + 1900,
+            month: tm.tm_mon as u32 + 1,
+            day: tm.tm_mday as u32,
+            hour: tm.tm_hour as u32,
+            minute: tm.tm_min as u32,
+            weekday: tm.tm_wday as u32,
+        }
+    }
+
+    /// Without a portable local timezone lookup the schedule is evaluated in UTC
+    #[cfg(not(unix))]
+    pub fn from_unix(seconds: u64) -> Self {
+        Self::from_unix_utc(seconds)
+    }
+
+    fn from_unix_utc(seconds: u64) -> Self {
+        let days = (seconds / 86_400) as i64;
+        let seconds_of_day = seconds % 86_400;
+        // Civil date from days since the epoch, see Howard Hinnant's date algorithms
+        let shifted = days + 719_468;
+        let era = shifted.div_euclid(146_097);
+        let day_of_era = shifted.rem_euclid(146_097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+        let month = if month_index < 10 {
+            month_index + 3
+        } else {
+            month_index - 9
+        } as u32;
+        let year = (year_of_era + era * 400 + i64::from(month <= 2)) as i32;
+        Self {
+            year,
+            month,
+            day,
+            hour: (seconds_of_day / 3600) as u32,
+            minute: (seconds_of_day % 3600 / 60) as u32,
+            // 1970-01-01 was a Thursday
+            weekday: (days + 4).rem_euclid(7) as u32,
+        }
+    }
+}
+
+impl Display for LocalTime {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MARCH_10_2024: u64 = 1_710_028_800;
+    const NOVEMBER_3_2024: u64 = 1_730_592_000;
+
+    fn schedule(expression: &str) -> Schedule {
+        expression.parse().unwrap()
+    }
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    /// Next run in UTC as Unix seconds
+    fn next_utc(expression: &str, after: u64) -> Option<u64> {
+        schedule(expression)
+            .next_after_in(at(after), LocalTime::from_unix_utc)
+            .map(|time| time.duration_since(UNIX_EPOCH).unwrap().as_secs())
+    }
+
+    fn bits(values: &[u32]) -> u64 {
+        values.iter().fold(0, |bits, value| bits | 1 << value)
+    }
+
+    #[test]
+    fn parses_ranges_steps_and_lists() {
+        let parsed = schedule("*/15 9-17/4 1,15 * *");
+        assert_eq!(parsed.minutes, bits(&[0, 15, 30, 45]));
+        assert_eq!(parsed.hours, bits(&[9, 13, 17]));
+        assert_eq!(parsed.days_of_month, bits(&[1, 15]));
+        assert!(!parsed.any_day_of_month);
+        assert!(parsed.any_day_of_week);
+        assert_eq!(schedule("5/20 * * * *").minutes, bits(&[5, 25, 45]));
+    }
+
+    #[test]
+    fn parses_month_and_weekday_names() {
+        let parsed = schedule("0 0 * JAN,jul-Sep mon-fri");
+        assert_eq!(parsed.months, bits(&[1, 7, 8, 9]));
+        assert_eq!(parsed.days_of_week, bits(&[1, 2, 3, 4, 5]));
+    }
+
+    #[test]
+    fn seven_means_sunday() {
+        assert_eq!(schedule("0 0 * * 7").days_of_week & 1, 1);
+        assert_eq!(schedule("0 0 * * 5-7").days_of_week & 1, 1);
+        assert_eq!(schedule("0 0 * * 0"), schedule("@weekly"));
+    }
+
+    #[test]
+    fn expands_macros() {
+        assert_eq!(schedule("@hourly"), schedule("0 * * * *"));
+        assert_eq!(schedule("@midnight"), schedule("@daily"));
+        assert_eq!(schedule("@annually"), schedule("0 0 1 1 *"));
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        let error = |expression: &str| expression.parse::<Schedule>().unwrap_err();
+        assert!(error("* * * *").contains("Expected 5 fields"));
+        assert_eq!(
+            error("60 * * * *"),
+            "'60' in cron field '60' is not between 0 and 59"
+        );
+        assert_eq!(
+            error("* * 0 * *"),
+            "'0' in cron field '0' is not between 1 and 31"
+        );
+        assert_eq!(error("*/0 * * * *"), "Invalid step '0' in cron field '*/0'");
+        assert_eq!(
+            error("* 5-1 * * *"),
+            "Range '5-1' in cron field '5-1' ends before it starts"
+        );
+        assert_eq!(
+            error("* * * * funday"),
+            "'funday' in cron field 'funday' is not between 0 and 7"
+        );
+    }
+
+    #[test]
+    fn finds_the_next_matching_minute() {
+        let ten_past_seven = MARCH_10_2024 + 10 * 3600 + 7 * 60;
+        assert_eq!(
+            next_utc("*/15 * * * *", ten_past_seven),
+            Some(MARCH_10_2024 + 10 * 3600 + 15 * 60)
+        );
+        // Strictly after, even when the time itself matches
+        assert_eq!(
+            next_utc("0 0 * * *", MARCH_10_2024),
+            Some(MARCH_10_2024 + 86_400)
+        );
+        assert_eq!(
+            next_utc("30 2 * * *", MARCH_10_2024 + 3 * 3600),
+            Some(MARCH_10_2024 + 86_400 + 2 * 3600 + 30 * 60)
+        );
+    }
+
+    #[test]
+    fn either_restricted_day_field_matches() {
+        // Sunday March 10th 2024. The 13th is a Wednesday and the next Friday the 15th
+        assert_eq!(
+            next_utc("0 0 13 * 5", MARCH_10_2024),
+            Some(MARCH_10_2024 + 3 * 86_400)
+        );
+        assert_eq!(
+            next_utc("0 0 20 * 5", MARCH_10_2024),
+            Some(MARCH_10_2024 + 5 * 86_400)
+        );
+        // With the weekday unrestricted only the day of the month counts
+        assert_eq!(
+            next_utc("0 0 13 * *", MARCH_10_2024),
+            Some(MARCH_10_2024 + 3 * 86_400)
+        );
+    }
+
+    #[test]
+    fn impossible_dates_never_fire() {
+        assert_eq!(next_utc("0 0 30 2 *", MARCH_10_2024), None);
+        assert_eq!(next_utc("0 0 31 4,6,9,11 *", MARCH_10_2024), None);
+    }
+
+    #[test]
+    fn finds_rare_dates() {
+        assert_eq!(next_utc("0 0 29 2 *", MARCH_10_2024), Some(1_835_395_200));
+        // Either day field matching is enough, so the first Monday in February fires
+        assert_eq!(next_utc("0 0 29 2 1", MARCH_10_2024), Some(1_738_540_800));
+    }
+
+    #[test]
+    fn converts_unix_seconds_to_civil_dates() {
+        let date = |seconds: u64| {
+            let local = LocalTime::from_unix_utc(seconds);
+            (local.year, local.month, local.day, local.weekday)
+        };
+        assert_eq!(date(0), (1970, 1, 1, 4));
+        assert_eq!(date(951_782_400), (2000, 2, 29, 2));
+        assert_eq!(date(1_709_164_800), (2024, 2, 29, 4));
+        assert_eq!(date(4_102_444_800), (2100, 1, 1, 5));
+        let time = LocalTime::from_unix_utc(MARCH_10_2024 + 23 * 3600 + 59 * 60 + 59);
+        assert_eq!((time.hour, time.minute), (23, 59));
+        assert_eq!(time.to_string(), "2024-03-10 23:59");
+    }
+
+    /// Timezone that changes its UTC offset at 02:00 local time on `switch_day`
+    fn daylight_saving(
+        switch_day: u64,
+        offset_before: i64,
+        offset_after: i64,
+    ) -> impl Fn(u64) -> LocalTime {
+        move |seconds| {
+            let switch = (switch_day + 2 * 3600) as i64 - offset_before;
+            let offset = if (seconds as i64) < switch {
+                offset_before
+            } else {
+                offset_after
+            };
+            LocalTime::from_unix_utc((seconds as i64 + offset) as u64)
+        }
+    }
+
+    #[test]
+    fn day_skip_lands_on_midnight_after_daylight_saving_starts() {
+        // Clocks go forward an hour at 02:00 on Sunday, a 23 hour day
+        let local_time = daylight_saving(MARCH_10_2024, 0, 3600);
+        let next = schedule("30 0 * * 1")
+            .next_after_in(at(MARCH_10_2024), &local_time)
+            .unwrap();
+        let seconds = next.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        assert_eq!(local_time(seconds).to_string(), "2024-03-11 00:30");
+        assert_eq!(seconds, MARCH_10_2024 + 86_400 - 3600 + 30 * 60);
+    }
+
+    #[test]
+    fn day_skip_lands_on_midnight_after_daylight_saving_ends() {
+        // Clocks go back an hour at 02:00 on Sunday, a 25 hour day
+        let local_time = daylight_saving(NOVEMBER_3_2024, 3600, 0);
+        let next = schedule("30 0 * * 1")
+            .next_after_in(at(NOVEMBER_3_2024 - 3600), &local_time)
+            .unwrap();
+        let seconds = next.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        assert_eq!(local_time(seconds).to_string(), "2024-11-04 00:30");
+        assert_eq!(seconds, NOVEMBER_3_2024 + 86_400 + 30 * 60);
+    }
+}
@@ -0,0 +1,51 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// CSV log of every read/write iteration of the download loop, written by --trace-transfers.
+/// Each chunk adds a line so the file grows with the number of bytes transferred.
+pub struct TransferTrace {
+    file: Mutex<BufWriter<File>>,
+}
+
+impl TransferTrace {
+    pub fn create(path: &Path) -> std::io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        writeln!(
+            file,
+            "timestamp_us,remote_path,offset,bytes,read_us,write_us"
+        )?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Record one iteration. Write errors are ignored so tracing never fails a transfer
+    pub fn record(
+        &self,
+        remote_path: &Path,
+        offset: u64,
+        bytes: usize,
+        read: Duration,
+        write: Duration,
+    ) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros();
+        let remote_path = remote_path.to_string_lossy().replace('"', "\"\"");
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = writeln!(
+            file,
+            "{timestamp},\"{remote_path}\",{offset},{bytes},{},{}",
+            read.as_micros(),
+            write.as_micros()
+        );
+    }
+
+    pub fn flush(&self) -> std::io::Result<()> {
+        self.file.lock().unwrap_or_else(|e| e.into_inner()).flush()
+    }
+}
@@ -0,0 +1,96 @@
+use crate::remote_error::RemoteContext;
+use ssh2::Session;
+use std::io::Read;
+use std::path::Path;
+
+pub type ExtendedAttributes = Vec<(String, Vec<u8>)>;
+
+/// Read the extended attributes of a remote file. SFTP v3 has no way to transfer xattrs so this
+/// runs `getfattr` on the server over an exec channel. Returns `None` when `getfattr` is not
+/// available on the server.
+pub fn read_remote(
+    session: &Session,
+    remote_path: &Path,
+) -> Result<Option<ExtendedAttributes>, Box<dyn std::error::Error>> {
+    let mut channel = session
+        .channel_session()
+        .session_context("opening an exec channel")?;
+    channel
+        .exec(&format!(
+            "getfattr --absolute-names --dump --match=- --encoding=hex -- {}",
+            shell_quote(&remote_path.to_string_lossy())
+        ))
+        .remote_context("running getfattr on", remote_path)?;
+    let mut output = String::new();
+    channel.read_to_string(&mut output)?;
+    channel.wait_close()?;
+    match channel.exit_status()? {
+        0 => {}
+        127 => return Ok(None),
+        status => {
+            return Err(format!("getfattr exited with status {status} for {remote_path:?}").into())
+        }
+    }
+    let mut attributes = Vec::new();
+    for line in output.lines() {
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+        let Some((name, value)) = line.split_once('=') else {
+            continue;
+        };
+        let Some(hex) = value.strip_prefix("0x") else {
+            continue;
+        };
+        attributes.push((name.to_string(), decode_hex(hex)?));
+    }
+    Ok(Some(attributes))
+}
+
+#[cfg(target_os = "linux")]
+pub fn apply_local(local_path: &Path, attributes: &ExtendedAttributes) -> std::io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(local_path.as_os_str().as_bytes())?;
+    for (name, value) in attributes {
+        let name = CString::new(name.as_bytes())?;
+        // SAFETY: path and name are valid NUL terminated strings and value points to
+        // value.len() readable bytes for the duration of the call
+        let result = unsafe {
+            libc::setxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn apply_local(_local_path: &Path, _attributes: &ExtendedAttributes) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Setting extended attributes is only supported on Linux",
+    ))
+}
+
+pub(crate) fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, String> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(format!("Invalid hex value 0x{hex}"));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|e| format!("{e}")))
+        .collect()
+}
@@ -0,0 +1,26 @@
+use std::net::TcpListener;
+use std::process::Command;
+
+#[test]
+#[cfg(unix)]
+fn stream_mode_keeps_diagnostics_off_stdout() {
+    // Bind and release a port so the connection is refused
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let output = Command::new(env!("CARGO_BIN_EXE_sftp-sync"))
+        .args(["--local-directory", "-", "--remote-directory", "/file.txt"])
+        .args(["--ip", "127.0.0.1", "--port", &port.to_string()])
+        .args(["--username", "user", "--password", "secret", "--verbose"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Error attempting to create an SFTP connection"),
+        "{stderr}"
+    );
+}